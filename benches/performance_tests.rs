@@ -1,5 +1,7 @@
 use budget_ditto::{self, pattern};
-use budget_ditto::queues::round_robin;
+use budget_ditto::queues::{priority_queue, round_robin};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::packet_io::PacketIo;
 use std::time::{Duration, Instant};
 use std::thread;
 use pnet::packet::ethernet;
@@ -23,17 +25,10 @@ fn send(input: &str) {
 
     let packets = get_eth_frames();
 
-    for i in 0..NUM_PACKETS as usize {
-        match ch_tx.tx.send_to(&packets[i], None) {
-            Some(res) => {
-                match res {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("Error sending frame: {}", e),
-                }
-            }
-            None => {
-                eprintln!("No packets to send");
-            }
+    for packet in packets.iter().take(NUM_PACKETS as usize) {
+        match ch_tx.send(packet) {
+            Ok(_) => (),
+            Err(e) => eprintln!("Error sending frame: {}", e),
         }
     }
 }
@@ -46,7 +41,7 @@ fn receive(output: &str) {
 
     thread::spawn(move || {
         for _ in 0..NUM_PACKETS as usize {
-            match ch_rx.rx.next() {
+            match ch_rx.recv() {
                 // process_packet(packet, &mut scheduler),
                 Ok(_) =>  {
                     //println!("Received length = {}", packet.len());
@@ -94,8 +89,8 @@ fn get_random_pkt_len() -> i32 {
 
 fn rr_pop() {
     // Pop empty queues
-    let rrs = round_robin::RoundRobinScheduler::new(budget_ditto::pattern::PATTERN.len(), 1e6, SRC_IP_ADDR, DST_IP_ADDR);
-    rrs.pop(pattern::PATTERN.len()-1); // Pop from last q (currently longest so worst case scenario. Be careful about this)
+    let rrs = round_robin::RoundRobinScheduler::new(&pattern::DEFAULT_PATTERN, 1e6, priority_queue::SlotFormat::new(SRC_IP_ADDR, DST_IP_ADDR, None, WireFormat::Shim));
+    rrs.pop(pattern::DEFAULT_PATTERN.len()-1); // Pop from last q (currently longest so worst case scenario. Be careful about this)
 }
 
 fn thread_timer() {
@@ -124,7 +119,7 @@ fn bench_send(c: &mut Criterion) {
 }
 
 fn bench_get_pkts(c: &mut Criterion) {
-    c.bench_function("get_eth_frames", |b| b.iter(|| get_eth_frames()));
+    c.bench_function("get_eth_frames", |b| b.iter(get_eth_frames));
 }
 
 fn bench_get_channel(c: &mut Criterion) {
@@ -147,11 +142,11 @@ fn bench_receive(c: &mut Criterion) {
 // }
 
 fn bench_rr_pop(c: &mut Criterion) {
-    c.bench_function("rr_pop", |b| b.iter(|| rr_pop()));
+    c.bench_function("rr_pop", |b| b.iter(rr_pop));
 }
 
 fn bench_thread_timer(c: &mut Criterion) {
-    c.bench_function("thread_timer", |b| b.iter(|| thread_timer()));
+    c.bench_function("thread_timer", |b| b.iter(thread_timer));
 }
 
 // Before running this need to setup virtual eth 1,2,3
@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::config::AdaptiveConfig;
+use crate::control::Control;
+use crate::shutdown::Shutdown;
+
+// How often the controller thread checks for a shutdown between two checks of the backlog
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A change of rate and why it was made
+#[derive(Debug, Clone, PartialEq)]
+pub struct Switch {
+    pub from: f64,
+    pub to: f64,
+    pub reason: String,
+}
+
+// Picks the rate of one peer among the levels from the backlog of its queues. The rate is what an observer sees, so
+// it only ever moves one level at a time, at most max_switches times in switch_window and never twice within
+// min_dwell. What it gives away is bounded by leakage_bits
+pub struct RateController {
+    levels: Vec<f64>,
+    level: usize,
+    high_backlog: Duration,
+    low_backlog: Duration,
+    min_dwell: Duration,
+    max_switches: usize,
+    switch_window: Duration,
+    check_interval: Duration,
+    // Times of the switches within the last switch_window
+    switches: VecDeque<Instant>,
+    last_switch: Instant,
+    // Last time the backlog was above low_backlog, or the rate switched
+    last_busy: Instant,
+}
+
+impl RateController {
+    pub fn new(config: &AdaptiveConfig, rate: f64, now: Instant) -> Option<RateController> {
+        // None if rate is not one of the levels, the controller would not know where it starts
+        let level = config.levels.iter().position(|&level| level == rate)?;
+        Some(RateController {
+            levels: config.levels.clone(),
+            level,
+            high_backlog: Duration::from_secs_f64(config.high_backlog),
+            low_backlog: Duration::from_secs_f64(config.low_backlog),
+            min_dwell: Duration::from_secs_f64(config.min_dwell),
+            max_switches: config.max_switches,
+            switch_window: Duration::from_secs_f64(config.switch_window),
+            check_interval: Duration::from_secs_f64(config.check_interval),
+            switches: VecDeque::new(),
+            last_switch: now,
+            last_busy: now,
+        })
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.levels[self.level]
+    }
+
+    pub fn leakage_bits(&self) -> f64 {
+        // Most an observer of the rate learns in a switch_window: whether each switch is up or down, and on which
+        // check it happened
+        let checks = (self.switch_window.as_secs_f64() / self.check_interval.as_secs_f64()).max(1.0);
+        let switches = self.max_switches.min((self.switch_window.as_secs_f64() / self.min_dwell.as_secs_f64()).ceil() as usize);
+        switches as f64 * (1.0 + checks.log2())
+    }
+
+    pub fn update(&mut self, backlog: Duration, now: Instant) -> Option<Switch> {
+        // Called on every check with the backlog of the fullest queue, the switch to make if any
+        if backlog > self.low_backlog {
+            self.last_busy = now;
+        }
+        while self.switches.front().is_some_and(|&time| now.duration_since(time) >= self.switch_window) {
+            self.switches.pop_front();
+        }
+        if now.duration_since(self.last_switch) < self.min_dwell || self.switches.len() >= self.max_switches {
+            return None;
+        }
+
+        let (level, reason) = if backlog >= self.high_backlog && self.level + 1 < self.levels.len() {
+            (self.level + 1, format!("backlog of {:?} is above {:?}", backlog, self.high_backlog))
+        } else if now.duration_since(self.last_busy) >= self.min_dwell && self.level > 0 {
+            (self.level - 1, format!("backlog stayed below {:?} for {:?}", self.low_backlog, now.duration_since(self.last_busy)))
+        } else {
+            return None;
+        };
+        let from = self.rate();
+        self.level = level;
+        self.switches.push_back(now);
+        self.last_switch = now;
+        self.last_busy = now;
+        Some(Switch { from, to: self.rate(), reason })
+    }
+}
+
+pub fn spawn(peers: Vec<(IpAddr, Arc<Control>)>, config: AdaptiveConfig, shutdown: Shutdown) -> JoinHandle<()> {
+    // One controller per peer, each following the backlog of its own queues
+    let start = Instant::now();
+    let mut controllers: Vec<(IpAddr, Arc<Control>, Option<RateController>)> = peers.into_iter()
+        .map(|(dst, control)| {
+            let controller = RateController::new(&config, control.rate(), start);
+            (dst, control, controller)
+        })
+        .collect();
+    if let Some((_, _, Some(controller))) = controllers.first() {
+        println!("Adapting the rate between {:?} Mbps, leaking at most {:.0} bits every {}s", config.levels, controller.leakage_bits(), config.switch_window);
+    }
+
+    let check_interval = Duration::from_secs_f64(config.check_interval);
+    thread::spawn(move || {
+        let mut next_check = start + check_interval;
+        while !shutdown.is_requested() {
+            let now = Instant::now();
+            if now < next_check {
+                thread::sleep(POLL_INTERVAL.min(next_check - now));
+                continue;
+            }
+            next_check += check_interval;
+
+            for (dst, control, slot) in controllers.iter_mut() {
+                let Some(controller) = slot else {
+                    continue;
+                };
+                // A rate set through the control socket is left alone from then on
+                if control.rate() != controller.rate() {
+                    println!("Rate to {} changed to {} Mbps from outside, it is no longer adapted", dst, control.rate());
+                    *slot = None;
+                    continue;
+                }
+                // Nothing is sent while paused, the backlog says nothing about the rate
+                if control.is_paused() {
+                    continue;
+                }
+                if let Some(switch) = controller.update(control.scheduler().backlog(), now) {
+                    println!("Rate to {} switched from {} to {} Mbps: {}", dst, switch.from, switch.to, switch.reason);
+                    control.metrics().rate_switches.inc();
+                    if let Err(e) = control.set_rate(switch.to) {
+                        eprintln!("Error switching the rate to {}: {}", dst, e);
+                    }
+                }
+            }
+        }
+    })
+}
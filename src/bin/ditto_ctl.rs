@@ -0,0 +1,69 @@
+use budget_ditto::config::Config;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::time::Duration;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <control socket> [dst <peer>] <command> [args]", args[0]);
+        eprintln!("  peers                Address, rate, pattern and state of every peer, the other commands go to the first one without dst");
+        eprintln!("  queues               Length, capacity, depth and limits of every queue");
+        eprintln!("  stats                Pushed, dropped and queued packets and padding since the pattern was loaded");
+        eprintln!("  rate [<Mbps>]        Show or change the rate");
+        eprintln!("  pause | resume       Stop or restart sending the pattern, packets are still queued while paused");
+        eprintln!("  pattern <size>...    Replace the pattern, the packets still queued are dropped");
+        eprintln!("  gaps [<weight>...]   Show or change the relative time after each slot of the pattern, evenly spaced again on a new pattern");
+        eprintln!("  reload <config>      Replace the pattern and its gaps with the [pattern] section of a config file");
+        process::exit(1);
+    }
+
+    // The peer is kept in front of every command sent
+    let (peer, command) = match args[2].as_str() {
+        "dst" if args.len() >= 5 => (format!("dst {} ", args[3]), &args[4..]),
+        "dst" => exit_with_error("Missing command after dst <peer>"),
+        _ => (String::new(), &args[2..]),
+    };
+    let commands = match command[0].as_str() {
+        "reload" => {
+            let path = command.get(1).unwrap_or_else(|| exit_with_error("Missing config file to reload"));
+            let config = Config::from_file(path).unwrap_or_else(|e| exit_with_error(&e.to_string()));
+            let sizes: Vec<String> = config.pattern.sizes.iter().map(|size| size.to_string()).collect();
+            let mut commands = vec![format!("pattern {}", sizes.join(" "))];
+            // The new pattern starts evenly spaced
+            if let Some(gaps) = &config.pattern.gaps {
+                let gaps: Vec<String> = gaps.iter().map(|gap| gap.to_string()).collect();
+                commands.push(format!("gaps {}", gaps.join(" ")));
+            }
+            commands
+        },
+        _ => vec![command.join(" ")],
+    };
+
+    for command in commands {
+        let reply = send_command(&args[1], &format!("{}{}", peer, command)).unwrap_or_else(|e| exit_with_error(&format!("Failed to talk to {}: {}", args[1], e)));
+        println!("{}", reply);
+        // Scripts can rely on the exit code instead of parsing the reply
+        let is_ok = serde_json::from_str::<serde_json::Value>(&reply).is_ok_and(|reply| reply["ok"] == true);
+        if !is_ok {
+            process::exit(1);
+        }
+    }
+}
+
+fn send_command(path: &str, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    writeln!(stream, "{}", command)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
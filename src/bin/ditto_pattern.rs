@@ -0,0 +1,102 @@
+use budget_ditto::config::{Config, GeneralConfig};
+use budget_ditto::optimizer::{self, Evaluation, SlotModel, Trace};
+use budget_ditto::pattern;
+use std::fs;
+use std::process;
+
+const DEFAULT_DELAY_WEIGHT: f64 = 0.1;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <trace.pcap> <pattern length> [--rate <Mbps>] [--delay-weight <per ms>] [--config <file>] [--output <file>]", args[0]);
+        eprintln!("  --rate          Rate budget, by default the lowest rate keeping every queue below {:.0}% utilization", optimizer::TARGET_UTILIZATION * 100.0);
+        eprintln!("  --delay-weight  Cost of 1ms of mean queueing delay relative to 100% padding overhead (default {})", DEFAULT_DELAY_WEIGHT);
+        eprintln!("  --config        Config the pattern is for, its wire format, key and outer headers set how much each slot carries");
+        process::exit(1);
+    }
+
+    let trace_path = &args[1];
+    let length: usize = parse_arg(&args[2], "pattern length");
+    if length == 0 {
+        exit_with_error("Pattern length must be at least 1");
+    }
+
+    let mut rate_budget = None;
+    let mut delay_weight = DEFAULT_DELAY_WEIGHT;
+    let mut output = None;
+    // Without a config, slots are sent unencrypted over IPv4 in the default wire format
+    let mut model = SlotModel::new(false, GeneralConfig::default().wire_format, pattern::IP_HEADER_LEN);
+    let mut i = 3;
+    while i < args.len() {
+        let value = args.get(i + 1).unwrap_or_else(|| exit_with_error(&format!("Missing value for {}", args[i])));
+        match args[i].as_str() {
+            "--rate" => rate_budget = Some(parse_arg::<f64>(value, "rate")),
+            "--delay-weight" => delay_weight = parse_arg(value, "delay weight"),
+            "--config" => model = get_model(value),
+            "--output" => output = Some(value.clone()),
+            other => exit_with_error(&format!("Unknown option {}", other)),
+        }
+        i += 2;
+    }
+
+    let trace = match Trace::from_pcap(trace_path) {
+        Ok(trace) => trace,
+        Err(e) => exit_with_error(&format!("Failed to read trace {}: {}", trace_path, e)),
+    };
+    eprintln!("Read {} packets over {:.2}s from {}", trace.num_packets, trace.duration, trace_path);
+
+    let evaluation = optimizer::optimize(&trace, length, &model, rate_budget, delay_weight);
+    if !evaluation.cost.is_finite() {
+        exit_with_error("No pattern of that length can carry the trace within the rate budget");
+    }
+
+    let snippet = get_snippet(&evaluation);
+    match output {
+        Some(path) => {
+            fs::write(&path, &snippet).unwrap_or_else(|e| exit_with_error(&format!("Failed to write {}: {}", path, e)));
+            eprint!("{}", snippet);
+        }
+        None => print!("{}", snippet),
+    }
+}
+
+fn get_snippet(evaluation: &Evaluation) -> String {
+    // The report goes in comments so the output can be pasted in a config as is
+    let mut snippet = String::new();
+    snippet.push_str(&format!("# Expected overhead: {:.1}% padding and chaff per byte of real traffic\n", evaluation.overhead * 100.0));
+    snippet.push_str(&format!("# Expected mean queueing delay: {:.3}ms\n", evaluation.mean_delay * 1e3));
+    snippet.push_str(&format!("# Rate: {:.2} Mbps ({:.0} packets/s)\n", evaluation.rate, evaluation.pps));
+    if evaluation.fragmented > 0 {
+        snippet.push_str(&format!("# {} packets in the trace are larger than every slot and would be split in fragments\n", evaluation.fragmented));
+    }
+    if evaluation.dropped > 0 {
+        snippet.push_str(&format!("# {} packets in the trace are larger than every slot and would be dropped\n", evaluation.dropped));
+    }
+    for class in &evaluation.classes {
+        snippet.push_str(&format!("#   {}B x{}: {:.0} packets/s, {:.1}% utilization, {:.3}ms delay\n",
+            class.size, class.slots, class.arrival_rate, class.utilization * 100.0, class.delay * 1e3));
+    }
+    snippet.push_str("[pattern]\n");
+    snippet.push_str(&format!("sizes={:?}\n", evaluation.pattern));
+    snippet
+}
+
+fn get_model(path: &str) -> SlotModel {
+    // The slots to the first peer, the other peers share its wire format and key
+    let config = Config::from_file(path).unwrap_or_else(|e| exit_with_error(&format!("Failed to read config {}: {}", path, e)));
+    let outer_header_len = pattern::get_outer_header_len(config.ip.src, config.ip.src_port.is_some());
+    SlotModel::new(config.crypto.key.is_some(), config.general.wire_format, outer_header_len)
+}
+
+fn parse_arg<T: std::str::FromStr>(value: &str, name: &str) -> T {
+    match value.parse() {
+        Ok(v) => v,
+        Err(_) => exit_with_error(&format!("Invalid {}: {}", name, value)),
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,557 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use crate::crypto;
+use crate::pattern;
+use crate::peer::Subnet;
+use pnet::util::MacAddr;
+use crate::shim::WireFormat;
+use crate::tun::DeviceType;
+use crate::packet_io::IoBackend;
+use crate::pacer::{CatchUp, PacerKind};
+use crate::queues::priority_queue::{QueueLimits, MAX_Q_LEN};
+
+const SECTIONS: [&str; 10] = ["ip", "pattern", "isolation", "interface", "general", "crypto", "metrics", "adaptive", "queues", "peer"];
+
+// Highest priority accepted by SCHED_FIFO
+const MAX_THREAD_PRIORITY: i32 = 99;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub ip: IpConfig,
+    #[serde(default)]
+    pub pattern: PatternConfig,
+    #[serde(default)]
+    pub isolation: IsolationConfig,
+    pub interface: InterfaceConfig,
+    #[serde(default)]
+    pub general: GeneralConfig,
+    #[serde(default)]
+    pub crypto: CryptoConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub adaptive: AdaptiveConfig,
+    #[serde(default)]
+    pub queues: QueuesConfig,
+    // Peers other than [ip] dst, each with a tunnel of its own
+    #[serde(default)]
+    pub peer: Vec<PeerConfig>,
+}
+
+// IP addresses used by the VPN, both IPv4 or both IPv6
+#[derive(Debug, Clone, Deserialize)]
+pub struct IpConfig {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    // Slots are sent over UDP from src_port when it is set, to dst_port which defaults to src_port
+    #[serde(default)]
+    pub src_port: Option<u16>,
+    #[serde(default)]
+    pub dst_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PatternConfig {
+    pub sizes: Vec<usize>,
+    // Relative time from each slot to the next, one per size, e.g. [0, 0, 2] for bursts of 3. Evenly spaced if not set
+    pub gaps: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IsolationConfig {
+    pub priority: i32,
+    pub isolate_send: bool,
+    pub core_send: usize,
+    pub isolate_obfuscate: bool,
+    pub core_obfuscate: usize,
+    pub isolate_deobfuscate: bool,
+    pub core_deobfuscate: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InterfaceConfig {
+    // Interface with the unobfuscated traffic, frames are read from it and deobfuscated frames written back to it
+    pub no_obf: String,
+    // Interface the obfuscated pattern is sent and received on
+    pub obf: String,
+    // Only frames with the src mac of this device or of no_obf are obfuscated. Defaults to no_obf
+    #[serde(default)]
+    pub src_device: Option<String>,
+    // With tun or tap, no_obf is the name of a device created at startup and everything routed into it is obfuscated
+    #[serde(default)]
+    pub device: DeviceType,
+    // How frames are sent and received on obf and, if it is an interface, on no_obf
+    #[serde(default)]
+    pub obf_io: IoBackend,
+    #[serde(default)]
+    pub no_obf_io: IoBackend,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GeneralConfig {
+    // In Mbps
+    pub rate: f64,
+    // In received packets, defaults to 10% of the packets sent per second
+    pub pad_log_interval: Option<f64>,
+    pub save: bool,
+    pub local: bool,
+    pub log: bool,
+    pub hw_obfuscation: bool,
+    pub backbone: bool,
+    // Legacy by default so that peers not knowing the shim header yet keep working, both peers must set the same
+    pub wire_format: WireFormat,
+    // Pack as many queued packets as fit in each slot, the peer must know aggregate slots
+    pub aggregate: bool,
+    // In seconds, how long the real packets still queued at shutdown keep being sent before they are dropped
+    pub drain_timeout: f64,
+    // In seconds, how long the fragments of a packet too large for the pattern are kept waiting for the missing ones
+    pub reassembly_timeout: f64,
+    // Path of a Unix socket to inspect and change the running pipeline with ditto-ctl, none if not set
+    pub control_socket: Option<String>,
+    // Where a backbone router sends the packets of the first peer
+    pub next_hop: Ipv4Addr,
+    // How the send threads wait for the time each slot is due
+    pub pacer: PacerKind,
+    // What the send threads do once they are behind schedule
+    pub catch_up: CatchUp,
+}
+
+// Another peer, sent its own pattern at its own rate. [ip] dst is the first peer, it gets the frames matching no other
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerConfig {
+    pub dst: IpAddr,
+    // Defaults to the dst_port of [ip]
+    #[serde(default)]
+    pub dst_port: Option<u16>,
+    // Default to the sizes of [pattern] and the rate of [general]
+    #[serde(default)]
+    pub pattern: Option<Vec<usize>>,
+    #[serde(default)]
+    pub rate: Option<f64>,
+    // Default to the gaps of [pattern] if the peer has no pattern of its own, else evenly spaced
+    #[serde(default)]
+    pub gaps: Option<Vec<f64>>,
+    // Frames to these subnets, e.g. 10.8.0.0/24, or to these MAC addresses are sent to this peer
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default)]
+    pub macs: Vec<String>,
+    // Where a backbone router sends the packets of this peer, defaults to the next hop of the first peer
+    #[serde(default)]
+    pub next_hop: Option<Ipv4Addr>,
+}
+
+// Encrypt and authenticate every slot with a key shared by both peers
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CryptoConfig {
+    // 32 bytes in hexadecimal, no encryption if not set
+    pub key: Option<String>,
+}
+
+// Export the counters of the pipeline in the Prometheus text format
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // Address of an HTTP endpoint serving /metrics, e.g. 127.0.0.1:9898
+    pub listen: Option<SocketAddr>,
+    // File rewritten every textfile_interval seconds, for the textfile collector of node_exporter
+    pub textfile: Option<String>,
+    pub textfile_interval: f64,
+}
+
+// Switch the rate between a few levels following the backlog of the queues, off unless levels are set
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConfig {
+    // In Mbps from lowest to highest, [general] rate must be one of them and is the level it starts at
+    pub levels: Vec<f64>,
+    // In seconds, how often the backlog is checked, switches only happen on these ticks
+    pub check_interval: f64,
+    // In seconds of backlog of the fullest queue, one level up above high_backlog, one level down once it stayed below
+    // low_backlog for min_dwell
+    pub high_backlog: f64,
+    pub low_backlog: f64,
+    // In seconds, least time spent at a level
+    pub min_dwell: f64,
+    // Most switches in any switch_window seconds
+    pub max_switches: usize,
+    pub switch_window: f64,
+}
+
+// Limits of every queue, derived from the rate when left out so that packets wait at most about 100ms
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QueuesConfig {
+    // Packets waiting in a queue, at most 1024
+    pub max_packets: Option<usize>,
+    // Bytes of the packets waiting in a queue, without their padding
+    pub max_bytes: Option<usize>,
+    // In seconds, packets that waited longer are dropped when their turn comes
+    pub max_sojourn: Option<f64>,
+}
+
+impl QueuesConfig {
+    pub fn limits(&self) -> QueueLimits {
+        QueueLimits {
+            max_packets: self.max_packets,
+            max_bytes: self.max_bytes,
+            max_sojourn: self.max_sojourn.map(Duration::from_secs_f64),
+        }
+    }
+}
+
+impl Default for PatternConfig {
+    fn default() -> Self {
+        PatternConfig { sizes: pattern::DEFAULT_PATTERN.to_vec(), gaps: None }
+    }
+}
+
+impl Default for IsolationConfig {
+    fn default() -> Self {
+        IsolationConfig {
+            priority: MAX_THREAD_PRIORITY,
+            isolate_send: false,
+            core_send: 2,
+            isolate_obfuscate: false,
+            core_obfuscate: 3,
+            isolate_deobfuscate: false,
+            core_deobfuscate: 4,
+        }
+    }
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        GeneralConfig {
+            rate: 100.0,
+            pad_log_interval: None,
+            save: false,
+            local: false,
+            log: false,
+            hw_obfuscation: false,
+            backbone: false,
+            wire_format: WireFormat::Legacy,
+            aggregate: false,
+            drain_timeout: 1.0,
+            reassembly_timeout: 1.0,
+            control_socket: None,
+            next_hop: Ipv4Addr::from(pattern::IP_NEXT_HOP),
+            pacer: PacerKind::Sleep,
+            catch_up: CatchUp::Burst,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            listen: None,
+            textfile: None,
+            textfile_interval: 10.0,
+        }
+    }
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        AdaptiveConfig {
+            levels: Vec::new(),
+            check_interval: 1.0,
+            high_backlog: 0.05,
+            low_backlog: 0.005,
+            min_dwell: 60.0,
+            max_switches: 10,
+            switch_window: 3600.0,
+        }
+    }
+}
+
+impl InterfaceConfig {
+    pub fn src_device(&self) -> &str {
+        self.src_device.as_deref().unwrap_or(&self.no_obf)
+    }
+}
+
+// All the problems found in a config, reported together instead of stopping at the first one
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration, found {} problem(s):", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let toml_str = fs::read_to_string(path).map_err(|e| ConfigError {
+            problems: vec![format!("Failed to read config file {}: {}", path, e)],
+        })?;
+        Config::from_toml_str(&toml_str)
+    }
+
+    pub fn from_toml_str(toml_str: &str) -> Result<Config, ConfigError> {
+        let table: toml::Table = toml_str.parse().map_err(|e| ConfigError {
+            problems: vec![format!("Failed to parse TOML: {}", e)],
+        })?;
+
+        let mut problems = Vec::new();
+        for name in table.keys() {
+            if !SECTIONS.contains(&name.as_str()) {
+                problems.push(format!("Unknown section [{}]", name));
+            }
+        }
+
+        // Each section is deserialized on its own so that a problem in one does not hide the others
+        let ip = parse_section::<IpConfig>(&table, "ip", &mut problems);
+        let pattern = parse_section::<PatternConfig>(&table, "pattern", &mut problems);
+        let isolation = parse_section::<IsolationConfig>(&table, "isolation", &mut problems);
+        let interface = parse_section::<InterfaceConfig>(&table, "interface", &mut problems);
+        let general = parse_section::<GeneralConfig>(&table, "general", &mut problems);
+        let crypto = parse_section::<CryptoConfig>(&table, "crypto", &mut problems);
+        let metrics = parse_section::<MetricsConfig>(&table, "metrics", &mut problems);
+        let adaptive = parse_section::<AdaptiveConfig>(&table, "adaptive", &mut problems);
+        let queues = parse_section::<QueuesConfig>(&table, "queues", &mut problems);
+        // An array of tables, there are no peers other than [ip] dst if it is missing
+        let peer = match table.get("peer") {
+            Some(value) => parse_value::<Vec<PeerConfig>>(value.clone(), "[peer]", &mut problems),
+            None => Some(Vec::new()),
+        };
+
+        if let (Some(ip), Some(pattern), Some(isolation), Some(interface), Some(general), Some(crypto), Some(metrics), Some(adaptive), Some(queues), Some(peer)) = (ip, pattern, isolation, interface, general, crypto, metrics, adaptive, queues, peer) {
+            let config = Config { ip, pattern, isolation, interface, general, crypto, metrics, adaptive, queues, peer };
+            problems.extend(config.get_problems());
+            if problems.is_empty() {
+                return Ok(config);
+            }
+        }
+        Err(ConfigError { problems })
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let problems = self.get_problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    fn get_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.ip.src.is_ipv4() != self.ip.dst.is_ipv4() {
+            problems.push(format!("[ip] src {} and dst {} must be of the same IP version", self.ip.src, self.ip.dst));
+        }
+        if self.ip.dst_port.is_some() && self.ip.src_port.is_none() {
+            problems.push("[ip] dst_port needs src_port to send slots over UDP".to_string());
+        }
+        if self.ip.src_port == Some(0) || self.ip.dst_port == Some(0) {
+            problems.push("[ip] src_port and dst_port must not be 0".to_string());
+        }
+        let outer_header_len = pattern::get_outer_header_len(self.ip.src, self.ip.src_port.is_some());
+        let is_encrypted = self.crypto.key.is_some();
+        if let Err(e) = pattern::validate_pattern(&self.pattern.sizes, outer_header_len, is_encrypted, self.general.wire_format) {
+            problems.push(e);
+        }
+        if let Some(gaps) = &self.pattern.gaps {
+            if let Err(e) = pattern::validate_gaps(gaps, self.pattern.sizes.len()) {
+                problems.push(format!("[pattern] {}", e));
+            }
+        }
+        if let Some(key) = &self.crypto.key {
+            if let Err(e) = crypto::parse_key(key) {
+                problems.push(format!("[crypto] {}", e));
+            }
+        }
+
+        if !self.general.rate.is_finite() || self.general.rate <= 0.0 {
+            problems.push(format!("[general] rate must be a positive number of Mbps, got {}", self.general.rate));
+        }
+        if let Some(interval) = self.general.pad_log_interval {
+            if !interval.is_finite() || interval < 1.0 {
+                problems.push(format!("[general] pad_log_interval must be at least 1 packet, got {}", interval));
+            }
+        }
+        if self.general.aggregate && self.general.wire_format != WireFormat::Shim {
+            problems.push("[general] aggregate needs the shim wire format".to_string());
+        }
+        if !self.general.drain_timeout.is_finite() || self.general.drain_timeout < 0.0 {
+            problems.push(format!("[general] drain_timeout must be a non-negative number of seconds, got {}", self.general.drain_timeout));
+        }
+        if !self.general.reassembly_timeout.is_finite() || self.general.reassembly_timeout <= 0.0 {
+            problems.push(format!("[general] reassembly_timeout must be a positive number of seconds, got {}", self.general.reassembly_timeout));
+        }
+        // Spinning on a core shared with other threads would slow them down and be preempted anyway
+        if self.general.pacer == PacerKind::Busy && !self.isolation.isolate_send {
+            problems.push("[general] pacer = \"busy\" needs the send thread on its own core with [isolation] isolate_send".to_string());
+        }
+        if self.general.control_socket.as_ref().is_some_and(|path| path.is_empty()) {
+            problems.push("[general] control_socket must not be empty".to_string());
+        }
+
+        if self.metrics.textfile.as_ref().is_some_and(|path| path.is_empty()) {
+            problems.push("[metrics] textfile must not be empty".to_string());
+        }
+        if !self.metrics.textfile_interval.is_finite() || self.metrics.textfile_interval <= 0.0 {
+            problems.push(format!("[metrics] textfile_interval must be a positive number of seconds, got {}", self.metrics.textfile_interval));
+        }
+
+        let adaptive = &self.adaptive;
+        if !adaptive.levels.is_empty() {
+            if adaptive.levels.len() < 2 {
+                problems.push("[adaptive] levels must have at least 2 rates to switch between".to_string());
+            }
+            if let Some(level) = adaptive.levels.iter().find(|level| !level.is_finite() || **level <= 0.0) {
+                problems.push(format!("[adaptive] levels must be positive numbers of Mbps, got {}", level));
+            }
+            if adaptive.levels.windows(2).any(|pair| pair[0] >= pair[1]) {
+                problems.push(format!("[adaptive] levels must be in increasing order, got {:?}", adaptive.levels));
+            }
+            if !adaptive.levels.contains(&self.general.rate) {
+                problems.push(format!("[adaptive] levels must include [general] rate {}, the level to start at", self.general.rate));
+            }
+        }
+        for (name, value) in [("check_interval", adaptive.check_interval), ("min_dwell", adaptive.min_dwell), ("switch_window", adaptive.switch_window)] {
+            if !value.is_finite() || value <= 0.0 {
+                problems.push(format!("[adaptive] {} must be a positive number of seconds, got {}", name, value));
+            }
+        }
+        if !adaptive.low_backlog.is_finite() || adaptive.low_backlog < 0.0 || !adaptive.high_backlog.is_finite() || adaptive.high_backlog <= adaptive.low_backlog {
+            problems.push(format!("[adaptive] low_backlog must be a non-negative number of seconds below high_backlog, got {} and {}", adaptive.low_backlog, adaptive.high_backlog));
+        }
+        if adaptive.max_switches == 0 {
+            problems.push("[adaptive] max_switches must be at least 1".to_string());
+        }
+
+        if let Some(max_packets) = self.queues.max_packets.filter(|max_packets| !(1..=MAX_Q_LEN).contains(max_packets)) {
+            problems.push(format!("[queues] max_packets must be between 1 and {}, got {}", MAX_Q_LEN, max_packets));
+        }
+        if self.queues.max_bytes == Some(0) {
+            problems.push("[queues] max_bytes must be at least 1".to_string());
+        }
+        if let Some(max_sojourn) = self.queues.max_sojourn.filter(|&max_sojourn| max_sojourn <= 0.0 || Duration::try_from_secs_f64(max_sojourn).is_err()) {
+            problems.push(format!("[queues] max_sojourn must be a positive number of seconds, got {}", max_sojourn));
+        }
+
+        if !(1..=MAX_THREAD_PRIORITY).contains(&self.isolation.priority) {
+            problems.push(format!("[isolation] priority must be between 1 and {}, got {}", MAX_THREAD_PRIORITY, self.isolation.priority));
+        }
+        let num_cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(usize::MAX);
+        let isolated_cores = [
+            ("core_send", self.isolation.isolate_send, self.isolation.core_send),
+            ("core_obfuscate", self.isolation.isolate_obfuscate, self.isolation.core_obfuscate),
+            ("core_deobfuscate", self.isolation.isolate_deobfuscate, self.isolation.core_deobfuscate),
+        ];
+        for (name, is_isolated, core) in isolated_cores {
+            if is_isolated && core >= num_cores {
+                problems.push(format!("[isolation] {} is {} but only {} cores are available", name, core, num_cores));
+            }
+        }
+
+        let interfaces = [
+            ("no_obf", Some(&self.interface.no_obf)),
+            ("obf", Some(&self.interface.obf)),
+            ("src_device", self.interface.src_device.as_ref()),
+        ];
+        for (name, interface) in interfaces {
+            if interface.is_some_and(|i| i.is_empty()) {
+                problems.push(format!("[interface] {} must not be empty", name));
+            }
+        }
+        if self.interface.device != DeviceType::Interface {
+            if self.interface.no_obf.len() >= libc::IFNAMSIZ {
+                problems.push(format!("[interface] no_obf must be shorter than {} characters to name a {:?} device", libc::IFNAMSIZ, self.interface.device));
+            }
+            if self.interface.no_obf_io != IoBackend::Datalink {
+                problems.push(format!("[interface] no_obf_io cannot be used with a {:?} device", self.interface.device));
+            }
+            if self.interface.src_device.is_some() {
+                problems.push(format!("[interface] src_device cannot be used with a {:?} device, everything routed into it is obfuscated", self.interface.device));
+            }
+        }
+
+        let mut dsts = vec![self.ip.dst];
+        for peer in &self.peer {
+            if peer.dst.is_ipv4() != self.ip.src.is_ipv4() {
+                problems.push(format!("[[peer]] dst {} must be of the same IP version as [ip] src {}", peer.dst, self.ip.src));
+            }
+            // Slots are told apart by the address of the peer they come from
+            if dsts.contains(&peer.dst) {
+                problems.push(format!("[[peer]] dst {} is used by more than one peer", peer.dst));
+            }
+            dsts.push(peer.dst);
+            if peer.dst_port.is_some() && self.ip.src_port.is_none() {
+                problems.push(format!("[[peer]] dst_port of {} needs [ip] src_port to send slots over UDP", peer.dst));
+            }
+            if peer.dst_port == Some(0) {
+                problems.push(format!("[[peer]] dst_port of {} must not be 0", peer.dst));
+            }
+            if let Some(sizes) = &peer.pattern {
+                if let Err(e) = pattern::validate_pattern(sizes, outer_header_len, is_encrypted, self.general.wire_format) {
+                    problems.push(format!("[[peer]] {}: {}", peer.dst, e));
+                }
+            }
+            if let Some(gaps) = &peer.gaps {
+                let sizes = peer.pattern.as_ref().unwrap_or(&self.pattern.sizes);
+                if let Err(e) = pattern::validate_gaps(gaps, sizes.len()) {
+                    problems.push(format!("[[peer]] {}: {}", peer.dst, e));
+                }
+            }
+            if let Some(rate) = peer.rate.filter(|rate| !rate.is_finite() || *rate <= 0.0) {
+                problems.push(format!("[[peer]] rate of {} must be a positive number of Mbps, got {}", peer.dst, rate));
+            }
+            for e in peer.routes.iter().filter_map(|route| route.parse::<Subnet>().err()) {
+                problems.push(format!("[[peer]] routes of {}: {}", peer.dst, e));
+            }
+            for mac in peer.macs.iter().filter(|mac| mac.parse::<MacAddr>().is_err()) {
+                problems.push(format!("[[peer]] macs of {}: invalid MAC address `{}`", peer.dst, mac));
+            }
+        }
+        // Every peer needs a socket of its own to send its pattern on
+        if !self.peer.is_empty() && self.interface.obf_io == IoBackend::AfXdp {
+            problems.push("[interface] obf_io cannot be af_xdp with more than one peer".to_string());
+        }
+
+        problems
+    }
+}
+
+fn parse_section<T: DeserializeOwned>(table: &toml::Table, name: &str, problems: &mut Vec<String>) -> Option<T> {
+    // A missing section is parsed as an empty one so that defaults apply and missing keys are reported
+    let value = table.get(name).cloned().unwrap_or_else(|| toml::Value::Table(toml::Table::new()));
+    parse_value(value, name, problems)
+}
+
+fn parse_value<T: DeserializeOwned>(value: toml::Value, name: &str, problems: &mut Vec<String>) -> Option<T> {
+    let mut unknown_keys = Vec::new();
+    let section = serde_ignored::deserialize(value, |path| unknown_keys.push(path.to_string()));
+
+    for key in unknown_keys {
+        problems.push(format!("Unknown key `{}` in [{}]", key, name));
+    }
+    match section {
+        Ok(section) => Some(section),
+        Err(e) => {
+            problems.push(format!("[{}] {}", name, e.to_string().trim_end().replace('\n', " ")));
+            None
+        }
+    }
+}
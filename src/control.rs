@@ -0,0 +1,270 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::metrics::Metrics;
+use crate::pattern;
+use crate::queues::priority_queue::{QueueLimits, SlotFormat};
+use crate::queues::round_robin::RoundRobinScheduler;
+use crate::shutdown::Shutdown;
+
+// How often the control thread checks for a shutdown while no client connects
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// A client sending nothing for this long is disconnected so that the next one can be served
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// Only the user running the pipeline may change it
+const SOCKET_MODE: u32 = 0o600;
+
+// What the pipeline threads use to send the pattern, changed at runtime through the control socket
+pub struct Control {
+    scheduler: RwLock<Arc<RoundRobinScheduler>>,
+    // Bumped every time the scheduler is replaced for a new pattern
+    generation: AtomicU64,
+    // In Mbps, f64 bits
+    rate: AtomicU64,
+    paused: AtomicBool,
+    format: SlotFormat,
+    metrics: Arc<Metrics>,
+}
+
+impl Control {
+    pub fn new(pattern: &[usize], rate: f64, format: SlotFormat) -> Control {
+        Control::with_metrics(pattern, rate, format, Arc::new(Metrics::default()))
+    }
+
+    pub fn with_metrics(pattern: &[usize], rate: f64, format: SlotFormat, metrics: Arc<Metrics>) -> Control {
+        // The counters of the pipeline threads can be shared by the tunnels to several peers
+        let rrs = RoundRobinScheduler::new(pattern, pattern::get_pps(rate, pattern), format.clone());
+        Control {
+            scheduler: RwLock::new(Arc::new(rrs)),
+            generation: AtomicU64::new(0),
+            rate: AtomicU64::new(rate.to_bits()),
+            paused: AtomicBool::new(false),
+            format,
+            metrics,
+        }
+    }
+
+    pub fn dst(&self) -> IpAddr {
+        // The peer the pattern is sent to, commands and metrics tell the tunnels apart by it
+        self.format.dst
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        // Shared with the deobfuscating thread, which does not use the scheduler
+        &self.metrics
+    }
+
+    pub fn scheduler(&self) -> Arc<RoundRobinScheduler> {
+        Arc::clone(&self.scheduler.read().unwrap())
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_rate(&self, rate: f64) -> Result<(), String> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(format!("Rate must be a positive number of Mbps, got {}", rate));
+        }
+        // Stored first, a pattern changed at the same time either reads the new rate or is the one updated below
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+        let rrs = self.scheduler.read().unwrap();
+        rrs.set_pps(pattern::get_pps(rate, &rrs.pattern()));
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        // While paused no slot is sent, packets are still queued until the queues are full
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn set_limits(&self, limits: QueueLimits) {
+        // Kept when the pattern is changed, the limits left out follow the rate
+        self.scheduler.read().unwrap().set_limits(limits);
+    }
+
+    pub fn set_gaps(&self, gaps: &[f64]) -> Result<(), String> {
+        // For the current pattern, a new one is sent evenly spaced until gaps are set for it
+        self.scheduler.read().unwrap().set_gaps(gaps)
+    }
+
+    pub fn set_pattern(&self, pattern: &[usize]) -> Result<usize, String> {
+        // Returns how many real packets were still in the previous queues, they are dropped
+        pattern::validate_pattern(pattern, self.format.outer_header_len(), self.format.cipher.is_some(), self.format.wire_format)?;
+
+        let mut rrs = self.scheduler.write().unwrap();
+        let next = RoundRobinScheduler::new(pattern, pattern::get_pps(self.rate(), pattern), self.format.clone());
+        next.resume_seq(&rrs);
+        next.set_limits(rrs.limits());
+        let previous = std::mem::replace(&mut *rrs, Arc::new(next));
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(previous.queued())
+    }
+}
+
+pub fn serve(path: &str, controls: Vec<Arc<Control>>, shutdown: Shutdown) -> io::Result<JoinHandle<()>> {
+    // A socket left behind by a previous run would make bind fail, anything else at path is kept
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))?;
+    listener.set_nonblocking(true)?;
+    let path = path.to_string();
+
+    Ok(thread::spawn(move || {
+        // Clients are served one at a time, commands are cheap
+        while !shutdown.is_requested() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = handle_client(stream, &controls) {
+                        eprintln!("Error on the control socket: {}", e);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) => {
+                    eprintln!("Error accepting on the control socket: {}", e);
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                },
+            }
+        }
+        let _ = fs::remove_file(&path);
+    }))
+}
+
+fn handle_client(stream: UnixStream, controls: &[Arc<Control>]) -> io::Result<()> {
+    // One command per line, each answered with one line of JSON
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = handle_command(&line?, controls);
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+pub fn handle_command(line: &str, controls: &[Arc<Control>]) -> Value {
+    // Replies always have "ok", and "error" when it is false. Commands go to the first peer unless they start with
+    // dst <address>, the replies about a peer say which one it is
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words == ["peers"] {
+        let peers: Vec<Value> = controls.iter().map(|control| get_peer(control)).collect();
+        return json!({ "peers": peers, "ok": true });
+    }
+    let (control, words) = match words.as_slice() {
+        ["dst", dst, words @ ..] => match dst.parse::<IpAddr>().ok().and_then(|dst| controls.iter().find(|control| control.dst() == dst)) {
+            Some(control) => (control, words),
+            None => return json!({ "ok": false, "error": format!("Unknown peer `{}`", dst) }),
+        },
+        words => match controls.first() {
+            Some(control) => (control, words),
+            None => return json!({ "ok": false, "error": "No peer" }),
+        },
+    };
+    let result = match words {
+        ["queues"] => Ok(get_queues(control)),
+        ["stats"] => Ok(get_stats(control)),
+        ["rate"] => Ok(get_rate(control)),
+        ["rate", rate] => parse(rate).and_then(|rate| control.set_rate(rate)).map(|_| get_rate(control)),
+        ["pause"] => {
+            control.set_paused(true);
+            Ok(json!({ "paused": true }))
+        },
+        ["resume"] => {
+            control.set_paused(false);
+            Ok(json!({ "paused": false }))
+        },
+        ["pattern", sizes @ ..] if !sizes.is_empty() => sizes.iter().map(|size| parse(size)).collect::<Result<Vec<usize>, _>>()
+            .and_then(|sizes| control.set_pattern(&sizes))
+            .map(|dropped| {
+                let mut reply = get_rate(control);
+                reply["pattern"] = json!(control.scheduler().pattern());
+                reply["dropped"] = json!(dropped);
+                reply
+            }),
+        ["gaps"] => Ok(get_gaps(control)),
+        ["gaps", gaps @ ..] => gaps.iter().map(|gap| parse(gap)).collect::<Result<Vec<f64>, _>>()
+            .and_then(|gaps| control.set_gaps(&gaps))
+            .map(|_| get_gaps(control)),
+        [] => Err("Empty command".to_string()),
+        _ => Err(format!("Unknown command `{}`", line.trim())),
+    };
+
+    match result {
+        Ok(mut reply) => {
+            reply["dst"] = json!(control.dst());
+            reply["ok"] = json!(true);
+            reply
+        },
+        Err(e) => json!({ "ok": false, "error": e }),
+    }
+}
+
+fn get_peer(control: &Control) -> Value {
+    json!({ "dst": control.dst(), "rate": control.rate(), "pattern": control.scheduler().pattern(), "paused": control.is_paused() })
+}
+
+fn get_queues(control: &Control) -> Value {
+    let rrs = control.scheduler();
+    let queues: Vec<Value> = rrs.queues.iter().enumerate().map(|(i, q)| {
+        let stats = q.stats();
+        json!({
+            "index": i,
+            "length": stats.length,
+            "capacity": q.capacity(),
+            "depth": stats.depth,
+            "pushed": stats.pushed,
+            "dropped": stats.dropped,
+            "expired": stats.expired,
+            "max_packets": q.max_packets(),
+            "max_bytes": q.max_bytes(),
+            "max_sojourn": q.max_sojourn().as_secs_f64(),
+        })
+    }).collect();
+    json!({ "queues": queues })
+}
+
+fn get_stats(control: &Control) -> Value {
+    // Counters start again from 0 when the pattern is changed
+    let stats = control.scheduler().stats();
+    json!({
+        "pushed": stats.pushed(),
+        "dropped": stats.dropped(),
+        "expired": stats.expired(),
+        "padding": stats.padding(),
+        "avg_pad": stats.avg_pad(),
+        "queued": stats.queued(),
+        "paused": control.is_paused(),
+    })
+}
+
+fn get_rate(control: &Control) -> Value {
+    json!({ "rate": control.rate(), "pps": control.scheduler().pps() })
+}
+
+fn get_gaps(control: &Control) -> Value {
+    // Scaled to average to 1, the interval is the average time between two slots
+    let rrs = control.scheduler();
+    json!({ "gaps": rrs.gaps(), "interval": rrs.interval().as_secs_f64() })
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid number `{}`", value))
+}
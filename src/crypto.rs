@@ -0,0 +1,64 @@
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+// XChaCha20 nonces are long enough to be drawn at random for every packet
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+// Bytes added to every slot, the nonce in front and the tag at the end
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn from_hex(key_hex: &str) -> Result<Cipher, String> {
+        let key = parse_key(key_hex)?;
+        Ok(Cipher { aead: XChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    pub fn seal(&self, buffer: &mut [u8]) {
+        // Buffer is [nonce | plaintext | tag], the plaintext is encrypted in place and the nonce and tag filled in
+        assert!(buffer.len() >= OVERHEAD, "Buffer must be at least {} bytes", OVERHEAD);
+        let (nonce, rest) = buffer.split_at_mut(NONCE_LEN);
+        let (data, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+
+        rand::thread_rng().fill_bytes(nonce);
+        let computed_tag = self.aead
+            .encrypt_in_place_detached(XNonce::from_slice(nonce), &[], data)
+            .expect("Slot too large to encrypt");
+        tag.copy_from_slice(&computed_tag);
+    }
+
+    pub fn open<'a>(&self, buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+        // Inverse of seal, returns the plaintext if the packet was sealed with the same key and not modified
+        if buffer.len() < OVERHEAD {
+            return None;
+        }
+        let (nonce, rest) = buffer.split_at_mut(NONCE_LEN);
+        let (data, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+
+        match self.aead.decrypt_in_place_detached(XNonce::from_slice(nonce), &[], data, Tag::from_slice(tag)) {
+            Ok(()) => Some(data),
+            Err(_) => None,
+        }
+    }
+}
+
+pub fn parse_key(key_hex: &str) -> Result<[u8; KEY_LEN], String> {
+    let key_hex = key_hex.trim();
+    if key_hex.len() != 2 * KEY_LEN || !key_hex.is_ascii() {
+        return Err(format!("Key must be {} hexadecimal characters, got {}", 2 * KEY_LEN, key_hex.len()));
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&key_hex[2 * i..2 * i + 2], 16) {
+            Ok(b) => b,
+            Err(_) => return Err(format!("Key has an invalid hexadecimal character around position {}", 2 * i)),
+        };
+    }
+    Ok(key)
+}
@@ -1,65 +1,386 @@
-use pnet::packet::ipv4;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use pnet::packet::{ipv4, ipv6};
 use crate::pattern;
 use crate::hardware_obf;
+use crate::crypto::Cipher;
+use crate::queues::priority_queue::SlotFormat;
+use crate::shim::{self, FragmentHeader, ShimHeader, ShimType, WireFormat};
 
+// Sequence numbers further behind than this are taken as the peer restarting rather than reordering
+const REORDER_WINDOW: u32 = 1 << 16;
+// Packets being reassembled at the same time, the oldest one is given up to start another
+const MAX_REASSEMBLIES: usize = 16;
 
 enum PacketType {
-    Chaff,          // Chaff -> All zeros. Look at byte after addresses (byte 13)
+    Chaff,          // Chaff -> All zeros. Look at the Ethernet header of the inner frame
     Obfuscated,     // Obfuscated -> Other
     // Normal,         // Normal -> N/A Only ditto traffic supported for now
 }
 
-fn get_packet_type(packet: &[u8]) -> PacketType {
+fn get_packet_type(packet: &[u8], outer_header_len: usize) -> PacketType {
     // Get the type of packet, can be one of 3 options
 
-    // Ethertype or id is never 0 byte except in chaff packets
-    
-    if packet[pattern::IP_HEADER_LEN + 2] == 0_u8 && packet[pattern::IP_HEADER_LEN + 3] == 0_u8 {
-        return PacketType::Chaff;
+    // A frame never has zero addresses and a zero ethertype, whatever it carries. Single bytes are not enough,
+    // e.g. IPv6 multicast to 33:33:00:00:00:01
+    let header = &packet[outer_header_len..packet.len().min(outer_header_len + pattern::ETH_HEADER_LEN)];
+    if header.iter().all(|&b| b == 0) {
+        PacketType::Chaff
     } else {
-        return PacketType::Obfuscated;
+        PacketType::Obfuscated
     }
 }
 
-pub fn process_packet(packet: &[u8], ip_src: [u8;4], is_local: bool, is_hw_obfuscation: bool) -> Option<&[u8]> {
-    if packet[pattern::IP_SRC_ADDR_OFFSET..pattern::IP_SRC_ADDR_OFFSET+pattern::IP_ADDR_LEN] != ip_src && !is_local 
-        || packet[pattern::IP_SRC_ADDR_OFFSET..pattern::IP_SRC_ADDR_OFFSET+pattern::IP_ADDR_LEN] == ip_src && is_local {
-        // Src ip is the same if local and different if not
-        assert!(packet.len() >= pattern::IP_HEADER_LEN, "Packet length must be at least {} bytes", pattern::IP_HEADER_LEN); 
+// Slots lost, reordered or replayed on the way, from the shim sequence numbers
+struct SeqTracker {
+    next: Option<u32>,
+    lost: u64,
+    late: u64,
+    replayed: u64,
+    // Bit seq % REORDER_WINDOW is set once seq has arrived, for the REORDER_WINDOW sequence numbers before next
+    seen: Vec<u64>,
+}
 
-        match get_packet_type(packet) {
-            PacketType::Chaff => None,
-            PacketType::Obfuscated => Some(deobfuscate(packet, is_hw_obfuscation)),
-            //_ => None
+impl Default for SeqTracker {
+    fn default() -> Self {
+        SeqTracker { next: None, lost: 0, late: 0, replayed: 0, seen: vec![0; REORDER_WINDOW as usize / 64] }
+    }
+}
+
+impl SeqTracker {
+    fn record(&mut self, seq: u32) -> bool {
+        // False if seq already arrived, the slot is a replay. Only caught within the window, a slot from further
+        // back is taken as the peer restarting
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                self.next = Some(seq.wrapping_add(1));
+                self.set_seen(seq);
+                return true;
+            }
+        };
+        let ahead = seq.wrapping_sub(next);
+        if ahead < REORDER_WINDOW {
+            // Every slot skipped is lost until it shows up late. The ones leaving the window are forgotten
+            self.lost += ahead as u64;
+            self.next = Some(seq.wrapping_add(1));
+            for skipped in 0..ahead {
+                self.clear_seen(next.wrapping_add(skipped));
+            }
+        } else if next.wrapping_sub(seq) <= REORDER_WINDOW {
+            if self.is_seen(seq) {
+                self.replayed += 1;
+                return false;
+            }
+            self.lost = self.lost.saturating_sub(1);
+            self.late += 1;
+        } else {
+            self.next = Some(seq.wrapping_add(1));
+            self.seen.fill(0);
         }
+        self.set_seen(seq);
+        true
+    }
 
-    } else {
-        // Outgoing packet
-        None
+    fn is_seen(&self, seq: u32) -> bool {
+        let bit = seq % REORDER_WINDOW;
+        self.seen[bit as usize / 64] & 1 << (bit % 64) != 0
+    }
+
+    fn set_seen(&mut self, seq: u32) {
+        let bit = seq % REORDER_WINDOW;
+        self.seen[bit as usize / 64] |= 1 << (bit % 64);
+    }
+
+    fn clear_seen(&mut self, seq: u32) {
+        let bit = seq % REORDER_WINDOW;
+        self.seen[bit as usize / 64] &= !(1 << (bit % 64));
+    }
+}
+
+// Packet split in fragments, its parts are copied at their offset as they arrive
+struct Reassembly {
+    id: u16,
+    started: Instant,
+    data: Vec<u8>,
+    offsets: Vec<u16>,
+    received: usize,
+    // Known once the last fragment has arrived
+    length: Option<usize>,
+}
+
+// Fragments of the peer put back together, packets missing fragments for longer than timeout are given up
+struct Reassembler {
+    timeout: Duration,
+    pending: Vec<Reassembly>,
+    timed_out: u64,
+    // Last packet reassembled
+    packet: Vec<u8>,
+}
+
+impl Reassembler {
+    fn new(timeout: Duration) -> Self {
+        Reassembler { timeout, pending: Vec::new(), timed_out: 0, packet: Vec::new() }
+    }
+
+    fn add(&mut self, header: &ShimHeader, fragment: &[u8]) -> Option<&[u8]> {
+        // Returns the packet once its last missing fragment is added
+        let FragmentHeader { id, offset } = FragmentHeader::parse(fragment)?;
+        let part = &fragment[shim::FRAGMENT_HEADER_LEN..];
+        let end = offset as usize + part.len();
+        if end > u16::MAX as usize {
+            return None;
+        }
+
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let pending = self.pending.len();
+        self.pending.retain(|r| now.duration_since(r.started) < timeout);
+        self.timed_out += (pending - self.pending.len()) as u64;
+
+        let position = match self.pending.iter().position(|r| r.id == id) {
+            Some(position) => position,
+            None => {
+                if self.pending.len() == MAX_REASSEMBLIES {
+                    // Oldest first, they are added at the end
+                    self.pending.remove(0);
+                    self.timed_out += 1;
+                }
+                self.pending.push(Reassembly { id, started: now, data: Vec::new(), offsets: Vec::new(), received: 0, length: None });
+                self.pending.len() - 1
+            },
+        };
+
+        let reassembly = &mut self.pending[position];
+        if reassembly.offsets.contains(&offset) {
+            // Duplicate
+            return None;
+        }
+        if reassembly.data.len() < end {
+            reassembly.data.resize(end, 0);
+        }
+        reassembly.data[offset as usize..end].copy_from_slice(part);
+        reassembly.offsets.push(offset);
+        reassembly.received += part.len();
+        if header.flags & shim::FLAG_MORE_FRAGMENTS == 0 {
+            reassembly.length = Some(end);
+        }
+
+        if reassembly.length != Some(reassembly.received) {
+            return None;
+        }
+        let mut reassembly = self.pending.remove(position);
+        std::mem::swap(&mut self.packet, &mut reassembly.data);
+        self.packet.truncate(reassembly.received);
+        Some(&self.packet)
+    }
+}
+
+// Real packets carried by a slot, one unless the peer packed several in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frames<'a> {
+    One(&'a [u8]),
+    // Each packet after its length, see shim::PACKED_LEN_LEN
+    Packed(&'a [u8]),
+    Done,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match std::mem::replace(self, Frames::Done) {
+            Frames::One(frame) => Some(frame),
+            Frames::Packed(packed) => {
+                // A length that does not fit ends the slot, like the zero padding after the last packet
+                let length = u16::from_be_bytes([*packed.first()?, *packed.get(1)?]) as usize;
+                let rest = &packed[shim::PACKED_LEN_LEN..];
+                if length == 0 || length > rest.len() {
+                    return None;
+                }
+                *self = Frames::Packed(&rest[length..]);
+                Some(&rest[..length])
+            },
+            Frames::Done => None,
+        }
     }
 }
 
-fn deobfuscate(packet: &[u8], is_hw_obfuscation: bool) -> &[u8] {
+pub struct Deobfuscator {
+    format: SlotFormat,
+    is_local: bool,
+    is_hw_obfuscation: bool,
+    seq: SeqTracker,
+    fragments: Reassembler,
+    // Encrypted packets are decrypted in here
+    buffer: Vec<u8>,
+}
+
+impl Deobfuscator {
+    pub fn new(format: SlotFormat, is_local: bool, is_hw_obfuscation: bool, reassembly_timeout: Duration) -> Self {
+        Deobfuscator {
+            format,
+            is_local,
+            is_hw_obfuscation,
+            seq: SeqTracker::default(),
+            fragments: Reassembler::new(reassembly_timeout),
+            buffer: Vec::with_capacity(pattern::MTU),
+        }
+    }
+
+    // Slots that never arrived, only known with the shim header
+    pub fn lost(&self) -> u64 {
+        self.seq.lost
+    }
+
+    // Slots that arrived after a slot sent later
+    pub fn late(&self) -> u64 {
+        self.seq.late
+    }
+
+    // Slots that arrived again, dropped
+    pub fn replayed(&self) -> u64 {
+        self.seq.replayed
+    }
+
+    // Packets split in fragments given up because a fragment did not arrive in time
+    pub fn reassembly_timeouts(&self) -> u64 {
+        self.fragments.timed_out
+    }
+
+    pub fn process_packet<'a>(&'a mut self, packet: &'a [u8]) -> Option<Frames<'a>> {
+        // The real packets carried by packet, None for chaff
+        let outer_header_len = self.format.outer_header_len();
+        if packet.len() < outer_header_len {
+            // Not an obfuscated packet
+            return None;
+        }
+        let Deobfuscator { format, is_local, is_hw_obfuscation, seq, fragments, buffer } = self;
+        let ip_src = get_ip_src(packet, format.src)?;
+        if format.udp.is_some() && !is_to_port(packet, format) {
+            // Other UDP traffic, or not UDP at all
+            return None;
+        }
+        if ip_src != format.src && !*is_local || ip_src == format.src && *is_local {
+            // Src ip is the same if local and different if not
+            let inner = match (format.wire_format, &format.cipher) {
+                (WireFormat::Legacy, None) => match get_packet_type(packet, outer_header_len) {
+                    PacketType::Chaff => None,
+                    PacketType::Obfuscated => deobfuscate(packet, format).map(Frames::One),
+                    //_ => None
+                },
+                (WireFormat::Legacy, Some(cipher)) => read_length_prefixed(decrypt(cipher, &packet[outer_header_len..], buffer)?).map(Frames::One),
+                (WireFormat::Shim, None) => read_shim(&packet[outer_header_len..], seq, fragments),
+                (WireFormat::Shim, Some(cipher)) => read_shim(decrypt(cipher, &packet[outer_header_len..], buffer)?, seq, fragments),
+            }?;
+            match inner {
+                // Packet has been obfuscated by tofino
+                // Remove padding ethernet headers 
+                Frames::One(inner) if *is_hw_obfuscation => hardware_obf::deobfuscate_tofino(inner).map(Frames::One),
+                inner => Some(inner),
+            }
+        } else {
+            // Outgoing packet
+            None
+        }
+    }
+}
+
+pub fn select_peer(deobfuscators: &[Deobfuscator], packet: &[u8]) -> Option<usize> {
+    // Index of the deobfuscator of the peer packet comes from, by the source of its outer header. With a single peer
+    // it takes everything, as before there could be several
+    if deobfuscators.len() == 1 {
+        return Some(0);
+    }
+    let local = deobfuscators.first()?.format.src;
+    let ip_src = get_ip_src(packet.get(..pattern::get_ip_header_len(local))?, local)?;
+    deobfuscators.iter().position(|deobfuscator| deobfuscator.format.dst == ip_src)
+}
+
+fn get_ip_src(packet: &[u8], local: IpAddr) -> Option<IpAddr> {
+    // Source of the outer header, None if it is not of the same IP version as the local address
+    match local {
+        IpAddr::V4(_) if packet[0] >> 4 == pattern::IP_VERSION => {
+            let octets: [u8; pattern::IP_ADDR_LEN] = packet[pattern::IP_SRC_ADDR_OFFSET..pattern::IP_SRC_ADDR_OFFSET + pattern::IP_ADDR_LEN].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        },
+        IpAddr::V6(_) if packet[0] >> 4 == pattern::IPV6_VERSION => {
+            let octets: [u8; pattern::IPV6_ADDR_LEN] = packet[pattern::IPV6_SRC_ADDR_OFFSET..pattern::IPV6_SRC_ADDR_OFFSET + pattern::IPV6_ADDR_LEN].try_into().ok()?;
+            Some(IpAddr::from(octets))
+        },
+        _ => None,
+    }
+}
+
+fn is_to_port(packet: &[u8], format: &SlotFormat) -> bool {
+    // Whether the outer header is followed by a UDP header to the local port, the slots of the peer are told apart by it
+    let Some(ports) = format.udp else {
+        return false;
+    };
+    let protocol_offset = match format.src {
+        IpAddr::V4(_) => pattern::IP_PROTOCOL_OFFSET,
+        IpAddr::V6(_) => pattern::IPV6_NEXT_HEADER_OFFSET,
+    };
+    let port_offset = format.ip_header_len() + pattern::UDP_DST_PORT_OFFSET;
+    packet[protocol_offset] == pattern::IP_PROTOCOL_UDP && packet[port_offset..port_offset + 2] == ports.src.to_be_bytes()
+}
+
+fn decrypt<'a>(cipher: &Cipher, slot: &[u8], buffer: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+    // Authenticate and decrypt before looking at anything else, packets that fail are dropped like chaff
+    buffer.clear();
+    buffer.extend_from_slice(slot);
+    cipher.open(buffer)
+}
+
+fn read_shim<'a>(slot: &'a [u8], seq: &mut SeqTracker, fragments: &'a mut Reassembler) -> Option<Frames<'a>> {
+    let header = ShimHeader::parse(slot)?;
+    // Chaff takes a sequence number too, so it counts towards losses
+    if !seq.record(header.seq) {
+        return None;
+    }
+    let length = header.length as usize;
+    if header.packet_type == ShimType::Chaff || length > slot.len() - shim::SHIM_HEADER_LEN {
+        return None;
+    }
+    let inner = &slot[shim::SHIM_HEADER_LEN..shim::SHIM_HEADER_LEN + length];
+    match header.packet_type {
+        ShimType::Fragment => fragments.add(&header, inner).map(Frames::One),
+        ShimType::Aggregate => Some(Frames::Packed(inner)),
+        _ => Some(Frames::One(inner)),
+    }
+}
+
+fn read_length_prefixed(plaintext: &[u8]) -> Option<&[u8]> {
+    if plaintext.len() < pattern::INNER_LEN_LEN {
+        return None;
+    }
+
+    // An inner length of 0 is chaff
+    let length = u16::from_be_bytes([plaintext[0], plaintext[1]]) as usize;
+    if length == 0 || length > plaintext.len() - pattern::INNER_LEN_LEN {
+        return None;
+    }
+    Some(&plaintext[pattern::INNER_LEN_LEN..pattern::INNER_LEN_LEN + length])
+}
+
+fn deobfuscate<'a>(packet: &'a [u8], format: &SlotFormat) -> Option<&'a [u8]> {
+    let outer_header_len = format.outer_header_len();
     // Or else it would be an invalid packet anyway
-    assert!(packet.len() >= pattern::IP_HEADER_LEN, "Packet length must be at least {} bytes", pattern::IP_HEADER_LEN); 
+    assert!(packet.len() >= outer_header_len, "Packet length must be at least {} bytes", outer_header_len); 
 
-    // Try to get length, only support IP packets
-    let pkt = ipv4::Ipv4Packet::new(packet).unwrap();
-    let length= pkt.get_total_length();
+    // Try to get length, only support IP packets. The IPv6 payload length does not count the header
+    let length = match format.src {
+        IpAddr::V4(_) => ipv4::Ipv4Packet::new(packet).unwrap().get_total_length(),
+        IpAddr::V6(_) => ipv6::Ipv6Packet::new(packet).unwrap().get_payload_length().saturating_add(pattern::IPV6_HEADER_LEN as u16),
+    };
 
-    if length <= packet.len() as u16 && length > pattern::IP_HEADER_LEN as u16 {
+    if length <= packet.len() as u16 && length > outer_header_len as u16 {
         // println!("{}, {:?}", pkt.get_destination(), packet);
         // println!("{}", pkt.get_source());
-        // Remove wrapped IP header, and truncate
-        if is_hw_obfuscation {
-            // Packet has been obfuscated by tofino
-            // Remove padding ethernet headers 
-            hardware_obf::deobfuscate_tofino(&packet[pattern::IP_HEADER_LEN..length as usize])
-        } else {
-            &packet[pattern::IP_HEADER_LEN..length as usize]
-        }
+        // Remove wrapped IP and UDP headers, and truncate
+        Some(&packet[outer_header_len..length as usize])
     } else {
         println!("Failed to read length for packet of length {}. Read {}. Returned raw packet.", packet.len() as u16, length);
-        packet
+        Some(packet)
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
-use pnet::packet::ethernet;
+use crate::pattern::{ETH_HEADER_LEN, MAC_ADDR_LEN};
 
 const ETHERTYPE_PADDING_META: u16 = 2184; 
 const PADDING_META_LEN: usize = 18; // Length is 18 Bytes (or 144 bits)
@@ -14,33 +14,43 @@ const ETHERTYPE_1B_PADS_TWO_TIMES_IN_A_ROW: u16 = 2313;
 const ETHERTYPE_LAST_PAD: u16 = 2304;
 
 
-pub fn deobfuscate_tofino(eth_buff: &[u8]) -> &[u8] {
+pub fn deobfuscate_tofino(eth_buff: &[u8]) -> Option<&[u8]> {
+    // The inner frame after the padding headers added by the switch, None if the frame ends before it
+    let ethertype = eth_buff.get(2 * MAC_ADDR_LEN..ETH_HEADER_LEN).map(|b| u16::from_be_bytes([b[0], b[1]]))?;
 
-    let eth_pkt = ethernet::EthernetPacket::new(eth_buff).unwrap();
-
-    // println!("{}, {}", eth_pkt.get_ethertype().0, eth_buff.len());
+    // println!("{}, {}", ethertype, eth_buff.len());
 
     // Problem, when look at ethertype, it is 8B for a 1B padding
     // When recirculate, seem to have 46B next queue header with ethertype at bytes 45,46
     // Right now when I see that I cut 32B so it is as if the remaining 14B are a valid header
     // However, it does not remove the last byte
 
-    match eth_pkt.get_ethertype().0 {
-        ETHERTYPE_PADDING_META => deobfuscate_tofino(&eth_buff[PADDING_META_LEN..]),
-        ETHERTYPE_32B_PADS => deobfuscate_tofino(&eth_buff[32..]),
-        ETHERTYPE_16B_PADS => deobfuscate_tofino(&eth_buff[16..]),
-        ETHERTYPE_8B_PADS => deobfuscate_tofino(&eth_buff[8..]),
-        ETHERTYPE_4B_PADS => deobfuscate_tofino(&eth_buff[4..]),
-        ETHERTYPE_2B_PADS => deobfuscate_tofino(&eth_buff[2..]),
-        ETHERTYPE_1B_PADS => deobfuscate_tofino(&eth_buff[1..]),
-        ETHERTYPE_1B_PADS_TWO_TIMES_IN_A_ROW => deobfuscate_tofino(&eth_buff[1..]),
+    match ethertype {
+        ETHERTYPE_PADDING_META => strip(eth_buff, PADDING_META_LEN),
+        ETHERTYPE_32B_PADS => strip(eth_buff, 32),
+        ETHERTYPE_16B_PADS => strip(eth_buff, 16),
+        ETHERTYPE_8B_PADS => strip(eth_buff, 8),
+        ETHERTYPE_4B_PADS => strip(eth_buff, 4),
+        ETHERTYPE_2B_PADS => strip(eth_buff, 2),
+        ETHERTYPE_1B_PADS => strip(eth_buff, 1),
+        ETHERTYPE_1B_PADS_TWO_TIMES_IN_A_ROW => strip(eth_buff, 1),
         ETHERTYPE_LAST_PAD => {
-            if eth_buff[14] == 69 {
-                // Start of ipv4 frame, return that
-                return &eth_buff[14..];
+            // Start of the inner frame if the next header is not padding, whatever the frame carries
+            let next_ethertype = eth_buff.get(ETH_HEADER_LEN + 2 * MAC_ADDR_LEN..2 * ETH_HEADER_LEN).map(|b| u16::from_be_bytes([b[0], b[1]]));
+            if next_ethertype.is_some_and(|ethertype| !is_padding(ethertype)) {
+                return eth_buff.get(ETH_HEADER_LEN..);
             }
-            deobfuscate_tofino(&eth_buff[32..])
+            strip(eth_buff, 32)
         },
-        _ => &eth_buff[14..]
+        _ => eth_buff.get(ETH_HEADER_LEN..)
     }
+}
+
+fn strip(eth_buff: &[u8], len: usize) -> Option<&[u8]> {
+    deobfuscate_tofino(eth_buff.get(len..)?)
+}
+
+fn is_padding(ethertype: u16) -> bool {
+    matches!(ethertype, ETHERTYPE_PADDING_META | ETHERTYPE_32B_PADS | ETHERTYPE_16B_PADS | ETHERTYPE_8B_PADS | ETHERTYPE_4B_PADS
+        | ETHERTYPE_2B_PADS | ETHERTYPE_1B_PADS | ETHERTYPE_1B_PADS_TWO_TIMES_IN_A_ROW | ETHERTYPE_LAST_PAD)
 }
\ No newline at end of file
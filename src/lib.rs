@@ -1,23 +1,42 @@
+pub mod config;
 pub mod pattern;
-mod deobfuscate;
+pub mod deobfuscate;
 pub mod queues;
 mod feature_flags;
 pub mod hardware_obf;
-
-use std::fs::OpenOptions;
+pub mod crypto;
+pub mod pcap;
+pub mod optimizer;
+pub mod shim;
+pub mod tun;
+pub mod packet_io;
+pub mod shutdown;
+pub mod control;
+pub mod metrics;
+pub mod peer;
+pub mod pacer;
+pub mod adaptive;
+
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::net;
 use crate::queues::round_robin;
+use crate::packet_io::PacketIo;
 use pnet::datalink;
 use pnet::datalink::Channel::Ethernet;
 use std::error::Error;
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
-use toml::Value;
 
-const FACTOR_MEGABITS: f64 = 1e6;
-const BITS_PER_BYTE: f64 = 8.0;
+// Most slots sent with a single kick when transmit is behind schedule
+const MAX_TX_BATCH: usize = 64;
+// How often sequence number losses seen by the deobfuscator are reported
+const LOSS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// How often transmit reports the slots it had to burst or miss to keep up with the schedule
+const CATCH_UP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// How often transmit checks whether it was resumed
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct ChannelCustom {
     pub tx: Box<dyn datalink::DataLinkSender>,
@@ -25,51 +44,129 @@ pub struct ChannelCustom {
     pub mac_addr: Option<pnet::util::MacAddr>,
 }
 
-pub fn run(settings: Value) -> Result<(), Box<dyn Error>> {
-    
-    let rate = settings["general"]["rate"].as_float().expect("Rate setting not found");
-    let pps = rate / pattern::get_average_pattern_length() * FACTOR_MEGABITS / BITS_PER_BYTE;
+pub fn run(config: config::Config) -> Result<(), Box<dyn Error>> {
+    config.validate()?;
+    // Before any thread is spawned, they must all leave SIGINT and SIGTERM to the thread handling them
+    let shutdown = shutdown::Shutdown::on_signals()?;
+    let pattern = config.pattern.sizes;
+
+    let rate = config.general.rate;
+    let pps = pattern::get_pps(rate, &pattern);
     // println!("{}", pps);
 
-    let pad_log_interval = match settings["general"]["pad_log_interval"].as_float()  {
+    let pad_log_interval = match config.general.pad_log_interval {
         Some(p) => p,
         None => 0.1*pps,
     };
     
-    let save_data = settings["general"]["save"].as_bool().expect("Save setting not found");
-    let is_local = settings["general"]["local"].as_bool().expect("Is local setting not found");
-    let is_log = settings["general"]["log"].as_bool().expect("Is log setting not found");
-    let is_hw_obfuscation = settings["general"]["hw_obfuscation"].as_bool().expect("Obfuscation Mode setting not found");
-    let is_backbone = settings["general"]["backbone"].as_bool().expect("Is backbone setting not found");
-
-    let avg_pkt_size = pattern::PATTERN.iter().sum::<usize>() as f64 / pattern::PATTERN.len() as f64;
+    let save_data = config.general.save;
+    let is_local = config.general.local;
+    let is_log = config.general.log;
+    let is_hw_obfuscation = config.general.hw_obfuscation;
+    let is_backbone = config.general.backbone;
+    let drain_timeout = Duration::from_secs_f64(config.general.drain_timeout);
+    let reassembly_timeout = Duration::from_secs_f64(config.general.reassembly_timeout);
+
+    let avg_pkt_size = pattern.iter().sum::<usize>() as f64 / pattern.len() as f64;
     println!("Sending {} packets/s with avg size of {}B => rate = {:.2} KB/s", pps, avg_pkt_size, pps*avg_pkt_size/1000.0);
 
-    let ip_src = parse_ip(settings["ip"]["src"].as_str().expect("Src ip address not found").to_string());
-    let ip_dst = parse_ip(settings["ip"]["dst"].as_str().expect("Dst ip address not found").to_string());
-
-    println!("Setting up queues for pattern {:?}", pattern::PATTERN);
-    let rrs = Arc::new(round_robin::RoundRobinScheduler::new(pattern::PATTERN.len(), pps, ip_src, ip_dst));
-
-    let tx_queue = Arc::clone(&rrs);
-    let rx_queue = Arc::clone(&rrs);
-
-    let is_deobf_isolated = settings["isolation"]["isolate_deobfuscate"].as_bool().expect("Isolate deobf setting not found");
-    let core_id_deobf = settings["isolation"]["core_deobfuscate"].as_integer().expect("Core deobf setting not found") as usize;
-
-    let is_send_isolated = settings["isolation"]["isolate_send"].as_bool().expect("Isolate send setting not found");  
-    let core_id_send = settings["isolation"]["core_send"].as_integer().expect("Core send setting not found") as usize;
+    let ip_src = config.ip.src;
+    let ip_dst = config.ip.dst;
 
-    let is_obf_isolated = settings["isolation"]["isolate_obfuscate"].as_bool().expect("Isolate obf setting not found");     
-    let core_id_obf = settings["isolation"]["core_obfuscate"].as_integer().expect("Core obf setting not found") as usize;
-
-    let priority = settings["isolation"]["priority"].as_integer().expect("Thread priority setting not found") as i32; 
-
-    let interface_obfuscate = settings["interface"]["no_obf"].as_str().expect("Unobfuscated interface setting not found").to_string(); 
-    let interface_transmit: String = settings["interface"]["obf"].as_str().expect("Obfuscated interface setting not found").to_string(); 
-    let interface_deobfuscate_input = settings["interface"]["obf"].as_str().expect("Unobfuscated interface setting not found").to_string(); 
-    let interface_deobfuscate_output: String = settings["interface"]["no_obf"].as_str().expect("Obfuscated interface setting not found").to_string(); 
-    let src_device: String = settings["interface"]["src_device"].as_str().expect("Obfuscated interface setting not found").to_string(); 
+    let cipher = match &config.crypto.key {
+        Some(key) => Some(crypto::Cipher::from_hex(key)?),
+        None => None,
+    };
+    let mut slot_format = queues::priority_queue::SlotFormat::new(ip_src, ip_dst, cipher, config.general.wire_format);
+    slot_format.aggregate = config.general.aggregate;
+    slot_format.udp = config.ip.src_port.map(|src| queues::priority_queue::UdpPorts { src, dst: config.ip.dst_port.unwrap_or(src) });
+    let deobf_format = slot_format.clone();
+
+    // The first peer is [ip] dst, every [[peer]] gets a scheduler of its own and the frames routed to it
+    println!("Setting up queues for pattern {:?}", pattern);
+    let metrics = Arc::new(metrics::Metrics::default());
+    let mut controls = vec![Arc::new(control::Control::with_metrics(&pattern, rate, slot_format.clone(), Arc::clone(&metrics)))];
+    if let Some(gaps) = &config.pattern.gaps {
+        controls[0].set_gaps(gaps)?;
+    }
+    controls[0].set_limits(config.queues.limits());
+    let mut deobf_formats = vec![deobf_format.clone()];
+    let mut next_hops = vec![config.general.next_hop];
+    let mut classifier = peer::Classifier::default();
+    for peer_config in &config.peer {
+        let mut format = slot_format.clone();
+        format.dst = peer_config.dst;
+        format.udp = slot_format.udp.map(|ports| queues::priority_queue::UdpPorts { src: ports.src, dst: peer_config.dst_port.unwrap_or(ports.dst) });
+        let peer_pattern = peer_config.pattern.as_ref().unwrap_or(&pattern);
+        let peer_rate = peer_config.rate.unwrap_or(rate);
+        println!("Setting up queues for pattern {:?} to peer {}", peer_pattern, peer_config.dst);
+
+        let index = controls.len();
+        for route in &peer_config.routes {
+            classifier.add_subnet(route.parse()?, index);
+        }
+        for mac in &peer_config.macs {
+            classifier.add_mac(mac.parse()?, index);
+        }
+        let peer_control = control::Control::with_metrics(peer_pattern, peer_rate, format.clone(), Arc::clone(&metrics));
+        // Gaps go with the pattern they were written for
+        let peer_gaps = if peer_config.pattern.is_some() { peer_config.gaps.as_ref() } else { peer_config.gaps.as_ref().or(config.pattern.gaps.as_ref()) };
+        if let Some(gaps) = peer_gaps {
+            peer_control.set_gaps(gaps)?;
+        }
+        peer_control.set_limits(config.queues.limits());
+        controls.push(Arc::new(peer_control));
+        deobf_formats.push(format);
+        next_hops.push(peer_config.next_hop.unwrap_or(config.general.next_hop));
+    }
+    let rx_controls = controls.clone();
+
+    let is_deobf_isolated = config.isolation.isolate_deobfuscate;
+    let core_id_deobf = config.isolation.core_deobfuscate;
+
+    let is_send_isolated = config.isolation.isolate_send;
+    let core_id_send = config.isolation.core_send;
+
+    let is_obf_isolated = config.isolation.isolate_obfuscate;
+    let core_id_obf = config.isolation.core_obfuscate;
+
+    let priority = config.isolation.priority;
+
+    let interface_obfuscate = config.interface.no_obf.clone();
+    let interface_transmit = config.interface.obf.clone();
+    let interface_deobfuscate_input = config.interface.obf.clone();
+    let interface_deobfuscate_output = config.interface.no_obf.clone();
+
+    // Open the unobfuscated side here, a TUN/TAP device can only be created once and is shared by both directions
+    let (ch_obfuscate, ch_deobfuscate_output, src_mac) = match config.interface.device {
+        tun::DeviceType::Interface => {
+            let (ch_deobfuscate_output, ch_obfuscate) = open_io(&interface_obfuscate, config.interface.no_obf_io, None)?;
+            let src_mac = get_mac_addr(config.interface.src_device())?;
+            (ch_obfuscate, ch_deobfuscate_output, src_mac)
+        },
+        device_type => {
+            // Inner frames must fit in the largest slot of every peer, unless they can be split in fragments
+            let max_capacity = controls.iter().map(|control| control.scheduler().queues.iter().map(|q| q.capacity()).max().unwrap_or(0)).max().unwrap_or(0);
+            let mtu = match config.general.wire_format {
+                shim::WireFormat::Shim => pattern::MTU.max(max_capacity - pattern::ETH_HEADER_LEN),
+                shim::WireFormat::Legacy => max_capacity - pattern::ETH_HEADER_LEN,
+            };
+            let device = tun::TunDevice::create(&interface_obfuscate, device_type, mtu)?;
+            let ch_obfuscate: Box<dyn PacketIo> = Box::new(device.channel()?);
+            let src_mac = ch_obfuscate.mac_addr().unwrap_or_default();
+            (ch_obfuscate, Box::new(device.channel()?) as Box<dyn PacketIo>, src_mac)
+        },
+    };
+    // Only the slots of the peer are taken from the kernel, locally they are the ones sent by this side
+    let slot_filter = packet_io::af_xdp::XdpFilter { src: if is_local { ip_src } else { ip_dst }, udp_port: slot_format.udp.map(|ports| ports.src) };
+    let (ch_transmit, ch_deobfuscate_input) = open_io(&interface_transmit, config.interface.obf_io, Some(&slot_filter))?;
+    // Each peer's pattern is sent on its own, the receiving side of the other ones is left unused
+    let mut ch_transmits = vec![ch_transmit];
+    for _ in 1..controls.len() {
+        ch_transmits.push(open_io(&interface_transmit, config.interface.obf_io, Some(&slot_filter))?.0);
+    }
+    let catch_up = config.general.catch_up;
+    let pacers = controls.iter().map(|_| pacer::open(config.general.pacer)).collect::<Result<Vec<_>, _>>()?;
 
     if is_log {
         println!("Listening for Ethernet frames on interface {}...", interface_obfuscate);
@@ -77,10 +174,50 @@ pub fn run(settings: Value) -> Result<(), Box<dyn Error>> {
         println!("Listening for obfuscated Ethernet frames on interface {}...", interface_deobfuscate_input);
         println!("Sending deobfuscated Ethernet frames on interface {}...", interface_deobfuscate_output);
         println!("Send on specific cores = {}", is_send_isolated);
+        println!("Pacer = {:?}", config.general.pacer);
+        println!("Catch-up policy = {:?}", config.general.catch_up);
+        println!("Adaptive rate levels = {:?}", config.adaptive.levels);
         println!("Using hardware obfuscation = {}", is_hw_obfuscation);
         println!("Running as a backbone router = {}", is_backbone);
+        println!("Encrypting slots = {}", deobf_format.cipher.is_some());
+        println!("Wire format = {:?}", deobf_format.wire_format);
+        println!("Packing packets in slots = {}", deobf_format.aggregate);
+        println!("Peers = {}", controls.len());
+        println!("Unobfuscated device type = {:?}", config.interface.device);
+        println!("Obfuscated interface backend = {:?}", config.interface.obf_io);
+        println!("Unobfuscated interface backend = {:?}", config.interface.no_obf_io);
     }
 
+    // Control socket and metrics exporters, they keep running while the queues are drained
+    let services_shutdown = shutdown::Shutdown::new();
+    let mut service_handles = Vec::new();
+    if let Some(path) = &config.general.control_socket {
+        println!("Listening for commands on {}", path);
+        service_handles.push(control::serve(path, controls.clone(), services_shutdown.clone())?);
+    }
+    if let Some(addr) = config.metrics.listen {
+        println!("Serving metrics on http://{}/metrics", addr);
+        service_handles.push(metrics::serve_http(addr, controls.clone(), Arc::clone(&metrics), services_shutdown.clone())?);
+    }
+    if let Some(path) = &config.metrics.textfile {
+        let interval = Duration::from_secs_f64(config.metrics.textfile_interval);
+        service_handles.push(metrics::serve_textfile(path.clone(), interval, controls.clone(), Arc::clone(&metrics), services_shutdown.clone()));
+    }
+    if !config.adaptive.levels.is_empty() {
+        // Peers with a rate of their own keep it
+        let mut adapted = vec![(ip_dst, Arc::clone(&controls[0]))];
+        for (peer_config, peer_control) in config.peer.iter().zip(&controls[1..]) {
+            if peer_config.rate.is_none() {
+                adapted.push((peer_config.dst, Arc::clone(peer_control)));
+            }
+        }
+        service_handles.push(adaptive::spawn(adapted, config.adaptive.clone(), services_shutdown.clone()));
+    }
+
+    let deobf_metrics = Arc::clone(&metrics);
+    let shutdown_obf = shutdown.clone();
+    let shutdown_deobf = shutdown.clone();
+
     // Spawn thread for obfuscating packets
     let obf_handle = thread::spawn(move || {
         if is_obf_isolated {
@@ -98,31 +235,35 @@ pub fn run(settings: Value) -> Result<(), Box<dyn Error>> {
             }
         }
         if feature_flags::FF_NO_REORDERING {
-            obfuscate_data_in_order(&interface_obfuscate, rx_queue, pps, pad_log_interval, save_data);
+            obfuscate_data_in_order(ch_obfuscate, &rx_controls, &classifier, pad_log_interval, save_data, &shutdown_obf);
         } else {
-            obfuscate_data(&interface_obfuscate, &src_device, rx_queue, pps, pad_log_interval, save_data);
+            obfuscate_data(ch_obfuscate, src_mac, &rx_controls, &classifier, pad_log_interval, save_data, &shutdown_obf);
         }
     });
 
-    // Spawn thread for sending obfuscated packets
-    let send_handle = thread::spawn(move || {
-        if is_send_isolated {
-            unsafe {
-                let mut cpuset: libc::cpu_set_t = std::mem::zeroed();
-                libc::CPU_SET(core_id_send, &mut cpuset);
-                libc::sched_setaffinity(0, std::mem::size_of_val(&cpuset), &cpuset);
-
-                let thread =  libc::pthread_self();
-                let param = libc::sched_param { sched_priority: priority };
-                let result = libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param as *const libc::sched_param);
-                if result != 0 {
-                    panic!("Failed to set thread priority");
+    // Spawn a thread per peer for sending obfuscated packets, each keeps the schedule of its own pattern
+    let mut send_handles = Vec::new();
+    for ((ch_transmit, tx_pacer), tx_control) in ch_transmits.into_iter().zip(pacers).zip(controls) {
+        let shutdown_send = shutdown.clone();
+        send_handles.push(thread::spawn(move || {
+            if is_send_isolated {
+                unsafe {
+                    let mut cpuset: libc::cpu_set_t = std::mem::zeroed();
+                    libc::CPU_SET(core_id_send, &mut cpuset);
+                    libc::sched_setaffinity(0, std::mem::size_of_val(&cpuset), &cpuset);
+
+                    let thread =  libc::pthread_self();
+                    let param = libc::sched_param { sched_priority: priority };
+                    let result = libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param as *const libc::sched_param);
+                    if result != 0 {
+                        panic!("Failed to set thread priority");
+                    }
                 }
             }
-        }
 
-        transmit(&interface_transmit, tx_queue, pps, save_data);
-    });
+            transmit(ch_transmit, tx_pacer, catch_up, tx_control, save_data, &shutdown_send, drain_timeout);
+        }));
+    }
 
     // Spawn thread for sending deobfuscating and forwarding packets
     let deobf_handle = thread::spawn(move || {
@@ -134,13 +275,24 @@ pub fn run(settings: Value) -> Result<(), Box<dyn Error>> {
             }
         }
 
-        deobfuscate_data(&interface_deobfuscate_input, &interface_deobfuscate_output, ip_src, is_local, is_hw_obfuscation, is_backbone);
+        let mut deobfuscators: Vec<deobfuscate::Deobfuscator> = deobf_formats.into_iter()
+            .map(|format| deobfuscate::Deobfuscator::new(format, is_local, is_hw_obfuscation, reassembly_timeout))
+            .collect();
+        let next_hops = if is_backbone { Some(next_hops.as_slice()) } else { None };
+        deobfuscate_data(ch_deobfuscate_input, ch_deobfuscate_output, &mut deobfuscators, &deobf_metrics, next_hops, is_log, &shutdown_deobf);
     });
 
-    // Wait for both threads to finish
+    // Wait for the threads to finish, they stop on SIGINT or SIGTERM or when an interface goes away
     obf_handle.join().expect("Obfuscating thread panicked");
-    send_handle.join().expect("Sending thread panicked");
+    for send_handle in send_handles {
+        send_handle.join().expect("Sending thread panicked");
+    }
     deobf_handle.join().expect("Deobfuscating thread panicked");
+    services_shutdown.request();
+    for handle in service_handles {
+        handle.join().expect("Service thread panicked");
+    }
+    println!("Shut down");
 
     Ok(())
 }
@@ -157,8 +309,9 @@ pub fn get_channel(interface_name: &str) -> Result<ChannelCustom, &'static str>{
     
     let mac_addr = interface.mac;
 
-    // Create a channel to receive Ethernet frames
-    let (tx, rx) = match datalink::channel(&interface, Default::default()) {
+    // Create a channel to receive Ethernet frames, with a timeout so that a shutdown is noticed without traffic
+    let channel_config = datalink::Config { read_timeout: Some(packet_io::RECV_TIMEOUT), ..Default::default() };
+    let (tx, rx) = match datalink::channel(&interface, channel_config) {
         Ok(Ethernet(tx, rx)) => (tx, rx),
         Ok(_) => return Err("Unknown channel type"),
         Err(e) => panic!("Failed to create channel {e}"),
@@ -173,29 +326,56 @@ pub fn get_channel(interface_name: &str) -> Result<ChannelCustom, &'static str>{
     Ok(ch)
 }
 
-fn transmit(obf_output_interface: &str, rrs: Arc<round_robin::RoundRobinScheduler>, pps: f64, save_data: bool) {
+// Sending and receiving side of an interface
+type IoPair = (Box<dyn PacketIo>, Box<dyn PacketIo>);
+
+fn open_io(interface_name: &str, backend: packet_io::IoBackend, filter: Option<&packet_io::af_xdp::XdpFilter>) -> Result<IoPair, Box<dyn Error>> {
+    // filter only applies to AF_XDP, the other backends see every packet of the interface
+    Ok(match backend {
+        packet_io::IoBackend::Datalink => (Box::new(get_channel(interface_name)?), Box::new(get_channel(interface_name)?)),
+        packet_io::IoBackend::PacketMmap => (
+            Box::new(packet_io::packet_mmap::PacketMmap::open_tx(interface_name)?),
+            Box::new(packet_io::packet_mmap::PacketMmap::open_rx(interface_name)?),
+        ),
+        packet_io::IoBackend::AfXdp => {
+            // Both sides share the socket and its UMEM
+            let (tx, rx) = packet_io::af_xdp::open(interface_name, filter)?;
+            (Box::new(tx), Box::new(rx))
+        },
+    })
+}
+
+fn get_mac_addr(interface_name: &str) -> Result<pnet::util::MacAddr, &'static str> {
+    match datalink::interfaces().into_iter().find(|iface| iface.name == interface_name) {
+        Some(iface) => iface.mac.ok_or("Network interface has no mac address"),
+        None => Err("Failed to find network interface"),
+    }
+}
+
+// Sends the pattern of control's scheduler on io until it is closed or a shutdown is requested, popping real packets
+// or chaff from it, with pacer waiting for the time each slot is due and catch_up deciding what happens once it is
+// late. After a shutdown the pattern goes on until the real packets left are sent or drain_timeout has passed
+pub fn transmit<T: PacketIo, P: pacer::Pacer>(mut io: T, mut pacer: P, catch_up: pacer::CatchUp, control: Arc<control::Control>, save_data: bool, shutdown: &shutdown::Shutdown, drain_timeout: Duration) {
     println!("Transmitting data...");
 
-    let mut ch_tx = match get_channel(obf_output_interface) {
-        Ok(tx) => tx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
+    let mut rrs = control.scheduler();
+    let mut generation = control.generation();
+    let metrics = control.metrics();
 
     // Keep track of time
-    let interval = Duration::from_nanos((1e9/pps) as u64);
+    let interval = rrs.interval();
     println!("Sending packets in intervals of {:?}", interval);
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(save_data) // Overwrite
-        .create(true)
-        .open("data.csv")
-        .expect("Could not open file");
-
     if save_data {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(save_data) // Overwrite
+            .create(true)
+            .open("data.csv")
+            .expect("Could not open file");
         writeln!(file, "Iteration,Time").expect("Failed to write to file");
 
-        write_params_to_file(save_data, interval.as_nanos());
+        write_params_to_file(save_data, interval.as_nanos(), &rrs.pattern());
     }
     
     //let interval = Duration::from_nanos(100);
@@ -206,36 +386,98 @@ fn transmit(obf_output_interface: &str, rrs: Arc<round_robin::RoundRobinSchedule
     // for _ in 0..NUM_PKTS_TO_SAVE as usize {
 
     let mut last_iteration_time = Instant::now();
+    let mut last_catch_up_log = Instant::now();
+    let mut last_behind = (0, 0);
+    let mut batched = 0;
+    let mut slots_sent: u64 = 0;
+    let mut drain_deadline = None;
     loop {
-        let packet = rrs.pop(current_q);
-        current_q = (current_q + 1) % pattern::PATTERN.len();
+        if shutdown.is_requested() {
+            let deadline = *drain_deadline.get_or_insert_with(|| Instant::now() + drain_timeout);
+            if rrs.queued() == 0 || Instant::now() >= deadline {
+                break;
+            }
+        }
+        if control.generation() != generation {
+            // New pattern, start from its first slot
+            generation = control.generation();
+            rrs = control.scheduler();
+            current_q = 0;
+        }
+        if control.is_paused() {
+            // The schedule starts again from the time it is resumed
+            thread::sleep(PAUSE_POLL_INTERVAL);
+            last_iteration_time = Instant::now();
+            continue;
+        }
+        // The slot is written straight to the output buffer of io, last_iteration_time is when it was due
+        let q = current_q;
+        metrics.pacing_deviation.record(last_iteration_time.elapsed());
+        let result = io.send_with(rrs.slot_len(q), &mut |buffer| rrs.pop_into(q, buffer));
+        current_q = (current_q + 1) % rrs.queues.len();
 
         // println!("Transmit packet of length {}", packet.len());
-        match ch_tx.tx.send_to(&packet, None) {
-            Some(res) => {
-                match res {
-                    Ok(_) => (),
-                    Err(e) => println!("Error sending frame: {}", e),
-                }
-            }
-            None => {
-                println!("No packets to send");
-            }
+        match result {
+            Ok(_) => slots_sent += 1,
+            Err(e) if packet_io::is_closed(&e) => break,
+            Err(e) => {
+                metrics.send_errors.inc();
+                println!("Error sending frame: {}", e);
+            },
         }
 
-        // Calculate time to sleep
+        // Calculate time to sleep, the rate and the gaps may have changed. A gap of 0 is meant to be back to back
+        let gap = rrs.gap(q);
+        let next_time = last_iteration_time + gap;
         let elapsed_time = last_iteration_time.elapsed();
-        let sleep_time = if elapsed_time < interval {
-            interval - elapsed_time
-        } else {
-            Duration::new(0, 0)
-        };
-        // Sleep for the remaining time until the next iteration
-        thread::sleep(sleep_time);
-        if elapsed_time > interval {
+        let is_late = !gap.is_zero() && elapsed_time > gap;
+
+        // Kick the slot now unless the next one is already due and sent right away, late slots then go out with a
+        // single kick
+        batched += 1;
+        if !(is_late && catch_up == pacer::CatchUp::Burst) || batched >= MAX_TX_BATCH {
+            match io.flush() {
+                Ok(_) => (),
+                Err(e) if packet_io::is_closed(&e) => break,
+                Err(e) => {
+                    metrics.send_errors.inc();
+                    println!("Error sending frame: {}", e);
+                },
+            }
+            batched = 0;
+        }
+        // Wait for the remaining time until the next iteration
+        pacer.wait_until(next_time);
+        last_iteration_time = next_time;
+        if is_late {
             // println!("Ran out of time processing {:?} at pkt {}", elapsed_time, count);
+            metrics.timer_overruns.inc();
+            match catch_up {
+                pacer::CatchUp::Burst => metrics.slots_bursted.inc(),
+                pacer::CatchUp::Skip => {
+                    // On to the first slot still ahead, the queues of the others are passed over
+                    let (passed, next_q, next_time) = skip_passed(&rrs, current_q, next_time, Instant::now());
+                    metrics.slots_missed.add(passed);
+                    current_q = next_q;
+                    last_iteration_time = next_time;
+                },
+                pacer::CatchUp::Reanchor => {
+                    // The next slot goes out now, the schedule carries on from it
+                    let (passed, _, _) = skip_passed(&rrs, current_q, next_time, Instant::now());
+                    metrics.slots_missed.add(passed.saturating_sub(1));
+                    last_iteration_time = Instant::now();
+                },
+            }
+        }
+        if last_catch_up_log.elapsed() >= CATCH_UP_LOG_INTERVAL {
+            // Only report when it fell behind since the last report
+            let behind = (metrics.slots_bursted.get(), metrics.slots_missed.get());
+            if behind != last_behind {
+                println!("Fell behind schedule: {} slots bursted and {} missed so far", behind.0, behind.1);
+                last_behind = behind;
+            }
+            last_catch_up_log = Instant::now();
         }
-        last_iteration_time = last_iteration_time + interval;
         
         // if save_data {
         //     let elapsed_time = last_iteration_time.elapsed();
@@ -245,7 +487,11 @@ fn transmit(obf_output_interface: &str, rrs: Arc<round_robin::RoundRobinSchedule
         // count += 1;
     }
 
-    // println!("Done trasnmitting data!");
+    // Slots still batched go out now, the interface may already be gone
+    let _ = io.flush();
+    println!("Sent {} slots, dropped {} real packets still queued", slots_sent, rrs.queued());
+    println!("Slots were sent {:?} after their deadline on average, {:?} at most", metrics.pacing_deviation.mean(), metrics.pacing_deviation.max());
+    println!("Fell behind schedule {} times, {} slots bursted and {} missed", metrics.timer_overruns.get(), metrics.slots_bursted.get(), metrics.slots_missed.get());
 
     // if save_data {
     //     println!("Saving...");
@@ -256,45 +502,93 @@ fn transmit(obf_output_interface: &str, rrs: Arc<round_robin::RoundRobinSchedule
     // }
 }
 
-fn obfuscate_data(input_interface: &str, src_device: &str, rrs: Arc<round_robin::RoundRobinScheduler>, pps: f64, pad_log_interval: f64, save_data: bool) {
-    let mut ch_rx = match get_channel(input_interface) {
-        Ok(rx) => rx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
+// Where obfuscate_data pushes the frames of one peer, refreshed when the pattern of the peer is changed
+struct PushTarget {
+    control: Arc<control::Control>,
+    rrs: Arc<round_robin::RoundRobinScheduler>,
+    generation: u64,
+    psv: Vec<(usize, usize)>,
+    // Next queue when the queues are not reordered
+    current_q: usize,
+}
 
-    let ch_src = match get_channel(src_device) {
-        Ok(rx) => rx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
+impl PushTarget {
+    fn new(control: &Arc<control::Control>) -> Self {
+        let rrs = control.scheduler();
+        PushTarget {
+            control: Arc::clone(control),
+            generation: control.generation(),
+            psv: pattern::get_push_state_vector(&rrs.pattern()),
+            rrs,
+            current_q: 0,
+        }
+    }
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(save_data) // Overwrite
-        .create(true)
-        .open("pad.csv")
-        .expect("Could not open file");
+    fn refresh(&mut self) {
+        if self.control.generation() != self.generation {
+            // New pattern, push to its queues from now on
+            *self = PushTarget::new(&self.control);
+        }
+    }
+}
 
-    if save_data {
-        writeln!(file, "Iteration,Pad").expect("Failed to write to file");
+fn get_avg_pad(targets: &[PushTarget]) -> f64 {
+    // Over the packets pushed to every peer
+    let (padding, pushed) = targets.iter().map(|target| target.rrs.stats()).fold((0, 0), |(padding, pushed), stats| (padding + stats.padding(), pushed + stats.pushed()));
+    if pushed == 0 { 0.0 } else { padding as f64 / pushed as f64 }
+}
+
+fn skip_passed(rrs: &round_robin::RoundRobinScheduler, mut q: usize, mut time: Instant, now: Instant) -> (u64, usize, Instant) {
+    // Slots from the one of queue q on, due at time, whose time has passed by now, with the queue of the first slot
+    // still ahead and when it is due. The gaps of a pattern do not add up to 0, a round of it always moves time on
+    let mut passed = 0;
+    while time <= now {
+        time += rrs.gap(q);
+        q = (q + 1) % rrs.queues.len();
+        passed += 1;
     }
+    (passed, q, time)
+}
+
+// Pushes the frames received on io to the scheduler of the peer of controls classifier picks, until io is closed or a
+// shutdown is requested, only frames from io's or src_mac's address if io has one
+pub fn obfuscate_data<T: PacketIo>(mut io: T, src_mac: pnet::util::MacAddr, controls: &[Arc<control::Control>], classifier: &peer::Classifier, pad_log_interval: f64, save_data: bool, shutdown: &shutdown::Shutdown) {
+    let mut file = if save_data {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(save_data) // Overwrite
+            .create(true)
+            .open("pad.csv")
+            .expect("Could not open file");
+        writeln!(file, "Iteration,Pad").expect("Failed to write to file");
+        Some(file)
+    } else {
+        None
+    };
 
     let mut count = 0;
-    let mut psv = pattern::get_push_state_vector();
-    let mac_addr = ch_rx.mac_addr.unwrap();
-    let src_mac = ch_src.mac_addr.unwrap();
+    let mut dropped = 0;
+    let mut targets: Vec<PushTarget> = controls.iter().map(PushTarget::new).collect();
+    let metrics = controls[0].metrics();
+    let mac_addr = io.mac_addr();
     // println!("src mac: {:?} or {:?}", mac_addr, src_mac);
     // Process received Ethernet frames
-    loop { 
-        match ch_rx.rx.next() {
+    while !shutdown.is_requested() {
+        targets.iter_mut().for_each(PushTarget::refresh);
+
+        match io.recv() {
             // process_packet(packet, &mut scheduler),
             Ok(packet) =>  {
-                if check_src_eth(packet, mac_addr, src_mac) {
+                metrics.frames_received.inc();
+                if mac_addr.is_none_or(|mac_addr| check_src_eth(packet, mac_addr, src_mac)) {
                     // let pkt_len = packet.len();
                     // println!("Received length = {}", packet.len());
-                    let idx = rrs.push(packet.to_vec(), &psv);
+                    let PushTarget { rrs, psv, .. } = &mut targets[classifier.classify(packet)];
+                    let idx = rrs.push(packet.to_vec(), psv);
                     let mut previous_state = 0;
-                    if idx == pattern::PATTERN.len() {
+                    if idx == psv.len() {
                         // println!("Failed to push packet of length {}", pkt_len);
+                        dropped += 1;
                         continue;
                     } else if idx > 0 {
                         previous_state = psv[idx-1].1;
@@ -302,11 +596,16 @@ fn obfuscate_data(input_interface: &str, src_device: &str, rrs: Arc<round_robin:
                     // println!("Pushed packet to queue {}", idx);
                     // println!("Pushed packet of length {}", packet.len());
                     // We pushed in a state with many queues, adjust the next queue that will be pushed to in that state
+                    // idx is a position in the pattern sorted by size, the same indexing as psv
                     let modulus = psv[idx].1 - previous_state;
                     let next_queue = psv[idx].0 - previous_state + 1;
                     psv[idx].0 = next_queue % modulus + previous_state;
+                } else {
+                    metrics.frames_filtered.inc();
                 }
             },
+            Err(e) if packet_io::is_closed(&e) => break,
+            Err(e) if packet_io::is_timeout(&e) => continue,
             Err(e) => {
                 eprintln!("Error receiving frame: {}", e);
                 continue;
@@ -314,73 +613,95 @@ fn obfuscate_data(input_interface: &str, src_device: &str, rrs: Arc<round_robin:
         };
 
         if count % pad_log_interval as usize == 0 && count != 0{
-            let lock_pad = round_robin::TOTAL_PAD.lock().unwrap();
-            // Could reset it here if want to or else moving average
-            let avg_pad = (*lock_pad) / count as f64 * pps;
-            if save_data {
-                writeln!(file, "{},{}", count, avg_pad).expect("Failed to write to file");
+            // Moving average, the counters of the queues are never reset
+            if let Some(file) = &mut file {
+                write_avg_pad(file, count, get_avg_pad(&targets));
             } else {
-                // println!("Average pad of {:.2}B", avg_pad);
+                // println!("Average pad of {:.2}B", get_avg_pad(&targets));
             }
         }
 
         count += 1;
     }
-}
-
-fn deobfuscate_data(obf_input_interface: &str, output_interface: &str, ip_src: [u8;4], is_local: bool, is_hw_obfuscation: bool, is_backbone: bool) {
-    let mut ch_rx = match get_channel(obf_input_interface) {
-        Ok(rx) => rx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
-
-    let mut ch_tx = match get_channel(output_interface) {
-        Ok(tx) => tx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
 
+    let avg_pad = get_avg_pad(&targets);
+    if let Some(file) = &mut file {
+        write_avg_pad(file, count, avg_pad);
+    }
+    println!("Received {} frames to obfuscate, dropped {} too large for the pattern, average pad of {:.2}B", count, dropped, avg_pad);
+}
 
-    let mac_addr = ch_tx.mac_addr.unwrap().octets();
+// Forwards the real packets received on rx from any of the peers of deobfuscators to tx until one of them is closed or
+// a shutdown is requested. As a backbone router, packets are sent on to the next hop of the peer they came from
+pub fn deobfuscate_data<R: PacketIo, W: PacketIo>(mut rx: R, mut tx: W, deobfuscators: &mut [deobfuscate::Deobfuscator], metrics: &metrics::Metrics, next_hops: Option<&[Ipv4Addr]>, is_log: bool, shutdown: &shutdown::Shutdown) {
+    let mac_addr = tx.mac_addr().unwrap_or_default().octets();
     // println!("CHange mac to {:?}", mac_addr);
 
+    let mut last_loss_log = Instant::now();
+    let mut last_lost = 0;
+    let mut forwarded: u64 = 0;
+
     // Process received Ethernet frames
-    loop {
-        match ch_rx.rx.next() {
+    'receive: while !shutdown.is_requested() {
+        let (lost, late) = (deobfuscators.iter().map(|d| d.lost()).sum(), deobfuscators.iter().map(|d| d.late()).sum());
+        metrics.slots_lost.set(lost);
+        metrics.slots_late.set(late);
+        metrics.slots_replayed.set(deobfuscators.iter().map(|d| d.replayed()).sum());
+        metrics.reassembly_timeouts.set(deobfuscators.iter().map(|d| d.reassembly_timeouts()).sum());
+        if is_log && last_loss_log.elapsed() >= LOSS_LOG_INTERVAL {
+            // Only report when slots were lost since the last report
+            if lost != last_lost {
+                println!("Lost {} slots from the peers, {} arrived late", lost, late);
+                last_lost = lost;
+            }
+            last_loss_log = Instant::now();
+        }
+
+        let (frames, next_hop) = match rx.recv() {
             // process_packet(packet, &mut scheduler),
             Ok(packet) =>  {
-                match deobfuscate::process_packet(&packet, ip_src, is_local, is_hw_obfuscation) {
-                    // Real packets
-                    Some(packet) => {
-                        // println!("Deobfuscated packet with length = {}", packet.len());
-                        if is_backbone {
-                            ch_tx.tx.send_to(&process_backbone_packet(packet, mac_addr), None);
-                        } else {
-                            ch_tx.tx.send_to(&packet, None);
-                        }
-                    }, 
-                    // Chaff
-                    None => continue,
+                let Some(peer) = deobfuscate::select_peer(deobfuscators, packet) else {
+                    metrics.discarded.inc();
+                    continue;
+                };
+                let next_hop = next_hops.map(|next_hops| next_hops[peer]);
+                match deobfuscators[peer].process_packet(packet) {
+                    Some(frames) => (frames, next_hop),
+                    // Chaff, or a fragment of a packet still missing others
+                    None => {
+                        metrics.discarded.inc();
+                        continue;
+                    },
                 }
             },
+            Err(e) if packet_io::is_closed(&e) => break,
+            Err(e) if packet_io::is_timeout(&e) => continue,
             Err(e) => {
                 eprintln!("Error receiving frame: {}", e);
                 continue;
             }
         };
+        // Real packets, several when the peer packed them in one slot
+        for packet in frames {
+            metrics.deobfuscated.inc();
+            // println!("Deobfuscated packet with length = {}", packet.len());
+            let result = match next_hop {
+                Some(next_hop) => tx.send(&process_backbone_packet(packet, mac_addr, next_hop)),
+                None => tx.send(packet),
+            };
+            match result {
+                Ok(_) => forwarded += 1,
+                Err(e) if packet_io::is_closed(&e) => break 'receive,
+                Err(_) => metrics.send_errors.inc(),
+            }
+        }
     }
-}
 
-fn parse_ip(ip_str: String) -> [u8;4] {
-    let ip_addr = match ip_str.parse::<net::Ipv4Addr>() {
-        Ok(addr) => addr,
-        Err(e) => {
-            panic!("Failed to parse IP address: {}", e);
-        }
-    };
-    ip_addr.octets()
+    let (lost, late): (u64, u64) = (deobfuscators.iter().map(|d| d.lost()).sum(), deobfuscators.iter().map(|d| d.late()).sum());
+    println!("Forwarded {} packets, lost {} slots from the peers, {} arrived late", forwarded, lost, late);
 }
 
-fn write_params_to_file<T: std::fmt::Display>(overwrite: bool, interval: T) {
+fn write_params_to_file<T: std::fmt::Display>(overwrite: bool, interval: T, pattern: &[usize]) {
     let mut params_file = OpenOptions::new()
             .write(true)
             .truncate(overwrite) // Overwrite
@@ -390,40 +711,46 @@ fn write_params_to_file<T: std::fmt::Display>(overwrite: bool, interval: T) {
 
     writeln!(params_file, "Name,Value").expect("Failed to write to file");
     writeln!(params_file, "interval,{}",interval).expect("Failed to write to file");
-    writeln!(params_file, "pattern, {:?}", pattern::PATTERN).expect("Failed to write to file");
+    writeln!(params_file, "pattern, {:?}", pattern).expect("Failed to write to file");
 }
 
-fn obfuscate_data_in_order(input_interface: &str, rrs: Arc<round_robin::RoundRobinScheduler>, pps: f64, pad_log_interval: f64, save_data: bool) {
-    let mut ch_rx = match get_channel(input_interface) {
-        Ok(rx) => rx,
-        Err(error) => panic!("Error getting channel: {error}"),
-    };
-
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(save_data) // Overwrite
-        .create(true)
-        .open("pad.csv")
-        .expect("Could not open file");
-
-    if save_data {
+fn obfuscate_data_in_order<T: PacketIo>(mut io: T, controls: &[Arc<control::Control>], classifier: &peer::Classifier, pad_log_interval: f64, save_data: bool, shutdown: &shutdown::Shutdown) {
+    let mut file = if save_data {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(save_data) // Overwrite
+            .create(true)
+            .open("pad.csv")
+            .expect("Could not open file");
         writeln!(file, "Iteration,Pad").expect("Failed to write to file");
-    }
+        Some(file)
+    } else {
+        None
+    };
 
     let mut count = 0;
-    let mut current_q = 0;
-    let mac_addr = ch_rx.mac_addr.unwrap();
+    let mut targets: Vec<PushTarget> = controls.iter().map(PushTarget::new).collect();
+    let metrics = controls[0].metrics();
+    let mac_addr = io.mac_addr();
     println!("src mac: {:?}", mac_addr);
     // Process received Ethernet frames
-    loop { 
-        match ch_rx.rx.next() {
+    while !shutdown.is_requested() {
+        targets.iter_mut().for_each(PushTarget::refresh);
+
+        match io.recv() {
             // process_packet(packet, &mut scheduler),
             Ok(packet) =>  {
                 //println!("Received length = {}", packet.len());
-                if check_src_eth(packet, mac_addr, mac_addr) {
-                    current_q = rrs.push_no_reorder(packet.to_vec(), current_q);
+                metrics.frames_received.inc();
+                if mac_addr.is_none_or(|mac_addr| check_src_eth(packet, mac_addr, mac_addr)) {
+                    let target = &mut targets[classifier.classify(packet)];
+                    target.current_q = target.rrs.push_no_reorder(packet.to_vec(), target.current_q);
+                } else {
+                    metrics.frames_filtered.inc();
                 }
             },
+            Err(e) if packet_io::is_closed(&e) => break,
+            Err(e) if packet_io::is_timeout(&e) => continue,
             Err(e) => {
                 eprintln!("Error receiving frame: {}", e);
                 continue;
@@ -431,18 +758,30 @@ fn obfuscate_data_in_order(input_interface: &str, rrs: Arc<round_robin::RoundRob
         };
 
         if count % pad_log_interval as usize == 0 && count != 0{
-            let lock_pad = round_robin::TOTAL_PAD.lock().unwrap();
-            // COuld reset it here if want to or else moving average
-            let avg_pad = (*lock_pad) / count as f64 * pps;
-            if save_data {
-                writeln!(file, "{},{}", count, avg_pad).expect("Failed to write to file");
+            // Moving average, the counters of the queues are never reset
+            if let Some(file) = &mut file {
+                write_avg_pad(file, count, get_avg_pad(&targets));
             } else {
-                // println!("Average pad of {:.2}B", avg_pad);
+                // println!("Average pad of {:.2}B", get_avg_pad(&targets));
             }
         }
 
         count += 1;
     }
+
+    let avg_pad = get_avg_pad(&targets);
+    if let Some(file) = &mut file {
+        write_avg_pad(file, count, avg_pad);
+    }
+    println!("Received {} frames to obfuscate, average pad of {:.2}B", count, avg_pad);
+}
+
+fn write_avg_pad(file: &mut File, count: usize, avg_pad: f64) {
+    // Average padding of the packets pushed so far, in bytes
+    if count == 0 {
+        return;
+    }
+    writeln!(file, "{},{}", count, avg_pad).expect("Failed to write to file");
 }
 
 fn check_src_eth(data: &[u8], mac_addr: pnet::util::MacAddr, src_device_mac: pnet::util::MacAddr) -> bool {
@@ -453,16 +792,21 @@ fn check_src_eth(data: &[u8], mac_addr: pnet::util::MacAddr, src_device_mac: pne
     data_mac == mac_addr || data_mac == src_device_mac
 }
 
-fn process_backbone_packet(packet: &[u8], mac_addr: [u8; 6]) -> Vec<u8> {
+fn process_backbone_packet(packet: &[u8], mac_addr: [u8; 6], next_hop: Ipv4Addr) -> Vec<u8> {
     // Set ip dst and mac for deobfuscated packets that should be forwarded
-    // assume the destination is zurich and the destination ip address is already in the 10.7.0.0/24 subnet
+    // assume the destination ip address is already in the subnet of next_hop
     let mut pkt = vec![0u8; packet.len()]; 
     pkt.clone_from_slice(packet);
 
     pkt[pattern::IP_HEADER_LEN+pattern::ETH_MAC_SRC_ADDR_OFFSET.. pattern::IP_HEADER_LEN+pattern::ETH_MAC_SRC_ADDR_OFFSET+pattern::MAC_ADDR_LEN]
         .copy_from_slice(&mac_addr);
     
-    pkt[pattern::IP_HEADER_LEN+pattern::ETH_HEADER_LEN+pattern::IP_DST_ADDR_OFFSET..pattern::IP_HEADER_LEN+pattern::ETH_HEADER_LEN+pattern::IP_DST_ADDR_OFFSET+pattern::IP_ADDR_LEN]
-        .copy_from_slice(&pattern::IP_NEXT_HOP);
+    // Only IPv4 packets have a next hop to rewrite, after the VLAN tags if the frame has some
+    if let Some((pattern::ETHERTYPE_IPV4, offset)) = pattern::get_ethertype(&pkt[pattern::IP_HEADER_LEN..]) {
+        let ip_dst = pattern::IP_HEADER_LEN + offset + pattern::IP_DST_ADDR_OFFSET;
+        if let Some(dst) = pkt.get_mut(ip_dst..ip_dst + pattern::IP_ADDR_LEN) {
+            dst.copy_from_slice(&next_hop.octets());
+        }
+    }
     pkt
 }
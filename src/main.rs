@@ -1,5 +1,4 @@
-use std::fs;
-use toml::Value;
+use budget_ditto::config::Config;
 
 fn main() {
     // Get the name of the network interface from the command-line arguments
@@ -11,12 +10,16 @@ fn main() {
         std::process::exit(1);
     }
 
-    let toml_str = fs::read_to_string(args.pop().unwrap_or_default()).expect("Failed to read file");
-    let toml_value: Value = toml::from_str(&toml_str).expect("Failed to parse TOML");
+    let config = match Config::from_file(&args.pop().unwrap_or_default()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    if let Err(e) = budget_ditto::run(toml_value) {
+    if let Err(e) = budget_ditto::run(config) {
         eprintln!("Application error: {e}");
         std::process::exit(1);
     }
 }
-
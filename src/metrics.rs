@@ -0,0 +1,279 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::control::Control;
+use crate::queues::priority_queue::QueueStats;
+use crate::queues::round_robin::SchedulerStats;
+use crate::shutdown::Shutdown;
+
+// How often the exporter threads check for a shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// A scraper sending nothing for this long is disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+type QueueCounter = fn(&QueueStats) -> u64;
+// Upper bounds of the buckets of the deviation of send times from their deadlines, in nanoseconds
+const DEVIATION_BUCKETS_NS: [u64; 8] = [1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 10_000_000];
+
+// Count shared between threads, only ever increased except when mirrored from another count with set
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max(&self, value: u64) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+}
+
+// How late slots were sent compared to their deadline, what the pacer could not hide
+#[derive(Debug, Default)]
+pub struct DeviationHistogram {
+    // Slots that were at most DEVIATION_BUCKETS_NS late, each counted in its smallest bucket only
+    buckets: [Counter; DEVIATION_BUCKETS_NS.len()],
+    count: Counter,
+    sum_ns: Counter,
+    max_ns: Counter,
+}
+
+impl DeviationHistogram {
+    pub fn record(&self, deviation: Duration) {
+        let deviation_ns = deviation.as_nanos().min(u64::MAX as u128) as u64;
+        if let Some(i) = DEVIATION_BUCKETS_NS.iter().position(|&bound| deviation_ns <= bound) {
+            self.buckets[i].inc();
+        }
+        self.count.inc();
+        self.sum_ns.add(deviation_ns);
+        self.max_ns.set_max(deviation_ns);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count.get() {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos(self.sum_ns.get() / count),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_ns.get())
+    }
+
+    fn render(&self, text: &mut String, name: &str) {
+        // Buckets are cumulative in the exposition format
+        let mut cumulative = 0;
+        for (bound, bucket) in DEVIATION_BUCKETS_NS.iter().zip(&self.buckets) {
+            cumulative += bucket.get();
+            let _ = writeln!(text, "ditto_{}_bucket{{le=\"{}\"}} {}", name, *bound as f64 / 1e9, cumulative);
+        }
+        let _ = writeln!(text, "ditto_{}_bucket{{le=\"+Inf\"}} {}", name, self.count.get());
+        let _ = writeln!(text, "ditto_{}_sum {}", name, self.sum_ns.get() as f64 / 1e9);
+        let _ = writeln!(text, "ditto_{}_count {}", name, self.count.get());
+    }
+}
+
+// Counters of the pipeline threads, the per-queue ones are in the queues of the scheduler
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Frames read on the unobfuscated side, and those of them rejected by the source MAC filter
+    pub frames_received: Counter,
+    pub frames_filtered: Counter,
+    // Slots that were due before the previous one was sent
+    pub timer_overruns: Counter,
+    // Slots sent back to back to catch up with the schedule, and slots of the schedule never sent to catch up with it
+    pub slots_bursted: Counter,
+    pub slots_missed: Counter,
+    // Frames that failed to be sent, slots or deobfuscated packets
+    pub send_errors: Counter,
+    // Real packets taken out of the frames received on the obfuscated side, and frames that were chaff or invalid
+    pub deobfuscated: Counter,
+    pub discarded: Counter,
+    // Slots of the peer that never arrived or arrived after later ones
+    pub slots_lost: Counter,
+    pub slots_late: Counter,
+    // Slots of the peer that arrived a second time, dropped
+    pub slots_replayed: Counter,
+    // Packets of the peer split in fragments that were given up because a fragment was missing
+    pub reassembly_timeouts: Counter,
+    // Rate changes made by the adaptive controller
+    pub rate_switches: Counter,
+    // How late each slot was handed to the interface
+    pub pacing_deviation: DeviationHistogram,
+}
+
+pub fn render(controls: &[Arc<Control>], metrics: &Metrics) -> String {
+    // Prometheus text exposition format, the counters of the pipeline threads are shared by the peers
+    let mut text = String::new();
+    let counters = [
+        ("frames_received_total", "Frames read on the unobfuscated side", &metrics.frames_received),
+        ("frames_filtered_total", "Frames rejected because of their source MAC address", &metrics.frames_filtered),
+        ("timer_overruns_total", "Slots sent later than scheduled", &metrics.timer_overruns),
+        ("slots_bursted_total", "Slots sent right after the previous one to catch up with the schedule", &metrics.slots_bursted),
+        ("slots_missed_total", "Slots of the schedule skipped or given up to catch up with it", &metrics.slots_missed),
+        ("send_errors_total", "Frames that could not be sent", &metrics.send_errors),
+        ("deobfuscated_total", "Real packets received from the peer", &metrics.deobfuscated),
+        ("discarded_total", "Chaff, invalid frames or fragments of incomplete packets received from the peer", &metrics.discarded),
+        ("slots_lost_total", "Slots of the peer that never arrived", &metrics.slots_lost),
+        ("slots_late_total", "Slots of the peer that arrived after later ones", &metrics.slots_late),
+        ("slots_replayed_total", "Slots of the peer that arrived again and were dropped", &metrics.slots_replayed),
+        ("reassembly_timeouts_total", "Packets of the peer given up because one of their fragments was missing", &metrics.reassembly_timeouts),
+        ("rate_switches_total", "Rate changes made by the adaptive controller", &metrics.rate_switches),
+    ];
+    for (name, help, counter) in counters {
+        write_header(&mut text, name, help, "counter");
+        let _ = writeln!(text, "ditto_{} {}", name, counter.get());
+    }
+
+    // Per queue, the labels tell the peers and the queues of the same length apart
+    let peers: Vec<(String, SchedulerStats)> = controls.iter().map(|control| (format!("peer=\"{}\"", control.dst()), control.scheduler().stats())).collect();
+    let labels: Vec<(&QueueStats, String)> = peers.iter()
+        .flat_map(|(peer, stats)| stats.queues.iter().enumerate().map(move |(i, q)| (q, format!("{},queue=\"{}\",length=\"{}\"", peer, i, q.length))))
+        .collect();
+    let queue_counters: [(&str, &str, QueueCounter); 6] = [
+        ("queue_pushed_total", "Packets pushed to the queue", |q| q.pushed),
+        ("queue_dropped_total", "Packets dropped because the queue was full", |q| q.dropped),
+        ("queue_expired_total", "Packets dropped because they waited in the queue for too long", |q| q.expired),
+        ("queue_bytes_total", "Bytes of the packets pushed to the queue", |q| q.bytes),
+        ("queue_padding_bytes_total", "Bytes of padding added to the packets pushed to the queue", |q| q.padding),
+        ("queue_aggregated_total", "Packets sent in the slot of an earlier packet of the queue", |q| q.aggregated),
+    ];
+    for (name, help, get) in queue_counters {
+        write_header(&mut text, name, help, "counter");
+        for (q, labels) in &labels {
+            let _ = writeln!(text, "ditto_{}{{{}}} {}", name, labels, get(q));
+        }
+    }
+    write_header(&mut text, "slots_sent_total", "Slots sent from the queue", "counter");
+    for (q, labels) in &labels {
+        let _ = writeln!(text, "ditto_slots_sent_total{{{},type=\"real\"}} {}", labels, q.real_sent);
+        let _ = writeln!(text, "ditto_slots_sent_total{{{},type=\"chaff\"}} {}", labels, q.chaff_sent);
+    }
+    write_header(&mut text, "queue_depth", "Packets waiting in the queue", "gauge");
+    for (q, labels) in &labels {
+        let _ = writeln!(text, "ditto_queue_depth{{{}}} {}", labels, q.depth);
+    }
+
+    write_header(&mut text, "pacing_deviation_seconds", "Delay between the deadline of a slot and the time it was sent", "histogram");
+    metrics.pacing_deviation.render(&mut text, "pacing_deviation_seconds");
+    write_header(&mut text, "pacing_deviation_max_seconds", "Largest delay between the deadline of a slot and the time it was sent", "gauge");
+    let _ = writeln!(text, "ditto_pacing_deviation_max_seconds {}", metrics.pacing_deviation.max().as_secs_f64());
+    write_header(&mut text, "fragmented_total", "Packets split in fragments because they fit in no queue", "counter");
+    for (peer, stats) in &peers {
+        let _ = writeln!(text, "ditto_fragmented_total{{{}}} {}", peer, stats.fragmented);
+    }
+    write_header(&mut text, "oversize_dropped_total", "Packets dropped because they fit in no queue", "counter");
+    for (peer, stats) in &peers {
+        let _ = writeln!(text, "ditto_oversize_dropped_total{{{}}} {}", peer, stats.oversize);
+    }
+    write_header(&mut text, "rate_mbps", "Rate the pattern is sent at", "gauge");
+    for ((peer, _), control) in peers.iter().zip(controls) {
+        let _ = writeln!(text, "ditto_rate_mbps{{{}}} {}", peer, control.rate());
+    }
+    write_header(&mut text, "paused", "1 while the pattern is paused", "gauge");
+    for ((peer, _), control) in peers.iter().zip(controls) {
+        let _ = writeln!(text, "ditto_paused{{{}}} {}", peer, control.is_paused() as u8);
+    }
+    text
+}
+
+fn write_header(text: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(text, "# HELP ditto_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE ditto_{} {}", name, metric_type);
+}
+
+pub fn serve_http(addr: SocketAddr, controls: Vec<Arc<Control>>, metrics: Arc<Metrics>, shutdown: Shutdown) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    Ok(thread::spawn(move || {
+        while !shutdown.is_requested() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = handle_scrape(stream, &controls, &metrics) {
+                        eprintln!("Error serving metrics: {}", e);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    eprintln!("Error accepting on the metrics endpoint: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                },
+            }
+        }
+    }))
+}
+
+fn handle_scrape(stream: TcpStream, controls: &[Arc<Control>], metrics: &Metrics) -> io::Result<()> {
+    // Only GET /metrics, one request per connection
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(controls, metrics)),
+        (Some("GET"), _) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, CONTENT_TYPE, body.len(), body)?;
+    writer.flush()
+}
+
+pub fn write_textfile(path: &str, controls: &[Arc<Control>], metrics: &Metrics) -> io::Result<()> {
+    // Written next to path then renamed, the collector never reads a partial file
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, render(controls, metrics))?;
+    fs::rename(&tmp_path, path)
+}
+
+pub fn serve_textfile(path: String, interval: Duration, controls: Vec<Arc<Control>>, metrics: Arc<Metrics>, shutdown: Shutdown) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_write: Option<Instant> = None;
+        loop {
+            let is_stopping = shutdown.is_requested();
+            // One last time on shutdown so the file has the final counters
+            if is_stopping || last_write.is_none_or(|time| time.elapsed() >= interval) {
+                if let Err(e) = write_textfile(&path, &controls, &metrics) {
+                    eprintln!("Error writing metrics to {}: {}", path, e);
+                }
+                last_write = Some(Instant::now());
+            }
+            if is_stopping {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL.min(interval));
+        }
+    })
+}
@@ -0,0 +1,351 @@
+use std::io;
+use crate::pattern;
+use crate::pcap::PcapReader;
+use crate::shim::{self, WireFormat};
+
+// Without a rate budget, send just fast enough that no size class is busier than this
+pub const TARGET_UTILIZATION: f64 = 0.9;
+// Too many candidate sizes make the search slow without improving the result much
+const MAX_CANDIDATES: usize = 64;
+const MAX_ROUNDS: usize = 50;
+// Patterns evaluated by the exhaustive part of the search
+const MAX_EVALUATIONS: f64 = 2e5;
+
+// Packet sizes seen in a trace, as a sorted histogram so a pattern can be evaluated without going over every packet
+pub struct Trace {
+    // (size, number of packets of that size), by increasing size
+    pub histogram: Vec<(usize, usize)>,
+    // Number of packets and bytes up to and including each histogram entry
+    cumulative: Vec<(usize, usize)>,
+    pub num_packets: usize,
+    // In seconds
+    pub duration: f64,
+}
+
+// How the slots of a pattern carry the frames of a trace, from the config the pattern is meant for
+#[derive(Debug, Clone, Copy)]
+pub struct SlotModel {
+    // Bytes of each slot not available for the frame, see pattern::get_slot_overhead
+    pub overhead: usize,
+    // Bytes in front of each slot that the sizes of the pattern do not include
+    pub outer_header_len: usize,
+    // Frames too large for every slot are split in fragments, only the shim header can carry them
+    pub can_fragment: bool,
+    // Smallest slot that can carry the headers
+    pub min_size: usize,
+}
+
+pub struct ClassStats {
+    pub size: usize,
+    pub slots: usize,
+    // Frames per second pushed to the queues of this size
+    pub packet_rate: f64,
+    // Slots per second filled by these frames, each fragment of a split frame takes one
+    pub arrival_rate: f64,
+    pub utilization: f64,
+    // Expected time spent in the queue in seconds
+    pub delay: f64,
+}
+
+pub struct Evaluation {
+    pub pattern: Vec<usize>,
+    pub pps: f64,
+    // In Mbps, with the same overhead per packet as used to compute the pps from the rate in run()
+    pub rate: f64,
+    // Padding and chaff bytes sent for every byte of real traffic
+    pub overhead: f64,
+    // Mean queueing delay over all packets that fit in the pattern, in seconds
+    pub mean_delay: f64,
+    // Packets larger than every size of the pattern, split in fragments or dropped if they cannot be
+    pub fragmented: usize,
+    pub dropped: usize,
+    pub classes: Vec<ClassStats>,
+    pub cost: f64,
+}
+
+impl SlotModel {
+    pub fn new(is_encrypted: bool, wire_format: WireFormat, outer_header_len: usize) -> SlotModel {
+        SlotModel {
+            overhead: pattern::get_slot_overhead(is_encrypted, wire_format),
+            outer_header_len,
+            can_fragment: wire_format == WireFormat::Shim,
+            min_size: pattern::get_min_slot_size(is_encrypted, wire_format).max(pattern::IP_HEADER_LEN),
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        // Largest size of the pattern that still fits in the MTU once wrapped, as checked by validate_pattern
+        pattern::MTU - self.outer_header_len
+    }
+
+    fn get_capacity(&self, size: usize) -> usize {
+        // Largest frame that fits in a slot of that size
+        size.saturating_sub(self.overhead)
+    }
+
+    fn get_fragments(&self, length: usize, size: usize) -> Option<usize> {
+        // Slots taken by a frame too large for size, like PriorityQueue::push_fragments. None if it is dropped
+        let fragment_capacity = self.get_capacity(size).saturating_sub(shim::FRAGMENT_HEADER_LEN);
+        if !self.can_fragment || fragment_capacity == 0 || length > u16::MAX as usize {
+            return None;
+        }
+        Some(length.div_ceil(fragment_capacity))
+    }
+}
+
+impl Trace {
+    pub fn from_pcap(path: &str) -> io::Result<Trace> {
+        let mut reader = PcapReader::open(path)?;
+        let mut sizes = Vec::new();
+        let mut first = None;
+        let mut last = None;
+        while let Some(record) = reader.read_packet()? {
+            sizes.push(record.orig_len);
+            first.get_or_insert(record.timestamp);
+            last = Some(record.timestamp);
+        }
+        let duration = match (first, last) {
+            (Some(first), Some(last)) if last > first => (last - first).as_secs_f64(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Trace needs at least two packets with different timestamps")),
+        };
+        Ok(Trace::new(sizes, duration))
+    }
+
+    pub fn new(mut sizes: Vec<usize>, duration: f64) -> Trace {
+        sizes.sort_unstable();
+        let mut histogram: Vec<(usize, usize)> = Vec::new();
+        for &size in &sizes {
+            match histogram.last_mut() {
+                Some((s, count)) if *s == size => *count += 1,
+                _ => histogram.push((size, 1)),
+            }
+        }
+        let mut cumulative = Vec::with_capacity(histogram.len());
+        let (mut packets, mut bytes) = (0, 0);
+        for &(size, count) in &histogram {
+            packets += count;
+            bytes += size * count;
+            cumulative.push((packets, bytes));
+        }
+        Trace { histogram, cumulative, num_packets: sizes.len(), duration }
+    }
+
+    fn get_totals_up_to(&self, size: usize) -> (usize, usize) {
+        // Number of packets and bytes of all packets no larger than size
+        match self.histogram.partition_point(|&(s, _)| s <= size) {
+            0 => (0, 0),
+            n => self.cumulative[n - 1],
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.histogram.last().map_or(0, |&(size, _)| size)
+    }
+
+    fn get_candidates(&self, model: &SlotModel) -> Vec<usize> {
+        // Sizes a pattern entry can take, only the slot sizes that exactly fit an observed size can be optimal since
+        // any other size only adds padding
+        let (min_size, max_size) = (model.min_size, model.max_size());
+        let mut candidates: Vec<usize> = if self.histogram.len() <= MAX_CANDIDATES {
+            self.histogram.iter().map(|&(size, _)| size).collect()
+        } else {
+            // Use quantiles of the size distribution
+            let mut candidates = Vec::with_capacity(MAX_CANDIDATES + 1);
+            let mut seen = 0;
+            let mut next_quantile = 1;
+            for &(size, count) in &self.histogram {
+                seen += count;
+                if seen * MAX_CANDIDATES >= next_quantile * self.num_packets {
+                    candidates.push(size);
+                    while seen * MAX_CANDIDATES >= next_quantile * self.num_packets {
+                        next_quantile += 1;
+                    }
+                }
+            }
+            candidates
+        };
+        candidates.push(self.max_size());
+        for c in candidates.iter_mut() {
+            *c = (*c + model.overhead).clamp(min_size, max_size);
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+pub fn evaluate(trace: &Trace, pattern: &[usize], model: &SlotModel, rate_budget: Option<f64>, delay_weight: f64) -> Evaluation {
+    // Model the RoundRobinScheduler push rules: a packet goes to the smallest size it fits in once the slot overhead
+    // is taken out, and the slots of that size share its traffic. Larger packets are split over the slots of the
+    // largest size. Each size class is then a queue served pattern.len() / slots times slower than pps
+    let mut sizes = pattern.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    let mut classes = Vec::with_capacity(sizes.len());
+    let (mut previous_packets, mut previous_bytes) = (0, 0);
+    for &size in &sizes {
+        let (packets, bytes) = trace.get_totals_up_to(model.get_capacity(size));
+        let packet_rate = (packets - previous_packets) as f64 / trace.duration;
+        classes.push(ClassStats {
+            size,
+            slots: pattern.iter().filter(|&&p| p == size).count(),
+            packet_rate,
+            arrival_rate: packet_rate,
+            utilization: 0.0,
+            delay: 0.0,
+        });
+        (previous_packets, previous_bytes) = (packets, bytes);
+    }
+
+    let (mut fragmented, mut fragments, mut dropped) = (0, 0, 0);
+    let largest = sizes.last().copied().unwrap_or(0);
+    let first_oversize = trace.histogram.partition_point(|&(s, _)| s <= model.get_capacity(largest));
+    for &(length, count) in &trace.histogram[first_oversize..] {
+        match model.get_fragments(length, largest) {
+            Some(slots) => {
+                fragmented += count;
+                fragments += slots * count;
+                previous_bytes += length * count;
+            },
+            None => dropped += count,
+        }
+    }
+    if let Some(class) = classes.last_mut() {
+        class.packet_rate += fragmented as f64 / trace.duration;
+        class.arrival_rate += fragments as f64 / trace.duration;
+    }
+    let bytes_per_sec = previous_bytes as f64 / trace.duration;
+
+    let pps = match rate_budget {
+        Some(rate) => pattern::get_pps(rate, pattern),
+        None => classes.iter()
+            .map(|c| c.arrival_rate * pattern.len() as f64 / (c.slots as f64 * TARGET_UTILIZATION))
+            .fold(f64::MIN_POSITIVE, f64::max),
+    };
+
+    let mut total_delay = 0.0;
+    let mut total_arrivals = 0.0;
+    let mut is_stable = true;
+    for class in classes.iter_mut() {
+        let service_rate = pps * class.slots as f64 / pattern.len() as f64;
+        class.utilization = class.arrival_rate / service_rate;
+        if class.utilization >= 1.0 {
+            is_stable = false;
+            class.delay = f64::INFINITY;
+        } else {
+            // M/D/1 waiting time, plus waiting on average half a cycle for the next slot of that size
+            let rho = class.utilization;
+            class.delay = rho / (2.0 * service_rate * (1.0 - rho)) + 1.0 / (2.0 * service_rate);
+        }
+        total_delay += class.delay * class.packet_rate;
+        total_arrivals += class.packet_rate;
+    }
+
+    let mean_delay = if total_arrivals > 0.0 { total_delay / total_arrivals } else { 0.0 };
+    let sent_bytes_per_sec = pps * pattern.iter().sum::<usize>() as f64 / pattern.len() as f64;
+    let overhead = if bytes_per_sec > 0.0 { (sent_bytes_per_sec - bytes_per_sec) / bytes_per_sec } else { f64::INFINITY };
+    let rate = pattern::get_rate(pps, pattern);
+
+    // Overhead is a ratio and the delay is weighted per millisecond
+    let cost = if is_stable { overhead + delay_weight * mean_delay * 1e3 } else { f64::INFINITY };
+
+    Evaluation { pattern: pattern.to_vec(), pps, rate, overhead, mean_delay, fragmented, dropped, classes, cost }
+}
+
+pub fn optimize(trace: &Trace, length: usize, model: &SlotModel, rate_budget: Option<f64>, delay_weight: f64) -> Evaluation {
+    // The largest entry is kept at the slot size of the largest packet, or the largest size the MTU allows, so that
+    // as few packets as possible are split or dropped. The order of the other entries does not change the cost, so
+    // search over multisets: exhaustively on a reduced set of candidate sizes, then refine one entry at a time over
+    // all candidates
+    let candidates = trace.get_candidates(model);
+    let max_size = *candidates.last().unwrap_or(&pattern::DEFAULT_PATTERN[0]);
+    let length = length.max(1);
+
+    let reduced = reduce_candidates(&candidates, length - 1);
+    let mut pattern = vec![max_size; length];
+    let mut best = evaluate(trace, &pattern, model, rate_budget, delay_weight);
+    enumerate(trace, &reduced, 0, 1, &mut pattern, model, rate_budget, delay_weight, &mut best);
+
+    pattern.clone_from(&best.pattern);
+    for _ in 0..MAX_ROUNDS {
+        let mut is_improved = false;
+        for i in 1..pattern.len() {
+            for &candidate in &candidates {
+                if candidate == pattern[i] {
+                    continue;
+                }
+                let previous = pattern[i];
+                pattern[i] = candidate;
+                let evaluation = evaluate(trace, &pattern, model, rate_budget, delay_weight);
+                if evaluation.cost < best.cost {
+                    best = evaluation;
+                    is_improved = true;
+                } else {
+                    pattern[i] = previous;
+                }
+            }
+        }
+        if !is_improved {
+            break;
+        }
+    }
+
+    best.pattern = interleave(&best.pattern);
+    best
+}
+
+#[allow(clippy::too_many_arguments)]
+fn enumerate(trace: &Trace, candidates: &[usize], start: usize, position: usize, pattern: &mut Vec<usize>, model: &SlotModel, rate_budget: Option<f64>, delay_weight: f64, best: &mut Evaluation) {
+    // Fill pattern[position..] with non decreasing candidate indices from start
+    if position == pattern.len() {
+        let evaluation = evaluate(trace, pattern, model, rate_budget, delay_weight);
+        if evaluation.cost < best.cost {
+            *best = evaluation;
+        }
+        return;
+    }
+    for i in start..candidates.len() {
+        pattern[position] = candidates[i];
+        enumerate(trace, candidates, i, position + 1, pattern, model, rate_budget, delay_weight, best);
+    }
+}
+
+fn reduce_candidates(candidates: &[usize], free_entries: usize) -> Vec<usize> {
+    // Keep as many evenly spread candidates as possible while the number of multisets stays below MAX_EVALUATIONS
+    let mut num_kept = candidates.len();
+    while num_kept > 1 && count_multisets(num_kept, free_entries) > MAX_EVALUATIONS {
+        num_kept -= 1;
+    }
+    let mut reduced: Vec<usize> = (0..num_kept)
+        .map(|i| candidates[(i + 1) * candidates.len() / num_kept - 1])
+        .collect();
+    reduced.dedup();
+    reduced
+}
+
+fn count_multisets(num_candidates: usize, size: usize) -> f64 {
+    // (num_candidates + size - 1) choose size
+    let mut count = 1.0;
+    for i in 0..size {
+        count = count * (num_candidates + i) as f64 / (i + 1) as f64;
+    }
+    count
+}
+
+pub fn interleave(pattern: &[usize]) -> Vec<usize> {
+    // Alternate large and small sizes so the pattern does not show a size ramp on the wire
+    let mut sorted = pattern.to_vec();
+    sorted.sort_unstable();
+    let mut interleaved = Vec::with_capacity(sorted.len());
+    let (mut low, mut high) = (0, sorted.len());
+    while low < high {
+        high -= 1;
+        interleaved.push(sorted[high]);
+        if low < high {
+            interleaved.push(sorted[low]);
+            low += 1;
+        }
+    }
+    interleaved
+}
@@ -0,0 +1,147 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+// How long before a deadline the hybrid pacer stops sleeping and spins
+const HYBRID_SPIN_MARGIN: Duration = Duration::from_micros(100);
+
+// How transmit waits for the deadline of the next slot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacerKind {
+    // thread::sleep, late by the wakeup latency of the scheduler
+    #[default]
+    Sleep,
+    // timerfd armed with the absolute deadline, a late wakeup does not push back the next ones
+    Timerfd,
+    // Sleep until shortly before the deadline, then spin
+    Hybrid,
+    // Spin all the time, only for a send thread isolated on its own core
+    Busy,
+}
+
+// What transmit does once it is late by more than an interval, e.g. after a slow send or being preempted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUp {
+    // Keep the schedule, the slots that are due are sent back to back until it is caught up with
+    #[default]
+    Burst,
+    // Keep the schedule, the slots whose time has passed are not sent and their queues are passed over
+    Skip,
+    // Start the schedule again from now, the time lost is never made up
+    Reanchor,
+}
+
+// Waits until the time a slot is due, returns right away if it is already past
+pub trait Pacer: Send {
+    fn wait_until(&mut self, deadline: Instant);
+}
+
+impl<T: Pacer + ?Sized> Pacer for Box<T> {
+    fn wait_until(&mut self, deadline: Instant) {
+        (**self).wait_until(deadline)
+    }
+}
+
+pub fn open(kind: PacerKind) -> io::Result<Box<dyn Pacer>> {
+    Ok(match kind {
+        PacerKind::Sleep => Box::new(SleepPacer),
+        PacerKind::Timerfd => Box::new(TimerfdPacer::new()?),
+        PacerKind::Hybrid => Box::new(HybridPacer),
+        PacerKind::Busy => Box::new(BusyPacer),
+    })
+}
+
+pub struct SleepPacer;
+
+impl Pacer for SleepPacer {
+    fn wait_until(&mut self, deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+pub struct HybridPacer;
+
+impl Pacer for HybridPacer {
+    fn wait_until(&mut self, deadline: Instant) {
+        if let Some(sleep_time) = deadline.saturating_duration_since(Instant::now()).checked_sub(HYBRID_SPIN_MARGIN) {
+            thread::sleep(sleep_time);
+        }
+        spin_until(deadline);
+    }
+}
+
+pub struct BusyPacer;
+
+impl Pacer for BusyPacer {
+    fn wait_until(&mut self, deadline: Instant) {
+        spin_until(deadline);
+    }
+}
+
+fn spin_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+pub struct TimerfdPacer {
+    fd: OwnedFd,
+    // The same point in time as an Instant and on CLOCK_MONOTONIC, to turn deadlines into absolute timer values
+    origin: Instant,
+    origin_monotonic: Duration,
+}
+
+impl TimerfdPacer {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+        let origin = Instant::now();
+        if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let origin_monotonic = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+        Ok(TimerfdPacer { fd, origin, origin_monotonic })
+    }
+
+    fn wait(&mut self, deadline: Instant) -> io::Result<()> {
+        let target = self.origin_monotonic + deadline.saturating_duration_since(self.origin);
+        let value = libc::timespec { tv_sec: target.as_secs() as libc::time_t, tv_nsec: target.subsec_nanos() as libc::c_long };
+        let spec = libc::itimerspec { it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 }, it_value: value };
+        if unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), libc::TFD_TIMER_ABSTIME, &spec, std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Blocks until the timer expires, with the number of expirations
+        let mut expirations = 0u64;
+        loop {
+            let result = unsafe { libc::read(self.fd.as_raw_fd(), &mut expirations as *mut u64 as *mut libc::c_void, std::mem::size_of::<u64>()) };
+            if result >= 0 {
+                return Ok(());
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
+
+impl Pacer for TimerfdPacer {
+    fn wait_until(&mut self, deadline: Instant) {
+        if deadline <= Instant::now() {
+            return;
+        }
+        // Should not happen once the timer could be created, sleeping still keeps the schedule
+        if let Err(e) = self.wait(deadline) {
+            eprintln!("Error waiting on timerfd, sleeping instead: {}", e);
+            SleepPacer.wait_until(deadline);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::time::Duration;
+use pnet::util::MacAddr;
+use serde::Deserialize;
+use crate::ChannelCustom;
+
+pub mod memory;
+pub mod pcap;
+pub mod packet_mmap;
+pub mod af_xdp;
+
+// Longest a recv blocks without a frame, so that the pipeline loops notice a shutdown
+pub const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How an interface is opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoBackend {
+    // pnet datalink channel, one syscall per frame
+    #[default]
+    Datalink,
+    // AF_PACKET socket with mmap'd TPACKET_V3 rings
+    PacketMmap,
+    // AF_XDP socket, only receives the frames coming in on the interface
+    AfXdp,
+}
+
+// Where the pipeline reads and writes frames: a pnet datalink channel, a TUN/TAP device, pcap files or memory
+pub trait PacketIo: Send {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+    // Lets fill write a frame of length bytes in place, it may only go out at the next flush
+    fn send_with(&mut self, length: usize, fill: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        let mut packet = vec![0u8; length];
+        fill(&mut packet);
+        self.send(&packet)
+    }
+
+    // Sends every frame written with send_with
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Blocks until a frame is available, fails with TimedOut after RECV_TIMEOUT without one and with UnexpectedEof once
+    // no more frames can come
+    fn recv(&mut self) -> io::Result<&[u8]>;
+
+    // Only frames from this address are obfuscated, None to take every frame
+    fn mac_addr(&self) -> Option<MacAddr> {
+        None
+    }
+}
+
+pub fn is_closed(error: &io::Error) -> bool {
+    // The other end is gone for good, the pipeline function using it stops instead of retrying
+    matches!(error.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe)
+}
+
+pub fn is_timeout(error: &io::Error) -> bool {
+    // Nothing arrived within RECV_TIMEOUT, recv can simply be called again
+    matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+pub(crate) fn poll(fd: RawFd, events: libc::c_short, timeout: Option<Duration>) -> io::Result<()> {
+    // Waits for one of events on fd, fails with TimedOut if none happened within timeout
+    let timeout_ms = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+    let mut pfd = libc::pollfd { fd, events, revents: 0 };
+    match unsafe { libc::poll(&mut pfd, 1, timeout_ms) } {
+        0 => Err(io::Error::new(io::ErrorKind::TimedOut, "No event before the timeout")),
+        result if result < 0 => {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(error)
+            }
+        },
+        _ => Ok(()),
+    }
+}
+
+impl<T: PacketIo + ?Sized> PacketIo for Box<T> {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        (**self).send(packet)
+    }
+
+    fn send_with(&mut self, length: usize, fill: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        (**self).send_with(length, fill)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        (**self).recv()
+    }
+
+    fn mac_addr(&self) -> Option<MacAddr> {
+        (**self).mac_addr()
+    }
+}
+
+impl PacketIo for ChannelCustom {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.tx.send_to(packet, None) {
+            Some(result) => result,
+            None => Err(io::Error::other("No packets to send")),
+        }
+    }
+
+    fn send_with(&mut self, length: usize, fill: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        // pnet builds the frame in its own write buffer
+        match self.tx.build_and_send(1, length, fill) {
+            Some(result) => result,
+            None => Err(io::Error::other("No packets to send")),
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        self.rx.next()
+    }
+
+    fn mac_addr(&self) -> Option<MacAddr> {
+        self.mac_addr
+    }
+}
@@ -0,0 +1,569 @@
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use pnet::datalink;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::util::MacAddr;
+use crate::packet_io::{self, PacketIo};
+use crate::pattern;
+
+// From linux/if_xdp.h and linux/bpf.h, not exported by every libc version
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+const XDP_PASS: i32 = 2;
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+// Offsets in struct xdp_md
+const XDP_MD_DATA: i16 = 0;
+const XDP_MD_DATA_END: i16 = 4;
+const XDP_MD_RX_QUEUE_INDEX: i16 = 16;
+// Large enough for every union member used
+const BPF_ATTR_LEN: usize = 128;
+
+// Only the first queue of the interface is redirected to the socket, use a single queue (e.g. ethtool -L) on NICs
+const QUEUE_ID: u32 = 0;
+// Chunks of the UMEM, the first half is used to send and the second half to receive
+const FRAME_SIZE: usize = 2048;
+const NUM_FRAMES: usize = 4096;
+const TX_FRAMES: usize = NUM_FRAMES / 2;
+// Every ring can hold all the frames of its direction so they never overflow
+const RING_SIZE: u32 = (NUM_FRAMES / 2) as u32;
+
+// Slots the XDP program redirects to the socket, every other packet goes on to the kernel stack
+#[derive(Debug, Clone, Copy)]
+pub struct XdpFilter {
+    // Outer source address of the slots, the peer sending them
+    pub src: IpAddr,
+    // Local UDP port the slots are sent to, none when they are carried by IP directly
+    pub udp_port: Option<u16>,
+}
+
+// Bytes at offset of a packet compared with value, only the bits set in mask
+struct FieldCheck {
+    offset: usize,
+    value: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl FieldCheck {
+    fn new(offset: usize, value: &[u8]) -> Self {
+        FieldCheck { offset, value: value.to_vec(), mask: vec![0xff; value.len()] }
+    }
+
+    fn end(&self) -> usize {
+        self.offset + self.value.len()
+    }
+}
+
+impl XdpFilter {
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        // Whether the XDP program redirects packet to the socket, it runs the same checks
+        let checks = self.get_checks();
+        checks.iter().all(|check| match packet.get(check.offset..check.end()) {
+            Some(bytes) => bytes.iter().zip(&check.mask).zip(&check.value).all(|((byte, mask), value)| byte & mask == *value),
+            None => false,
+        })
+    }
+
+    fn get_checks(&self) -> Vec<FieldCheck> {
+        // Slots start with their outer IP header, there is no Ethernet header on the obfuscated link
+        let (version, protocol_offset, src_offset, src, ip_protocol) = match self.src {
+            IpAddr::V4(src) => (pattern::IP_VERSION, pattern::IP_PROTOCOL_OFFSET, pattern::IP_SRC_ADDR_OFFSET, src.octets().to_vec(), IpNextHeaderProtocols::IpIp.0),
+            IpAddr::V6(src) => (pattern::IPV6_VERSION, pattern::IPV6_NEXT_HEADER_OFFSET, pattern::IPV6_SRC_ADDR_OFFSET, src.octets().to_vec(), IpNextHeaderProtocols::Ipv6.0),
+        };
+        let protocol = if self.udp_port.is_some() { pattern::IP_PROTOCOL_UDP } else { ip_protocol };
+        let mut checks = vec![
+            FieldCheck { offset: 0, value: vec![version << 4], mask: vec![0xf0] },
+            FieldCheck::new(protocol_offset, &[protocol]),
+        ];
+        // Loaded 4 bytes at a time
+        checks.extend(src.chunks(4).enumerate().map(|(i, word)| FieldCheck::new(src_offset + 4 * i, word)));
+        if let Some(port) = self.udp_port {
+            checks.push(FieldCheck::new(pattern::get_ip_header_len(self.src) + pattern::UDP_DST_PORT_OFFSET, &port.to_be_bytes()));
+        }
+        checks
+    }
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    // dst register in the low 4 bits, src register in the high 4 bits
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+// Memory shared with the kernel, unmapped when dropped
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Each ring is only used by one thread, the UMEM frames of each direction too
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+// Single producer single consumer ring shared with the kernel, this side is the producer or the consumer
+struct XdpRing {
+    // Unmapped when the ring is dropped
+    _map: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    entry_len: usize,
+    // Index of the next entry produced or consumed by this side
+    local: u32,
+}
+
+unsafe impl Send for XdpRing {}
+
+impl XdpRing {
+    fn map(fd: RawFd, offset: &XdpRingOffset, pgoff: libc::off_t, entry_len: usize) -> io::Result<XdpRing> {
+        let len = offset.desc as usize + RING_SIZE as usize * entry_len;
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, pgoff) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ptr = ptr as *mut u8;
+        unsafe {
+            Ok(XdpRing {
+                producer: ptr.add(offset.producer as usize) as *const AtomicU32,
+                consumer: ptr.add(offset.consumer as usize) as *const AtomicU32,
+                desc: ptr.add(offset.desc as usize),
+                entry_len,
+                // Rings of a new socket start empty
+                local: 0,
+                _map: Mmap { ptr, len },
+            })
+        }
+    }
+
+    fn entry(&self, index: u32) -> *mut u8 {
+        unsafe { self.desc.add((index & (RING_SIZE - 1)) as usize * self.entry_len) }
+    }
+
+    fn produce(&mut self) {
+        self.local = self.local.wrapping_add(1);
+        unsafe { (*self.producer).store(self.local, Ordering::Release) };
+    }
+
+    fn available(&self) -> u32 {
+        // Entries the kernel produced that this side did not consume yet
+        unsafe { (*self.producer).load(Ordering::Acquire).wrapping_sub(self.local) }
+    }
+
+    fn consume(&mut self) {
+        self.local = self.local.wrapping_add(1);
+        unsafe { (*self.consumer).store(self.local, Ordering::Release) };
+    }
+}
+
+// State kept alive as long as one of the two directions is used
+struct XdpSocket {
+    umem: Mmap,
+    fd: OwnedFd,
+    // Detaches the program from the interface when closed
+    _link: OwnedFd,
+    _prog: OwnedFd,
+    _map: OwnedFd,
+    mac_addr: Option<MacAddr>,
+}
+
+impl XdpSocket {
+    fn frame(&self, addr: u64) -> *mut u8 {
+        unsafe { self.umem.ptr.add(addr as usize) }
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        if unsafe { libc::sendto(self.fd.as_raw_fd(), ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) } < 0 {
+            let error = io::Error::last_os_error();
+            // The kernel is still busy with earlier frames, they go out with the next kick
+            if !matches!(error.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS)) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&self, events: libc::c_short, timeout: Option<Duration>) -> io::Result<()> {
+        packet_io::poll(self.fd.as_raw_fd(), events, timeout)
+    }
+}
+
+// Sending half of an AF_XDP socket, frames are written in the UMEM and handed to the kernel through the TX ring
+pub struct AfXdpTx {
+    socket: Arc<XdpSocket>,
+    tx: XdpRing,
+    completion: XdpRing,
+    // Frames of the UMEM not in use by the kernel
+    free_frames: Vec<u64>,
+    pending: usize,
+}
+
+// Receiving half of an AF_XDP socket, frames are read in place in the UMEM
+pub struct AfXdpRx {
+    socket: Arc<XdpSocket>,
+    rx: XdpRing,
+    fill: XdpRing,
+    // Frame returned by the last recv, given back to the kernel at the next one
+    held_frame: Option<u64>,
+}
+
+pub fn open(interface_name: &str, filter: Option<&XdpFilter>) -> io::Result<(AfXdpTx, AfXdpRx)> {
+    // Zero copy in driver mode if the NIC supports it, otherwise copy mode with the program in generic (SKB) mode,
+    // which works on any interface including veth. Without a filter every packet of the queue is redirected
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface_name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Failed to find network interface"))?;
+    let ifindex = interface.index;
+
+    let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let umem_len = NUM_FRAMES * FRAME_SIZE;
+    let umem_ptr = unsafe { libc::mmap(ptr::null_mut(), umem_len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+    if umem_ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let umem = Mmap { ptr: umem_ptr as *mut u8, len: umem_len };
+
+    let reg = XdpUmemReg { addr: umem.ptr as u64, len: umem_len as u64, chunk_size: FRAME_SIZE as u32, headroom: 0, flags: 0, tx_metadata_len: 0 };
+    set_option(&fd, XDP_UMEM_REG, &reg)?;
+    for option in [XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING] {
+        set_option(&fd, option, &RING_SIZE)?;
+    }
+
+    let mut offsets = XdpMmapOffsets::default();
+    let mut offsets_len = std::mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+    if unsafe { libc::getsockopt(fd.as_raw_fd(), SOL_XDP, XDP_MMAP_OFFSETS, &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void, &mut offsets_len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let raw_fd = fd.as_raw_fd();
+    let desc_len = std::mem::size_of::<XdpDesc>();
+    let rx = XdpRing::map(raw_fd, &offsets.rx, XDP_PGOFF_RX_RING, desc_len)?;
+    let tx = XdpRing::map(raw_fd, &offsets.tx, XDP_PGOFF_TX_RING, desc_len)?;
+    let mut fill = XdpRing::map(raw_fd, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING, std::mem::size_of::<u64>())?;
+    let completion = XdpRing::map(raw_fd, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING, std::mem::size_of::<u64>())?;
+
+    // Every receive frame starts in the fill ring, ready for the kernel
+    for i in TX_FRAMES..NUM_FRAMES {
+        unsafe { (fill.entry(fill.local) as *mut u64).write((i * FRAME_SIZE) as u64) };
+        fill.produce();
+    }
+
+    let is_zero_copy = bind(&fd, ifindex, XDP_ZEROCOPY).is_ok();
+    if !is_zero_copy {
+        bind(&fd, ifindex, XDP_COPY)?;
+    }
+
+    let map = bpf_create_xskmap()?;
+    bpf_update_elem(&map, QUEUE_ID, fd.as_raw_fd() as u32)?;
+    let prog = bpf_load_redirect_program(&map, filter)?;
+    let attach_flags = if is_zero_copy { XDP_FLAGS_DRV_MODE } else { XDP_FLAGS_SKB_MODE };
+    let link = bpf_link_xdp(&prog, ifindex, attach_flags)?;
+    println!("AF_XDP socket on {} queue {} in {} mode", interface_name, QUEUE_ID, if is_zero_copy { "zero copy" } else { "generic copy" });
+
+    let socket = Arc::new(XdpSocket { umem, fd, _link: link, _prog: prog, _map: map, mac_addr: interface.mac });
+    let free_frames = (0..TX_FRAMES).map(|i| (i * FRAME_SIZE) as u64).collect();
+    let tx = AfXdpTx { socket: Arc::clone(&socket), tx, completion, free_frames, pending: 0 };
+    let rx = AfXdpRx { socket, rx, fill, held_frame: None };
+    Ok((tx, rx))
+}
+
+impl AfXdpTx {
+    fn reclaim(&mut self) {
+        // Frames the kernel is done sending can be written again
+        while self.completion.available() > 0 {
+            let addr = unsafe { (self.completion.entry(self.completion.local) as *const u64).read() };
+            self.free_frames.push(addr - addr % FRAME_SIZE as u64);
+            self.completion.consume();
+        }
+    }
+}
+
+impl PacketIo for AfXdpTx {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.send_with(packet.len(), &mut |buffer| buffer.copy_from_slice(packet))?;
+        self.flush()
+    }
+
+    fn send_with(&mut self, length: usize, fill: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        if length > FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Frame of {}B does not fit in a UMEM frame", length)));
+        }
+        self.reclaim();
+        // Every frame is in flight, kick them and wait for the kernel to complete one
+        while self.free_frames.is_empty() {
+            self.flush()?;
+            thread::yield_now();
+            self.reclaim();
+        }
+
+        let addr = self.free_frames.pop().unwrap_or_default();
+        fill(unsafe { std::slice::from_raw_parts_mut(self.socket.frame(addr), length) });
+        unsafe { (self.tx.entry(self.tx.local) as *mut XdpDesc).write(XdpDesc { addr, len: length as u32, options: 0 }) };
+        self.tx.produce();
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        self.pending = 0;
+        self.socket.kick()
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Sending half of an AF_XDP socket"))
+    }
+
+    fn mac_addr(&self) -> Option<MacAddr> {
+        self.socket.mac_addr
+    }
+}
+
+impl PacketIo for AfXdpRx {
+    fn send(&mut self, _packet: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Receiving half of an AF_XDP socket"))
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        if let Some(addr) = self.held_frame.take() {
+            unsafe { (self.fill.entry(self.fill.local) as *mut u64).write(addr) };
+            self.fill.produce();
+        }
+        while self.rx.available() == 0 {
+            self.socket.poll(libc::POLLIN, Some(packet_io::RECV_TIMEOUT))?;
+        }
+        let desc = unsafe { (self.rx.entry(self.rx.local) as *const XdpDesc).read() };
+        self.rx.consume();
+        self.held_frame = Some(desc.addr - desc.addr % FRAME_SIZE as u64);
+        Ok(unsafe { std::slice::from_raw_parts(self.socket.frame(desc.addr), desc.len as usize) })
+    }
+
+    fn mac_addr(&self) -> Option<MacAddr> {
+        self.socket.mac_addr
+    }
+}
+
+fn set_option<T>(fd: &OwnedFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(fd.as_raw_fd(), SOL_XDP, option, value as *const T as *const libc::c_void, std::mem::size_of::<T>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bind(fd: &OwnedFd, ifindex: u32, flags: u16) -> io::Result<()> {
+    let addr = SockaddrXdp { sxdp_family: AF_XDP as u16, sxdp_flags: flags, sxdp_ifindex: ifindex, sxdp_queue_id: QUEUE_ID, sxdp_shared_umem_fd: 0 };
+    let result = unsafe {
+        libc::bind(fd.as_raw_fd(), &addr as *const SockaddrXdp as *const libc::sockaddr, std::mem::size_of::<SockaddrXdp>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u8; BPF_ATTR_LEN]) -> io::Result<OwnedFd> {
+    let result = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), BPF_ATTR_LEN) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(result as RawFd) })
+}
+
+fn write_attr(attr: &mut [u8; BPF_ATTR_LEN], offset: usize, value: &[u8]) {
+    attr[offset..offset + value.len()].copy_from_slice(value);
+}
+
+fn bpf_create_xskmap() -> io::Result<OwnedFd> {
+    let mut attr = [0u8; BPF_ATTR_LEN];
+    write_attr(&mut attr, 0, &BPF_MAP_TYPE_XSKMAP.to_ne_bytes());
+    // Key is the queue index, value the socket
+    write_attr(&mut attr, 4, &4u32.to_ne_bytes());
+    write_attr(&mut attr, 8, &4u32.to_ne_bytes());
+    write_attr(&mut attr, 12, &(QUEUE_ID + 1).to_ne_bytes());
+    bpf(BPF_MAP_CREATE, &mut attr)
+}
+
+fn bpf_update_elem(map: &OwnedFd, key: u32, value: u32) -> io::Result<()> {
+    let mut attr = [0u8; BPF_ATTR_LEN];
+    write_attr(&mut attr, 0, &(map.as_raw_fd() as u32).to_ne_bytes());
+    write_attr(&mut attr, 8, &(&key as *const u32 as u64).to_ne_bytes());
+    write_attr(&mut attr, 16, &(&value as *const u32 as u64).to_ne_bytes());
+    let result = unsafe { libc::syscall(libc::SYS_bpf, BPF_MAP_UPDATE_ELEM, attr.as_mut_ptr(), BPF_ATTR_LEN) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_filter_program(filter: &XdpFilter) -> (Vec<BpfInsn>, Vec<usize>) {
+    // if (!filter.matches(ctx->data, ctx->data_end)) return XDP_PASS;
+    // Returns the program and the jumps to patch with the offset of the XDP_PASS exit. r1 is left to the ctx, r2 and
+    // r3 hold the start and end of the packet
+    let checks = filter.get_checks();
+    let checked_len = checks.iter().map(FieldCheck::end).max().unwrap_or(0);
+
+    let mut program = vec![
+        // r2 = ctx->data, r3 = ctx->data_end
+        BpfInsn { code: 0x61, regs: 2 | 1 << 4, off: XDP_MD_DATA, imm: 0 },
+        BpfInsn { code: 0x61, regs: 3 | 1 << 4, off: XDP_MD_DATA_END, imm: 0 },
+        // if (r2 + checked_len > r3) pass, the verifier needs it before any read
+        BpfInsn { code: 0xbf, regs: 4 | 2 << 4, off: 0, imm: 0 },
+        BpfInsn { code: 0x07, regs: 4, off: 0, imm: checked_len as i32 },
+        BpfInsn { code: 0x2d, regs: 4 | 3 << 4, off: 0, imm: 0 },
+    ];
+    let mut to_pass = vec![program.len() - 1];
+    // r5 = the field at offset in the packet, masked and compared with the value in network order. 32 bit operations
+    // so that the value is not sign extended
+    for check in checks {
+        let code = match check.value.len() {
+            1 => 0x71,
+            2 => 0x69,
+            _ => 0x61,
+        };
+        let (mut value, mut mask) = ([0u8; 4], [0u8; 4]);
+        value[..check.value.len()].copy_from_slice(&check.value);
+        mask[..check.mask.len()].copy_from_slice(&check.mask);
+        program.push(BpfInsn { code, regs: 5 | 2 << 4, off: check.offset as i16, imm: 0 });
+        if check.mask.iter().any(|&byte| byte != 0xff) {
+            program.push(BpfInsn { code: 0x54, regs: 5, off: 0, imm: i32::from_ne_bytes(mask) });
+        }
+        program.push(BpfInsn { code: 0x56, regs: 5, off: 0, imm: i32::from_ne_bytes(value) });
+        to_pass.push(program.len() - 1);
+    }
+    (program, to_pass)
+}
+
+fn bpf_load_redirect_program(map: &OwnedFd, filter: Option<&XdpFilter>) -> io::Result<OwnedFd> {
+    // return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS); after the checks of the filter
+    let (mut program, to_pass) = filter.map(get_filter_program).unwrap_or_default();
+    program.extend([
+        // r2 = ctx->rx_queue_index
+        BpfInsn { code: 0x61, regs: 2 | 1 << 4, off: XDP_MD_RX_QUEUE_INDEX, imm: 0 },
+        // r1 = map, a 64 bit immediate over two instructions
+        BpfInsn { code: 0x18, regs: 1 | BPF_PSEUDO_MAP_FD << 4, off: 0, imm: map.as_raw_fd() },
+        BpfInsn { code: 0, regs: 0, off: 0, imm: 0 },
+        // r3 = action when the queue has no socket
+        BpfInsn { code: 0xb7, regs: 3, off: 0, imm: XDP_PASS },
+        BpfInsn { code: 0x85, regs: 0, off: 0, imm: BPF_FUNC_REDIRECT_MAP },
+        BpfInsn { code: 0x95, regs: 0, off: 0, imm: 0 },
+    ]);
+    // Packets not matching the filter: return XDP_PASS. The verifier rejects unreachable instructions, so only with
+    // a filter
+    if !to_pass.is_empty() {
+        let pass = program.len();
+        program.extend([
+            BpfInsn { code: 0xb7, regs: 0, off: 0, imm: XDP_PASS },
+            BpfInsn { code: 0x95, regs: 0, off: 0, imm: 0 },
+        ]);
+        for jump in to_pass {
+            program[jump].off = (pass - jump - 1) as i16;
+        }
+    }
+    let license = b"Dual MIT/GPL\0";
+    let mut attr = [0u8; BPF_ATTR_LEN];
+    write_attr(&mut attr, 0, &BPF_PROG_TYPE_XDP.to_ne_bytes());
+    write_attr(&mut attr, 4, &(program.len() as u32).to_ne_bytes());
+    write_attr(&mut attr, 8, &(program.as_ptr() as u64).to_ne_bytes());
+    write_attr(&mut attr, 16, &(license.as_ptr() as u64).to_ne_bytes());
+    write_attr(&mut attr, 48, b"ditto_xsk");
+    write_attr(&mut attr, 68, &BPF_XDP.to_ne_bytes());
+    bpf(BPF_PROG_LOAD, &mut attr)
+}
+
+fn bpf_link_xdp(prog: &OwnedFd, ifindex: u32, flags: u32) -> io::Result<OwnedFd> {
+    let mut attr = [0u8; BPF_ATTR_LEN];
+    write_attr(&mut attr, 0, &(prog.as_raw_fd() as u32).to_ne_bytes());
+    write_attr(&mut attr, 4, &ifindex.to_ne_bytes());
+    write_attr(&mut attr, 8, &BPF_XDP.to_ne_bytes());
+    write_attr(&mut attr, 12, &flags.to_ne_bytes());
+    bpf(BPF_LINK_CREATE, &mut attr)
+}
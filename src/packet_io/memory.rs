@@ -0,0 +1,50 @@
+use std::io;
+use std::time::Duration;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use crate::packet_io::{self, PacketIo};
+
+// Frames in flight on a link before new ones are dropped, like a full NIC queue
+const LINK_CAPACITY: usize = 4096;
+
+// One end of an in-memory link, clone it to send and receive on the same end from several threads
+#[derive(Clone)]
+pub struct MemoryIo {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    packet: Vec<u8>,
+}
+
+pub fn link() -> (MemoryIo, MemoryIo) {
+    // What is sent on one end is received on the other
+    let (a_tx, b_rx) = channel::bounded(LINK_CAPACITY);
+    let (b_tx, a_rx) = channel::bounded(LINK_CAPACITY);
+    (MemoryIo { tx: a_tx, rx: a_rx, packet: Vec::new() }, MemoryIo { tx: b_tx, rx: b_rx, packet: Vec::new() })
+}
+
+impl MemoryIo {
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<&[u8]> {
+        // None if nothing arrived in time
+        self.packet = self.rx.recv_timeout(timeout).ok()?;
+        Some(&self.packet)
+    }
+}
+
+impl PacketIo for MemoryIo {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.tx.try_send(packet.to_vec()) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Other end of the link was dropped")),
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        match self.rx.recv_timeout(packet_io::RECV_TIMEOUT) {
+            Ok(packet) => {
+                self.packet = packet;
+                Ok(&self.packet)
+            },
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received in time")),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Other end of the link was dropped")),
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use pnet::datalink;
+use pnet::util::MacAddr;
+use crate::packet_io::{self, PacketIo};
+
+// From linux/if_packet.h, not exported by every libc version
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const PACKET_LOSS: libc::c_int = 14;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_WRONG_FORMAT: u32 = 1 << 2;
+const ETH_P_ALL: u16 = 0x0003;
+
+// Offsets of the fields used in struct tpacket_block_desc and struct tpacket3_hdr
+const BLOCK_STATUS_OFFSET: usize = 8;
+const BLOCK_NUM_PACKETS_OFFSET: usize = 12;
+const BLOCK_FIRST_PACKET_OFFSET: usize = 16;
+const PACKET_NEXT_OFFSET: usize = 0;
+const PACKET_SNAPLEN_OFFSET: usize = 12;
+const PACKET_LEN_OFFSET: usize = 16;
+const PACKET_STATUS_OFFSET: usize = 20;
+const PACKET_MAC_OFFSET: usize = 24;
+// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)), where the kernel reads the frame to send
+const TX_DATA_OFFSET: usize = 48;
+
+// Large enough for a full MTU frame and its header
+const FRAME_SIZE: usize = 2048;
+const RX_BLOCK_SIZE: usize = 1 << 18;
+const RX_BLOCK_NR: usize = 16;
+// Partially filled blocks are handed over after this long, in ms
+const RX_BLOCK_TIMEOUT: u32 = 1;
+const TX_BLOCK_SIZE: usize = 1 << 16;
+const TX_BLOCK_NR: usize = 8;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+// Memory shared with the kernel, unmapped when dropped
+struct Ring {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The ring is only used by the thread owning the socket
+unsafe impl Send for Ring {}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+struct TxRing {
+    ring: Ring,
+    frame_nr: usize,
+    // Next frame to fill
+    frame: usize,
+    // Frames filled since the last kick
+    pending: usize,
+}
+
+struct RxRing {
+    ring: Ring,
+    // Block being read, it goes back to the kernel once all its packets were returned
+    block: usize,
+    is_block_held: bool,
+    remaining: u32,
+    next_packet: usize,
+}
+
+// AF_PACKET socket with a TX or an RX ring. Frames are written to and read from the rings, so sending a batch of
+// frames takes a single syscall and receiving takes none as long as packets keep coming
+pub struct PacketMmap {
+    // Declared first so the rings are unmapped before the socket is closed
+    tx: Option<TxRing>,
+    rx: Option<RxRing>,
+    fd: OwnedFd,
+    mac_addr: Option<MacAddr>,
+}
+
+impl PacketMmap {
+    pub fn open_tx(interface_name: &str) -> io::Result<PacketMmap> {
+        // Protocol 0 so the socket never receives anything
+        let (mut packet_mmap, ifindex) = PacketMmap::open(interface_name, 0)?;
+        // Frames the kernel rejects are skipped instead of left in the ring, only accepted before the ring is set up
+        packet_mmap.set_option(PACKET_LOSS, &1 as &libc::c_int)?;
+        let req = TpacketReq3 {
+            tp_block_size: TX_BLOCK_SIZE as u32,
+            tp_block_nr: TX_BLOCK_NR as u32,
+            tp_frame_size: FRAME_SIZE as u32,
+            tp_frame_nr: (TX_BLOCK_SIZE / FRAME_SIZE * TX_BLOCK_NR) as u32,
+            // Must be 0 for a TX ring
+            tp_retire_blk_tov: 0,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        let ring = packet_mmap.map_ring(PACKET_TX_RING, &req)?;
+        packet_mmap.tx = Some(TxRing { ring, frame_nr: req.tp_frame_nr as usize, frame: 0, pending: 0 });
+        packet_mmap.bind(ifindex, 0)?;
+        Ok(packet_mmap)
+    }
+
+    pub fn open_rx(interface_name: &str) -> io::Result<PacketMmap> {
+        let (mut packet_mmap, ifindex) = PacketMmap::open(interface_name, ETH_P_ALL)?;
+        let req = TpacketReq3 {
+            tp_block_size: RX_BLOCK_SIZE as u32,
+            tp_block_nr: RX_BLOCK_NR as u32,
+            tp_frame_size: FRAME_SIZE as u32,
+            tp_frame_nr: (RX_BLOCK_SIZE / FRAME_SIZE * RX_BLOCK_NR) as u32,
+            tp_retire_blk_tov: RX_BLOCK_TIMEOUT,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        let ring = packet_mmap.map_ring(PACKET_RX_RING, &req)?;
+        packet_mmap.rx = Some(RxRing { ring, block: 0, is_block_held: false, remaining: 0, next_packet: 0 });
+        packet_mmap.bind(ifindex, ETH_P_ALL)?;
+        Ok(packet_mmap)
+    }
+
+    fn open(interface_name: &str, protocol: u16) -> io::Result<(PacketMmap, libc::c_int)> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Failed to find network interface"))?;
+
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol.to_be() as libc::c_int) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let packet_mmap = PacketMmap { tx: None, rx: None, fd: unsafe { OwnedFd::from_raw_fd(fd) }, mac_addr: interface.mac };
+        packet_mmap.set_option(PACKET_VERSION, &TPACKET_V3)?;
+        Ok((packet_mmap, interface.index as libc::c_int))
+    }
+
+    fn map_ring(&self, option: libc::c_int, req: &TpacketReq3) -> io::Result<Ring> {
+        self.set_option(option, req)?;
+        let len = (req.tp_block_size * req.tp_block_nr) as usize;
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, self.fd.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Ring { ptr: ptr as *mut u8, len })
+    }
+
+    fn set_option<T>(&self, option: libc::c_int, value: &T) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(self.fd.as_raw_fd(), libc::SOL_PACKET, option, value as *const T as *const libc::c_void, std::mem::size_of::<T>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn bind(&self, ifindex: libc::c_int, protocol: u16) -> io::Result<()> {
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol.to_be();
+        addr.sll_ifindex = ifindex;
+        let result = unsafe {
+            libc::bind(self.fd.as_raw_fd(), &addr as *const libc::sockaddr_ll as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn poll(&self, events: libc::c_short, timeout: Option<Duration>) -> io::Result<()> {
+        packet_io::poll(self.fd.as_raw_fd(), events, timeout)
+    }
+}
+
+impl PacketIo for PacketMmap {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.send_with(packet.len(), &mut |buffer| buffer.copy_from_slice(packet))?;
+        self.flush()
+    }
+
+    fn send_with(&mut self, length: usize, fill: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        if length > FRAME_SIZE - TX_DATA_OFFSET {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Frame of {}B does not fit in the TX ring", length)));
+        }
+        let frame = match &self.tx {
+            Some(tx) => unsafe { tx.ring.ptr.add(tx.frame * FRAME_SIZE) },
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, "Socket has no TX ring")),
+        };
+
+        // Ring full, kick the pending frames and wait for the kernel to send one. Fails with TimedOut if it does not
+        // in time, so that the send thread still notices a shutdown
+        let deadline = Instant::now() + packet_io::RECV_TIMEOUT;
+        loop {
+            let status = get_status(frame, PACKET_STATUS_OFFSET);
+            match status.load(Ordering::Acquire) {
+                TP_STATUS_AVAILABLE => break,
+                // Rejected by the kernel despite PACKET_LOSS, the frame is given up so that the ring keeps moving
+                current if current & TP_STATUS_WRONG_FORMAT != 0 => {
+                    status.store(TP_STATUS_AVAILABLE, Ordering::Release);
+                    break;
+                },
+                _ => (),
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "TX ring still full"));
+            }
+            self.flush()?;
+            match self.poll(libc::POLLOUT, Some(remaining)) {
+                Err(e) if !packet_io::is_timeout(&e) => return Err(e),
+                _ => (),
+            }
+        }
+
+        unsafe {
+            fill(std::slice::from_raw_parts_mut(frame.add(TX_DATA_OFFSET), length));
+            write_u32(frame, PACKET_NEXT_OFFSET, 0);
+            write_u32(frame, PACKET_SNAPLEN_OFFSET, length as u32);
+            write_u32(frame, PACKET_LEN_OFFSET, length as u32);
+        }
+        get_status(frame, PACKET_STATUS_OFFSET).store(TP_STATUS_SEND_REQUEST, Ordering::Release);
+
+        if let Some(tx) = &mut self.tx {
+            tx.frame = (tx.frame + 1) % tx.frame_nr;
+            tx.pending += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.tx {
+            Some(tx) if tx.pending > 0 => tx.pending = 0,
+            _ => return Ok(()),
+        }
+        if unsafe { libc::send(self.fd.as_raw_fd(), ptr::null(), 0, libc::MSG_DONTWAIT) } < 0 {
+            let error = io::Error::last_os_error();
+            // The frames stay in the ring and go out with the next kick
+            if error.kind() != io::ErrorKind::WouldBlock && error.raw_os_error() != Some(libc::ENOBUFS) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let rx = match &mut self.rx {
+                Some(rx) => rx,
+                None => return Err(io::Error::new(io::ErrorKind::Unsupported, "Socket has no RX ring")),
+            };
+            let block = unsafe { rx.ring.ptr.add(rx.block * RX_BLOCK_SIZE) };
+
+            if rx.remaining > 0 {
+                rx.remaining -= 1;
+                unsafe {
+                    let packet = block.add(rx.next_packet);
+                    let snaplen = read_u32(packet, PACKET_SNAPLEN_OFFSET) as usize;
+                    let mac = (packet.add(PACKET_MAC_OFFSET) as *const u16).read_unaligned() as usize;
+                    rx.next_packet += read_u32(packet, PACKET_NEXT_OFFSET) as usize;
+                    return Ok(std::slice::from_raw_parts(packet.add(mac), snaplen));
+                }
+            }
+
+            if rx.is_block_held {
+                // Every packet of the block was returned, give it back and move to the next one
+                get_status(block, BLOCK_STATUS_OFFSET).store(TP_STATUS_KERNEL, Ordering::Release);
+                rx.block = (rx.block + 1) % RX_BLOCK_NR;
+                rx.is_block_held = false;
+                continue;
+            }
+
+            if get_status(block, BLOCK_STATUS_OFFSET).load(Ordering::Acquire) & TP_STATUS_USER != 0 {
+                rx.is_block_held = true;
+                rx.remaining = unsafe { read_u32(block, BLOCK_NUM_PACKETS_OFFSET) };
+                rx.next_packet = unsafe { read_u32(block, BLOCK_FIRST_PACKET_OFFSET) } as usize;
+            } else {
+                self.poll(libc::POLLIN | libc::POLLERR, Some(packet_io::RECV_TIMEOUT))?;
+            }
+        }
+    }
+
+    fn mac_addr(&self) -> Option<MacAddr> {
+        self.mac_addr
+    }
+}
+
+fn get_status<'a>(base: *mut u8, offset: usize) -> &'a AtomicU32 {
+    // Status words are shared with the kernel, they are always 4 byte aligned
+    unsafe { &*(base.add(offset) as *const AtomicU32) }
+}
+
+unsafe fn read_u32(base: *const u8, offset: usize) -> u32 {
+    (base.add(offset) as *const u32).read_volatile()
+}
+
+unsafe fn write_u32(base: *mut u8, offset: usize, value: u32) {
+    (base.add(offset) as *mut u32).write_volatile(value)
+}
@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::packet_io::PacketIo;
+use crate::pcap::{PcapReader, PcapWriter, LINKTYPE_ETHERNET};
+
+// Replays the frames of a capture and records the frames sent, either side can be left out
+pub struct PcapIo<R: Read, W: Write> {
+    reader: Option<PcapReader<R>>,
+    writer: Option<PcapWriter<W>>,
+}
+
+impl PcapIo<BufReader<File>, BufWriter<File>> {
+    pub fn open(input: Option<&str>, output: Option<&str>) -> io::Result<Self> {
+        let reader = input.map(PcapReader::open).transpose()?;
+        let writer = output.map(|path| PcapWriter::create(path, LINKTYPE_ETHERNET)).transpose()?;
+        Ok(PcapIo { reader, writer })
+    }
+}
+
+impl<R: Read, W: Write> PcapIo<R, W> {
+    pub fn new(reader: Option<PcapReader<R>>, writer: Option<PcapWriter<W>>) -> Self {
+        PcapIo { reader, writer }
+    }
+
+    pub fn into_writer(self) -> Option<PcapWriter<W>> {
+        self.writer
+    }
+}
+
+impl<R: Read + Send, W: Write + Send> PacketIo for PcapIo<R, W> {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        // Without an output file the frames are discarded
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writer.write_packet(timestamp, packet)?;
+        // Keep the file readable even if the process is killed
+        writer.flush()
+    }
+
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No input capture")),
+        };
+        match reader.read_packet()? {
+            Some(record) => Ok(record.data),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "End of the input capture")),
+        }
+    }
+}
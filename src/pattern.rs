@@ -1,14 +1,17 @@
-/*
-TO BE MORE EFFICIENT ASSUME THAT PATTERN IS IN ASCENDING ORDER
-*/
+use std::net::IpAddr;
+use crate::crypto;
+use crate::shim::{self, WireFormat};
 
 // 86B overhead with VPN: 1428+86=1514B -> Or else fragment
-pub const PATTERN: [usize; 3] = [200, 1400, 1400];
+// Used when no pattern is given, the actual pattern is read from the [pattern] section of the config
+pub const DEFAULT_PATTERN: [usize; 3] = [200, 1400, 1400];
 
 // Largest size possible in pattern
 pub const MTU: usize = 1500;
 pub const CHAFF: [u8; MTU] = [0; MTU];
 const WRAP_AND_WIREGUARD_OVERHAD: f64 = 100.0;
+const FACTOR_MEGABITS: f64 = 1e6;
+const BITS_PER_BYTE: f64 = 8.0;
 
 pub const IP_HEADER_LEN: usize = 20;
 pub const IP_SRC_ADDR_OFFSET: usize = 12;
@@ -18,32 +21,139 @@ pub const ETH_MAC_SRC_ADDR_OFFSET: usize = 6;
 pub const IP_ADDR_LEN: usize = 4;
 pub const MAC_ADDR_LEN: usize = 6;
 pub const IP_VERSION: u8 = 4;
+// Outer header of slots sent between IPv6 addresses, without extension headers
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const IPV6_SRC_ADDR_OFFSET: usize = 8;
+pub const IPV6_DST_ADDR_OFFSET: usize = 24;
+pub const IPV6_ADDR_LEN: usize = 16;
+pub const IPV6_VERSION: u8 = 6;
+pub const IP_PROTOCOL_OFFSET: usize = 9;
+pub const IPV6_NEXT_HEADER_OFFSET: usize = 6;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+// Between the outer IP header and the slot when slots are sent over UDP
+pub const UDP_HEADER_LEN: usize = 8;
+pub const UDP_DST_PORT_OFFSET: usize = 2;
+// Length of the inner packet, written in front of it in encrypted slots of the legacy wire format
+pub const INNER_LEN_LEN: usize = 2;
 pub const OBF_ETHERTYPE: pnet::packet::ethernet::EtherType = pnet::packet::ethernet::EtherType(2049);
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+// 802.1Q and 802.1ad tags, 4B each between the source MAC address and the ethertype of the payload
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_QINQ: u16 = 0x88a8;
+pub const VLAN_TAG_LEN: usize = 4;
 
 // Usually this would be done with routing tables, but since I needed to send the packet back to its source for my test I hardcoded the wireguard address of Zurich
 pub const IP_NEXT_HOP: [u8;4] = [10, 7, 0 , 2];
 
-pub fn get_sorted_indices() -> Vec<usize> {
+pub fn validate_pattern(pattern: &[usize], outer_header_len: usize, is_encrypted: bool, wire_format: WireFormat) -> Result<(), String> {
+    // Reject patterns that could not be sent, checked before a pattern is used so the hot path can trust it
+    if pattern.is_empty() {
+        return Err("Invalid pattern: it must contain at least one packet size".to_string());
+    }
+    // Slots also carry the shim header, and the nonce and tag when encrypted
+    let min_size = get_min_slot_size(is_encrypted, wire_format);
+    for (i, &size) in pattern.iter().enumerate() {
+        if size < IP_HEADER_LEN {
+            return Err(format!("Invalid pattern: size {}B at index {} is below the IP header length of {}B", size, i, IP_HEADER_LEN));
+        }
+        if size < min_size {
+            return Err(format!("Invalid pattern: size {}B at index {} is too small for the slot headers, which need at least {}B", size, i, min_size));
+        }
+        if size + outer_header_len > MTU {
+            return Err(format!("Invalid pattern: size {}B at index {} is above the MTU of {}B once wrapped in {}B of outer headers", size, i, MTU, outer_header_len));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_gaps(gaps: &[f64], pattern_len: usize) -> Result<(), String> {
+    // Relative gaps after each slot of a pattern, 0 to send the next slot right away, but not all of them
+    if gaps.len() != pattern_len {
+        return Err(format!("Invalid gaps: there must be one per size of the pattern, {} for {} sizes", gaps.len(), pattern_len));
+    }
+    if let Some((i, gap)) = gaps.iter().enumerate().find(|(_, gap)| !gap.is_finite() || **gap < 0.0) {
+        return Err(format!("Invalid gaps: gap {} at index {} must be a non-negative number", gap, i));
+    }
+    if gaps.iter().sum::<f64>() <= 0.0 {
+        return Err("Invalid gaps: at least one gap must be above 0".to_string());
+    }
+    Ok(())
+}
+
+pub fn get_ethertype(frame: &[u8]) -> Option<(u16, usize)> {
+    // Ethertype of the payload of an Ethernet frame and the offset of the payload, after any VLAN tags
+    let mut offset = ETH_HEADER_LEN - 2;
+    loop {
+        let ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+            return Some((ethertype, offset + 2));
+        }
+        offset += VLAN_TAG_LEN;
+    }
+}
+
+pub fn get_ip_header_len(addr: IpAddr) -> usize {
+    // Outer header in front of every slot, the sizes of the pattern do not include it
+    match addr {
+        IpAddr::V4(_) => IP_HEADER_LEN,
+        IpAddr::V6(_) => IPV6_HEADER_LEN,
+    }
+}
+
+pub fn get_outer_header_len(addr: IpAddr, is_udp: bool) -> usize {
+    // IP header, and UDP header when slots are sent over UDP
+    get_ip_header_len(addr) + if is_udp { UDP_HEADER_LEN } else { 0 }
+}
+
+pub fn get_inner_header_len(is_encrypted: bool, wire_format: WireFormat) -> usize {
+    // Header carrying the inner length, the legacy format only needs one when the outer IP header cannot be used
+    match wire_format {
+        WireFormat::Shim => shim::SHIM_HEADER_LEN,
+        WireFormat::Legacy if is_encrypted => INNER_LEN_LEN,
+        WireFormat::Legacy => 0,
+    }
+}
+
+pub fn get_slot_overhead(is_encrypted: bool, wire_format: WireFormat) -> usize {
+    // Bytes of each slot, after the outer IP header, that are not available for the inner packet
+    let crypto_overhead = if is_encrypted { crypto::OVERHEAD } else { 0 };
+    crypto_overhead + get_inner_header_len(is_encrypted, wire_format)
+}
+
+pub fn get_min_slot_size(is_encrypted: bool, wire_format: WireFormat) -> usize {
+    // Slots with headers must leave room for at least an Ethernet header, plain legacy slots only need the IP header
+    match get_slot_overhead(is_encrypted, wire_format) {
+        0 => 0,
+        overhead => overhead + ETH_HEADER_LEN,
+    }
+}
+
+pub fn get_sorted_indices(pattern: &[usize]) -> Vec<usize> {
     // Gets sorted indices needed to match incoming packets and the corresponding queue index to choose
-    let mut indices: Vec<usize> = (0..PATTERN.len()).collect();
+    let mut indices: Vec<usize> = (0..pattern.len()).collect();
     // Sort the indices based on the corresponding values in the data vector
-    indices.sort_by_key(|&i| &PATTERN[i]);
+    indices.sort_by_key(|&i| &pattern[i]);
     indices
 }
 
-pub fn get_push_state_vector() -> Vec<(usize,usize)> {
+pub fn get_push_state_vector(pattern: &[usize]) -> Vec<(usize,usize)> {
     // Store in a vector the ranges of each state [state_start_index,next_state_start[
     // Fancy encoding so no need to use hash maps and reduce overhead compared to accessing lists. 
-    // Since patterns are relatively small and in increasing order it should be ok.
+    // Since patterns are relatively small it should be ok.
+    // Indices are positions in the pattern sorted by size, map them back to a queue with get_sorted_indices
+    // so the pattern itself can be in any order and sizes can be interleaved.
     // Make the vector as long as the pattern for easier processing after
     // e.g PATTERN=[100,200,300,300,300,500] gives [(0,1),(1,2),(2,5),(2,5),(2,5),(5,6)]
+    // and PATTERN=[1400,200,1400,600] is sorted to [200,600,1400,1400] and gives [(0,1),(1,2),(2,4),(2,4)]
     // First number in tuple is next queue to push to, second number is the index at which the next state starts
+    let sorted_indices = get_sorted_indices(pattern);
     let mut state = Vec::new();
     let mut count = 0;
 
     let mut previous_state = 0;
-    for i in 0..PATTERN.len() {
-        if i < PATTERN.len()-1 && PATTERN[i] == PATTERN[i+1] {
+    for i in 0..sorted_indices.len() {
+        if i < sorted_indices.len()-1 && pattern[sorted_indices[i]] == pattern[sorted_indices[i+1]] {
             count += 1
         } else {
             for _ in 0..count+1 {
@@ -56,10 +166,21 @@ pub fn get_push_state_vector() -> Vec<(usize,usize)> {
     state
 }
 
-pub fn get_average_pattern_length() -> f64 {
+pub fn get_average_pattern_length(pattern: &[usize]) -> f64 {
     let mut total = 0.0;
-    for p in PATTERN {
+    for &p in pattern {
         total += p as f64;
     }
-    total / PATTERN.len() as f64 + WRAP_AND_WIREGUARD_OVERHAD
+    total / pattern.len() as f64 + WRAP_AND_WIREGUARD_OVERHAD
+}
+
+pub fn get_pps(rate: f64, pattern: &[usize]) -> f64 {
+    // Packets per second to send the pattern at rate Mbps. Uneven gaps between the slots average to the same interval,
+    // so they do not change it
+    rate / get_average_pattern_length(pattern) * FACTOR_MEGABITS / BITS_PER_BYTE
+}
+
+pub fn get_rate(pps: f64, pattern: &[usize]) -> f64 {
+    // Rate in Mbps of the pattern sent at pps packets per second
+    pps * get_average_pattern_length(pattern) * BITS_PER_BYTE / FACTOR_MEGABITS
 }
\ No newline at end of file
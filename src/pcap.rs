@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+
+// Classic libpcap file format, pcapng is not supported
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+// Larger records are treated as a corrupt file instead of trying to allocate them
+const MAX_RECORD_LEN: usize = 1 << 18;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+pub struct PcapRecord<'a> {
+    pub timestamp: Duration,
+    // Length of the packet on the wire, data can be shorter if the capture was truncated
+    pub orig_len: usize,
+    pub data: &'a [u8],
+}
+
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+pub struct PcapReader<R: Read> {
+    reader: R,
+    is_swapped: bool,
+    is_nanos: bool,
+    pub link_type: u32,
+    buffer: Vec<u8>,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        PcapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; GLOBAL_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (is_swapped, is_nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            m if m == MAGIC_MICROS.swap_bytes() => (true, false),
+            m if m == MAGIC_NANOS.swap_bytes() => (true, true),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a pcap file (pcapng is not supported)")),
+        };
+
+        let mut pcap = PcapReader { reader, is_swapped, is_nanos, link_type: 0, buffer: Vec::new() };
+        pcap.link_type = pcap.read_u32(&header[20..24]);
+        Ok(pcap)
+    }
+
+    pub fn read_packet(&mut self) -> io::Result<Option<PcapRecord<'_>>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        // A clean end of file can only happen between two records
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+
+        let ts_sec = self.read_u32(&header[0..4]) as u64;
+        let ts_frac = self.read_u32(&header[4..8]) as u64;
+        let incl_len = self.read_u32(&header[8..12]) as usize;
+        let orig_len = self.read_u32(&header[12..16]) as usize;
+        if incl_len > MAX_RECORD_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Pcap record of {}B is too large", incl_len)));
+        }
+
+        self.buffer.resize(incl_len, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+
+        let timestamp = if self.is_nanos {
+            Duration::new(ts_sec, ts_frac as u32)
+        } else {
+            Duration::new(ts_sec, 0) + Duration::from_micros(ts_frac)
+        };
+        Ok(Some(PcapRecord { timestamp, orig_len, data: &self.buffer }))
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if self.is_swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create(path: &str, link_type: u32) -> io::Result<Self> {
+        PcapWriter::new(BufWriter::new(File::create(path)?), link_type)
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W, link_type: u32) -> io::Result<Self> {
+        // Always written in microseconds and in native byte order, which readers detect from the magic
+        let mut header = Vec::with_capacity(GLOBAL_HEADER_LEN);
+        header.extend_from_slice(&MAGIC_MICROS.to_ne_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_ne_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_ne_bytes());
+        // Time zone offset and timestamp accuracy, always 0
+        header.extend_from_slice(&[0u8; 8]);
+        header.extend_from_slice(&(MAX_RECORD_LEN as u32).to_ne_bytes());
+        header.extend_from_slice(&link_type.to_ne_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    pub fn write_packet(&mut self, timestamp: Duration, data: &[u8]) -> io::Result<()> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(timestamp.as_secs() as u32).to_ne_bytes());
+        header[4..8].copy_from_slice(&timestamp.subsec_micros().to_ne_bytes());
+        header[8..12].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        header[12..16].copy_from_slice(&(data.len() as u32).to_ne_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
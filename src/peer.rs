@@ -0,0 +1,94 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use pnet::util::MacAddr;
+use crate::pattern;
+
+// Block of addresses routed to a peer, e.g. 10.8.0.0/24, a single address if the prefix length is left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(subnet), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(subnet) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(subnet), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(subnet) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| format!("Invalid address in subnet `{}`", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => max_len,
+            len => len.parse().ok().filter(|&len| len <= max_len).ok_or_else(|| format!("Invalid prefix length in subnet `{}`, must be at most {}", s, max_len))?,
+        };
+        Ok(Subnet { addr, prefix_len })
+    }
+}
+
+// Which peer the frames to obfuscate are sent to, by their destination MAC address, then by the longest subnet their
+// destination IP address is in. Frames matching no peer go to the first one
+#[derive(Debug, Clone, Default)]
+pub struct Classifier {
+    macs: Vec<(MacAddr, usize)>,
+    subnets: Vec<(Subnet, usize)>,
+}
+
+impl Classifier {
+    pub fn add_mac(&mut self, mac: MacAddr, peer: usize) {
+        self.macs.push((mac, peer));
+    }
+
+    pub fn add_subnet(&mut self, subnet: Subnet, peer: usize) {
+        self.subnets.push((subnet, peer));
+    }
+
+    pub fn classify(&self, frame: &[u8]) -> usize {
+        if let Some(dst_mac) = frame.get(..pattern::MAC_ADDR_LEN) {
+            let dst_mac = MacAddr::new(dst_mac[0], dst_mac[1], dst_mac[2], dst_mac[3], dst_mac[4], dst_mac[5]);
+            if let Some(&(_, peer)) = self.macs.iter().find(|(mac, _)| *mac == dst_mac) {
+                return peer;
+            }
+        }
+        let Some(dst) = get_ip_dst(frame) else {
+            return 0;
+        };
+        self.subnets.iter()
+            .filter(|(subnet, _)| subnet.contains(dst))
+            .max_by_key(|(subnet, _)| subnet.prefix_len)
+            .map_or(0, |&(_, peer)| peer)
+    }
+}
+
+fn get_ip_dst(frame: &[u8]) -> Option<IpAddr> {
+    // Destination of the IP packet carried by an Ethernet frame, after any VLAN tags
+    let (ethertype, offset) = pattern::get_ethertype(frame)?;
+    match ethertype {
+        pattern::ETHERTYPE_IPV4 => {
+            let start = offset + pattern::IP_DST_ADDR_OFFSET;
+            let octets: [u8; pattern::IP_ADDR_LEN] = frame.get(start..start + pattern::IP_ADDR_LEN)?.try_into().ok()?;
+            Some(IpAddr::from(octets))
+        },
+        pattern::ETHERTYPE_IPV6 => {
+            let start = offset + pattern::IPV6_DST_ADDR_OFFSET;
+            let octets: [u8; pattern::IPV6_ADDR_LEN] = frame.get(start..start + pattern::IPV6_ADDR_LEN)?.try_into().ok()?;
+            Some(IpAddr::from(octets))
+        },
+        _ => None,
+    }
+}
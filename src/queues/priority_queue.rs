@@ -1,37 +1,274 @@
-use pnet::packet::ipv4;
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::Packet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use pnet::packet::{ipv4, ipv6, udp};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use crossbeam::queue::ArrayQueue;
+use crate::crypto::{self, Cipher};
+use crate::metrics::Counter;
+use crate::shim::{self, WireFormat};
 use crate::pattern;
 
-const MAX_Q_LEN: usize = 1024;
+// Most packets a queue can hold, max_packets can only be lower
+pub const MAX_Q_LEN: usize = 1024;
+// Fewest packets a queue holds by default however low the rate
+const MIN_Q_LEN: usize = 8;
+// By default packets wait at most this long, or for this many slots of their queue at low rates
+const DEFAULT_MAX_SOJOURN: Duration = Duration::from_millis(100);
+const MIN_SOJOURN_SLOTS: u32 = 4;
+// Smallest Ethernet frame, how many packets an aggregate slot can carry at most
+const MIN_FRAME_LEN: usize = 60;
 
 // const SRC_IP_ADDR: [u8;4] = [10, 9, 0, 2];
 // const DST_IP_ADDR: [u8;4] = [10, 9, 0, 1];
 
+// Ports of the UDP header slots are wrapped in, src is the local one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpPorts {
+    pub src: u16,
+    pub dst: u16,
+}
+
+// How slots are wrapped, shared by all the queues of a scheduler and by the deobfuscation of the peer's slots
+#[derive(Clone)]
+pub struct SlotFormat {
+    // Both IPv4 or both IPv6, the outer header of the slots is of the same version
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    // Encrypt every slot, real or chaff, if a key is configured
+    pub cipher: Option<Cipher>,
+    pub wire_format: WireFormat,
+    // Fill slots with as many queued packets as fit instead of one each, only with the shim header
+    pub aggregate: bool,
+    // Put a UDP header after the outer IP header so that NATs and firewalls dropping IP in IP let slots through
+    pub udp: Option<UdpPorts>,
+}
+
+impl SlotFormat {
+    pub fn new(src: impl Into<IpAddr>, dst: impl Into<IpAddr>, cipher: Option<Cipher>, wire_format: WireFormat) -> Self {
+        SlotFormat { src: src.into(), dst: dst.into(), cipher, wire_format, aggregate: false, udp: None }
+    }
+
+    pub fn ip_header_len(&self) -> usize {
+        pattern::get_ip_header_len(self.src)
+    }
+
+    pub fn outer_header_len(&self) -> usize {
+        // Headers in front of the slot that the sizes of the pattern do not include
+        pattern::get_outer_header_len(self.src, self.udp.is_some())
+    }
+
+    pub fn nonce_len(&self) -> usize {
+        if self.cipher.is_some() { crypto::NONCE_LEN } else { 0 }
+    }
+
+    pub fn header_len(&self) -> usize {
+        pattern::get_inner_header_len(self.cipher.is_some(), self.wire_format)
+    }
+
+    pub fn inner_offset(&self) -> usize {
+        self.outer_header_len() + self.nonce_len() + self.header_len()
+    }
+
+    pub fn overhead(&self) -> usize {
+        pattern::get_slot_overhead(self.cipher.is_some(), self.wire_format)
+    }
+}
+
+// How much a queue holds, the limits left out are derived from the rate of the pattern
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub max_packets: Option<usize>,
+    // Of the packets themselves, without their padding
+    pub max_bytes: Option<usize>,
+    // Packets that waited longer are dropped instead of being sent
+    pub max_sojourn: Option<Duration>,
+}
+
+// Packet ready to be sent in a slot, with the length of what it carries and the time it was pushed
+struct Queued {
+    slot: Vec<u8>,
+    len: usize,
+    time: Instant,
+}
+
 pub struct PriorityQueue {
     // Might be more efficient to hard code a queue length in an array
-    pub queue: ArrayQueue<Vec<u8>>,
+    queue: ArrayQueue<Queued>,
     pub length: usize,
-    src: [u8;4],
-    dst: [u8;4],
+    format: SlotFormat,
     chaff: Vec<u8>,
+    // When aggregating, the packet popped that did not fit in the previous slot, it goes first in the next one
+    carry: ArrayQueue<Queued>,
+    // Limits on the packets waiting, the sojourn in nanoseconds, and the bytes they carry
+    max_packets: AtomicUsize,
+    max_bytes: AtomicUsize,
+    max_sojourn: AtomicU64,
+    queued_bytes: AtomicUsize,
+    // Packets pushed and dropped because the queue was full, dropped because they waited too long, bytes of the
+    // pushed packets and of their padding, slots sent with a real packet and with chaff, and packets sent in the slot
+    // of an earlier packet
+    pushed: Counter,
+    dropped: Counter,
+    expired: Counter,
+    bytes: Counter,
+    padding: Counter,
+    real_sent: Counter,
+    chaff_sent: Counter,
+    aggregated: Counter,
+}
+
+// Counters of a queue at one point in time, each read on its own so they may be off by the packets in flight
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub length: usize,
+    pub depth: usize,
+    pub pushed: u64,
+    pub dropped: u64,
+    pub expired: u64,
+    pub bytes: u64,
+    pub padding: u64,
+    pub real_sent: u64,
+    pub chaff_sent: u64,
+    pub aggregated: u64,
 }
 
 impl PriorityQueue {
-    pub fn new(length: usize, src: [u8;4], dst: [u8;4]) -> Self{
-        let chaff = get_chaff(length, src, dst);
-        PriorityQueue{queue: ArrayQueue::new(MAX_Q_LEN), length, src, dst, chaff}
+    pub fn new(length: usize, format: SlotFormat) -> Self{
+        let chaff = get_chaff(length, &format);
+        PriorityQueue{
+            queue: ArrayQueue::new(MAX_Q_LEN),
+            length,
+            format,
+            chaff,
+            carry: ArrayQueue::new(1),
+            // As many packets as fit and no other limit until set_limits is called
+            max_packets: AtomicUsize::new(MAX_Q_LEN),
+            max_bytes: AtomicUsize::new(usize::MAX),
+            max_sojourn: AtomicU64::new(u64::MAX),
+            queued_bytes: AtomicUsize::new(0),
+            pushed: Counter::default(),
+            dropped: Counter::default(),
+            expired: Counter::default(),
+            bytes: Counter::default(),
+            padding: Counter::default(),
+            real_sent: Counter::default(),
+            chaff_sent: Counter::default(),
+            aggregated: Counter::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        // Largest packet that fits in a slot of this queue
+        self.length - self.format.overhead()
+    }
+
+    pub fn set_limits(&self, limits: &QueueLimits, slots_per_sec: f64) {
+        // The queue gets slots_per_sec slots, a packet waits at most DEFAULT_MAX_SOJOURN by default, longer at low
+        // rates, and the queue holds what can be sent in that time
+        let max_sojourn = limits.max_sojourn.unwrap_or_else(|| DEFAULT_MAX_SOJOURN.max(Duration::from_secs_f64(1.0 / slots_per_sec) * MIN_SOJOURN_SLOTS));
+        let slots = ((max_sojourn.as_secs_f64() * slots_per_sec).ceil() as usize).max(MIN_Q_LEN);
+        let per_slot = if self.format.aggregate { (self.capacity() / (shim::PACKED_LEN_LEN + MIN_FRAME_LEN)).max(1) } else { 1 };
+        let max_packets = limits.max_packets.unwrap_or(slots.saturating_mul(per_slot)).min(MAX_Q_LEN);
+        let max_bytes = limits.max_bytes.unwrap_or(slots.saturating_mul(self.capacity()));
+        self.max_packets.store(max_packets, Ordering::Relaxed);
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+        self.max_sojourn.store(max_sojourn.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+
+    pub fn max_packets(&self) -> usize {
+        self.max_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.load(Ordering::Relaxed)
     }
 
-    pub fn push(&self, packet: Vec<u8>) {
-        // Pad when you push to be more efficient when you pop
-        let wrapped_packet = self.wrap_in_ipv4(packet);
-        let padded_data = pad(wrapped_packet, self.length + pattern::IP_HEADER_LEN);
-        if let Err(_) = self.queue.push(padded_data) {
+    pub fn max_sojourn(&self) -> Duration {
+        Duration::from_nanos(self.max_sojourn.load(Ordering::Relaxed))
+    }
+
+    fn has_room(&self, count: usize, bytes: usize) -> bool {
+        // Only the obfuscating thread pushes, the queue can only have more room by the time the packets are pushed
+        self.queue.len() + count <= self.max_packets() && self.queued_bytes.load(Ordering::Relaxed).saturating_add(bytes) <= self.max_bytes()
+    }
+
+    fn pop_fresh(&self) -> Option<Queued> {
+        // Next packet that did not wait longer than max_sojourn, the stale ones in front of it are dropped
+        let max_sojourn = self.max_sojourn();
+        loop {
+            let packet = self.queue.pop()?;
+            self.queued_bytes.fetch_sub(packet.len, Ordering::Relaxed);
+            if packet.time.elapsed() <= max_sojourn {
+                return Some(packet);
+            }
+            self.expired.inc();
+        }
+    }
+
+    pub fn push(&self, packet: Vec<u8>) -> bool {
+        // Pad when you push to be more efficient when you pop, false if the queue is full and the packet dropped
+        let length = packet.len();
+        if !self.has_room(1, length) {
+            self.dropped.inc();
+            return false;
+        }
+        let padded_data = match (&self.format.wire_format, &self.format.cipher) {
+            (WireFormat::Legacy, None) => {
+                let wrapped_packet = self.wrap_in_ip(packet);
+                pad(wrapped_packet, self.slot_len())
+            },
+            _ => self.wrap(packet),
+        };
+        // Counted before the packet can be popped, which takes it off again
+        self.queued_bytes.fetch_add(length, Ordering::Relaxed);
+        if self.queue.push(Queued { slot: padded_data, len: length, time: Instant::now() }).is_err() {
             // println!("Queue {} full, length = {}, error pushing", self.length, self.queue.len());
+            self.queued_bytes.fetch_sub(length, Ordering::Relaxed);
+            self.dropped.inc();
+            return false;
         }
         // println!("Queue length {}", self.queue.len());
+        self.pushed.inc();
+        self.bytes.add(length as u64);
+        self.padding.add((self.length - length) as u64);
+        true
+    }
+
+    pub fn fragment_capacity(&self) -> usize {
+        // Part of a packet carried by each fragment, only the shim header can say a slot is a fragment
+        match self.format.wire_format {
+            WireFormat::Shim => self.capacity().saturating_sub(shim::FRAGMENT_HEADER_LEN),
+            WireFormat::Legacy => 0,
+        }
+    }
+
+    pub fn push_fragments(&self, packet: &[u8], id: u16) -> bool {
+        // Split packet across consecutive slots of this queue, which keeps them in order. Either all the fragments
+        // are pushed or, if they do not fit in what is left of the queue, the packet is dropped
+        let fragment_capacity = self.fragment_capacity();
+        if fragment_capacity == 0 || packet.len() > u16::MAX as usize {
+            self.dropped.inc();
+            return false;
+        }
+        let count = packet.len().div_ceil(fragment_capacity);
+        if !self.has_room(count, packet.len()) {
+            self.dropped.inc();
+            return false;
+        }
+
+        self.queued_bytes.fetch_add(packet.len(), Ordering::Relaxed);
+        let time = Instant::now();
+        for (i, part) in packet.chunks(fragment_capacity).enumerate() {
+            let offset = i * fragment_capacity;
+            let is_last = offset + part.len() == packet.len();
+            // Cannot fail, there was room for all of them
+            let _ = self.queue.push(Queued { slot: self.wrap_fragment(part, id, offset as u16, is_last), len: part.len(), time });
+        }
+        self.pushed.add(count as u64);
+        self.bytes.add(packet.len() as u64);
+        self.padding.add((count * self.length - packet.len()) as u64);
+        true
     }
 
     // pub fn push_no_reorder(&self, packet: Vec<u8>, is_chaff: bool) {
@@ -48,42 +285,150 @@ impl PriorityQueue {
     //     }
     // }
 
-    pub fn pop(&self) -> Vec<u8> {
-       let packet = match self.queue.pop() {
+    pub fn pop(&self, seq: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; self.slot_len()];
+        self.pop_into(seq, &mut packet);
+        packet
+    }
+
+    pub fn pop_into(&self, seq: u32, packet: &mut [u8]) {
+       // Copy straight into the output buffer, chaff is never cloned
+       match self.carry.pop().or_else(|| self.pop_fresh()) {
            Some(pkt) => {
             //println!("Transmit real packet length {} with first byte after addresses {}", pkt.len(), pkt[12]);
             //assert_ne!(pkt[12], 0_u8); // Make sure first byte after addresses is not 0 or else will be seen as chaff
-            pkt.to_vec()
+            self.real_sent.inc();
+            packet.copy_from_slice(&pkt.slot);
+            if self.format.aggregate {
+                self.pack(packet);
+            }
            },
            None => {
-            self.chaff.to_vec()
+            self.chaff_sent.inc();
+            packet.copy_from_slice(&self.chaff)
             //rand::thread_rng().sample_iter(self.distribution).take(self.length).collect()
            }
        };
-       packet
+       // The sequence number follows the order slots are sent in, not the order they were pushed in
+       if self.format.wire_format == WireFormat::Shim {
+           shim::set_seq(&mut packet[self.format.outer_header_len() + self.format.nonce_len()..], seq);
+       }
+       // Encrypt when you pop so chaff gets a fresh nonce every time and cannot be told apart from real packets
+       if let Some(cipher) = &self.format.cipher {
+           cipher.seal(&mut packet[self.format.outer_header_len()..]);
+       }
+       // Last, the checksum covers the sequence number and the ciphertext
+       if self.format.udp.is_some() {
+           set_udp_checksum(packet, &self.format);
+       }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            length: self.length,
+            depth: self.depth(),
+            pushed: self.pushed.get(),
+            dropped: self.dropped.get(),
+            expired: self.expired.get(),
+            bytes: self.bytes.get(),
+            padding: self.padding.get(),
+            real_sent: self.real_sent.get(),
+            chaff_sent: self.chaff_sent.get(),
+            aggregated: self.aggregated.get(),
+        }
     }
 
-    fn wrap_in_ipv4(&self, data: Vec<u8>) -> Vec<u8> {
+    pub fn depth(&self) -> usize {
+        // Packets waiting to be sent
+        self.queue.len() + self.carry.len()
+    }
+
+    pub fn slot_len(&self) -> usize {
+        // Length on the wire, including the outer IP and UDP headers
+        self.length + self.format.outer_header_len()
+    }
+
+    fn wrap(&self, data: Vec<u8>) -> Vec<u8> {
+        // [IP header | nonce | header | inner packet | zero padding | tag], nonce and tag are only there when encrypting
+        // and filled when sealing. The IP header covers the whole slot so the real length is only in the header
+        let mut packet = vec![0u8; self.slot_len()];
+        set_ip_header(&mut packet, &self.format);
+        write_inner_header(&mut packet, &self.format, shim::ShimType::Data, data.len());
+
+        let inner_offset = self.format.inner_offset();
+        packet[inner_offset..inner_offset + data.len()].copy_from_slice(&data);
+        packet
+    }
+
+    fn pack(&self, packet: &mut [u8]) {
+        // Add the next packets of the queue to the slot in packet while they fit, turning it into an aggregate slot
+        // [header | length | packet | length | packet | ... | zero padding]. Fragments are never packed
+        let header_offset = self.format.outer_header_len() + self.format.nonce_len();
+        let inner_offset = self.format.inner_offset();
+        let capacity = self.capacity();
+        let first_len = match shim::ShimHeader::parse(&packet[header_offset..]) {
+            Some(header) if header.packet_type == shim::ShimType::Data => header.length as usize,
+            _ => return,
+        };
+        let mut used = shim::PACKED_LEN_LEN + first_len;
+        let mut count = 0;
+        while used + shim::PACKED_LEN_LEN < capacity {
+            let Some(next) = self.pop_fresh() else {
+                break;
+            };
+            let length = match shim::ShimHeader::parse(&next.slot[header_offset..]) {
+                Some(header) if header.packet_type == shim::ShimType::Data => header.length as usize,
+                _ => usize::MAX,
+            };
+            if length > capacity - used - shim::PACKED_LEN_LEN {
+                // Cannot fail, only this thread pops and the carried packet was popped before
+                let _ = self.carry.push(next);
+                break;
+            }
+
+            if count == 0 {
+                // Make room for the length of the first packet
+                packet.copy_within(inner_offset..inner_offset + first_len, inner_offset + shim::PACKED_LEN_LEN);
+                packet[inner_offset..inner_offset + shim::PACKED_LEN_LEN].copy_from_slice(&(first_len as u16).to_be_bytes());
+            }
+            let offset = inner_offset + used;
+            packet[offset..offset + shim::PACKED_LEN_LEN].copy_from_slice(&(length as u16).to_be_bytes());
+            packet[offset + shim::PACKED_LEN_LEN..offset + shim::PACKED_LEN_LEN + length].copy_from_slice(&next.slot[inner_offset..inner_offset + length]);
+            used += shim::PACKED_LEN_LEN + length;
+            count += 1;
+        }
+
+        if count > 0 {
+            let header = shim::ShimHeader { packet_type: shim::ShimType::Aggregate, flags: 0, length: used as u16, seq: 0 };
+            header.write(&mut packet[header_offset..]);
+            self.aggregated.add(count);
+        }
+    }
+
+    fn wrap_fragment(&self, part: &[u8], id: u16, offset: u16, is_last: bool) -> Vec<u8> {
+        // Same as wrap with the fragment header in front of the part of the packet
+        let mut packet = vec![0u8; self.slot_len()];
+        set_ip_header(&mut packet, &self.format);
+        let flags = if is_last { 0 } else { shim::FLAG_MORE_FRAGMENTS };
+        let header = shim::ShimHeader { packet_type: shim::ShimType::Fragment, flags, length: (shim::FRAGMENT_HEADER_LEN + part.len()) as u16, seq: 0 };
+        header.write(&mut packet[self.format.outer_header_len() + self.format.nonce_len()..]);
+
+        let inner_offset = self.format.inner_offset();
+        shim::FragmentHeader { id, offset }.write(&mut packet[inner_offset..]);
+        packet[inner_offset + shim::FRAGMENT_HEADER_LEN..inner_offset + shim::FRAGMENT_HEADER_LEN + part.len()].copy_from_slice(part);
+        packet
+    }
+
+    fn wrap_in_ip(&self, data: Vec<u8>) -> Vec<u8> {
+        // The length in the IP header is that of the inner packet, it is set before padding
         let initial_len = data.len();
+        let outer_header_len = self.format.outer_header_len();
         let mut data = data;
         
-        data.resize(initial_len + pattern::IP_HEADER_LEN, 0);
-        data.rotate_right(pattern::IP_HEADER_LEN);
-        let mut packet = ipv4::MutableIpv4Packet::new(&mut data).unwrap();
-    
-        // Set the IP header fields
-        packet.set_version(pattern::IP_VERSION);
-        packet.set_header_length((pattern::IP_HEADER_LEN/4) as u8);
-        packet.set_total_length(((initial_len + pattern::IP_HEADER_LEN)) as u16); // Set the total length of the packet
-        //packet.set_identification(1234);
-        packet.set_ttl(64);
-        packet.set_next_level_protocol(IpNextHeaderProtocols::IpIp); 
-        packet.set_source(self.src.into());
-        packet.set_destination(self.dst.into());
-    
-        packet.set_checksum(pnet::packet::ipv4::checksum(&packet.to_immutable()));
-        
-        packet.packet().to_vec()
+        data.resize(initial_len + outer_header_len, 0);
+        data.rotate_right(outer_header_len);
+        set_ip_header(&mut data, &self.format);
+        data
     }
 }
 
@@ -96,26 +441,101 @@ fn pad(data: Vec<u8>, target_length: usize) -> Vec<u8> {
     padded_data
 }
 
-fn get_chaff(length: usize, src_addr: [u8;4], dst_addr: [u8;4]) -> Vec<u8> {
+fn get_chaff(length: usize, format: &SlotFormat) -> Vec<u8> {
     let mut data = pattern::CHAFF.to_vec();
     
-    data.resize(length + pattern::IP_HEADER_LEN, 0);
-    data.rotate_right(pattern::IP_HEADER_LEN);
-    let mut packet = ipv4::MutableIpv4Packet::new(&mut data).unwrap();
+    data.resize(length + format.outer_header_len(), 0);
+    data.rotate_right(format.outer_header_len());
+    set_ip_header(&mut data, format);
+    // Legacy chaff is all zeros, otherwise the header says it is chaff with an inner length of 0
+    write_inner_header(&mut data, format, shim::ShimType::Chaff, 0);
+    data
+}
+
+fn write_inner_header(packet: &mut [u8], format: &SlotFormat, packet_type: shim::ShimType, length: usize) {
+    let offset = format.outer_header_len() + format.nonce_len();
+    match format.wire_format {
+        WireFormat::Shim => {
+            let header = shim::ShimHeader { packet_type, flags: 0, length: length as u16, seq: 0 };
+            header.write(&mut packet[offset..]);
+        },
+        WireFormat::Legacy if format.cipher.is_some() => {
+            packet[offset..offset + pattern::INNER_LEN_LEN].copy_from_slice(&(length as u16).to_be_bytes());
+        },
+        WireFormat::Legacy => (),
+    }
+}
+
+fn set_ip_header(data: &mut [u8], format: &SlotFormat) {
+    let protocol = match format.udp {
+        Some(ports) => {
+            set_udp_header(&mut data[format.ip_header_len()..], ports);
+            IpNextHeaderProtocols::Udp
+        },
+        None if format.src.is_ipv4() => IpNextHeaderProtocols::IpIp,
+        None => IpNextHeaderProtocols::Ipv6,
+    };
+    match (format.src, format.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => set_ipv4_header(data, src, dst, protocol),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => set_ipv6_header(data, src, dst, protocol),
+        // Rejected when the config is validated
+        _ => panic!("Source {} and destination {} are not of the same IP version", format.src, format.dst),
+    }
+}
+
+fn set_ipv4_header(data: &mut [u8], src_addr: Ipv4Addr, dst_addr: Ipv4Addr, protocol: IpNextHeaderProtocol) {
+    // The total length is the length of data, set the IP header fields in its first IP_HEADER_LEN bytes
+    let total_length = data.len();
+    let mut packet = ipv4::MutableIpv4Packet::new(data).unwrap();
 
-    // Set the IP header fields
     packet.set_version(pattern::IP_VERSION);
     packet.set_header_length((pattern::IP_HEADER_LEN/4) as u8);
-    packet.set_total_length(((length + pattern::IP_HEADER_LEN)) as u16); // Set the total length of the packet
+    packet.set_total_length(total_length as u16); // Set the total length of the packet
     //packet.set_identification(1234);
     packet.set_ttl(64);
-    packet.set_next_level_protocol(IpNextHeaderProtocols::IpIp); 
-    packet.set_source(src_addr.into());
-    packet.set_destination(dst_addr.into());
+    packet.set_next_level_protocol(protocol); 
+    packet.set_source(src_addr);
+    packet.set_destination(dst_addr);
 
     packet.set_checksum(pnet::packet::ipv4::checksum(&packet.to_immutable()));
-    
-    packet.packet().to_vec()
 }
 
+fn set_ipv6_header(data: &mut [u8], src_addr: Ipv6Addr, dst_addr: Ipv6Addr, protocol: IpNextHeaderProtocol) {
+    // Same as set_ipv4_header, the payload length leaves out the IPV6_HEADER_LEN bytes of the header itself
+    let payload_length = data.len() - pattern::IPV6_HEADER_LEN;
+    let mut packet = ipv6::MutableIpv6Packet::new(data).unwrap();
 
+    packet.set_version(pattern::IPV6_VERSION);
+    packet.set_payload_length(payload_length as u16);
+    packet.set_next_header(protocol);
+    packet.set_hop_limit(64);
+    packet.set_source(src_addr);
+    packet.set_destination(dst_addr);
+}
+
+fn set_udp_header(data: &mut [u8], ports: UdpPorts) {
+    // data starts after the IP header, its length is that of the UDP datagram. The checksum is set once the slot is sealed
+    let length = data.len();
+    let mut packet = udp::MutableUdpPacket::new(data).unwrap();
+
+    packet.set_source(ports.src);
+    packet.set_destination(ports.dst);
+    packet.set_length(length as u16);
+    packet.set_checksum(0);
+}
+
+fn set_udp_checksum(data: &mut [u8], format: &SlotFormat) {
+    // Over the pseudo header and the datagram, which can be shorter than the slot for plain legacy slots
+    let ip_header_len = format.ip_header_len();
+    let length = u16::from_be_bytes([data[ip_header_len + 4], data[ip_header_len + 5]]) as usize;
+    let datagram = &mut data[ip_header_len..ip_header_len + length];
+    let mut packet = udp::MutableUdpPacket::new(datagram).unwrap();
+    packet.set_checksum(0);
+    let checksum = match (format.src, format.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => udp::ipv4_checksum(&packet.to_immutable(), &src, &dst),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => udp::ipv6_checksum(&packet.to_immutable(), &src, &dst),
+        _ => panic!("Source {} and destination {} are not of the same IP version", format.src, format.dst),
+    };
+    // 0 means no checksum, one that comes out as 0 is sent as all ones
+    packet.set_checksum(if checksum == 0 { 0xffff } else { checksum });
+}
@@ -1,52 +1,76 @@
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 use crate::queues::priority_queue;
+use crate::metrics::Counter;
 use crate::pattern;
 
-pub static TOTAL_PAD: Mutex<f64> = Mutex::new(0.0);
-
 pub struct RoundRobinScheduler {
     // Change this to a hashmap of (length, Vec<queue>)
     // Find queue to push with hashmap key. If many queues of that length
     // then either keep track of last one pushed to, check their lengths or do a hash to decide which one
     pub queues: Vec<priority_queue::PriorityQueue>,
-    pub pps: f64
+    // f64 bits, the rate can be changed while the pattern is sent
+    pps: AtomicU64,
+    // f64 bits, time from each slot to the next in intervals, 1 for all of them unless gaps are set
+    gaps: Vec<AtomicU64>,
+    // Limits of the queues as configured, those left out follow the rate
+    limits: Mutex<priority_queue::QueueLimits>,
+    // Queue indices from smallest to largest size, the pattern itself can be in any order
+    sorted_indices: Vec<usize>,
+    // Sequence number of the next slot sent, and id of the next packet split in fragments
+    seq: AtomicU32,
+    fragment_id: AtomicU16,
+    // Packets split in fragments and dropped because they fit in no queue even split, the other counters are kept
+    // by each queue
+    fragmented: Counter,
+    oversize: Counter,
 }
 
 impl RoundRobinScheduler {
-    pub fn new(num_queues: usize, pps: f64, src: [u8;4], dst: [u8;4]) -> RoundRobinScheduler {
-        let mut queues = Vec::with_capacity(num_queues);
-        for i in 0..num_queues {
-            queues.push(priority_queue::PriorityQueue::new(pattern::PATTERN[i], src, dst));
+    pub fn new(pattern: &[usize], pps: f64, format: priority_queue::SlotFormat) -> RoundRobinScheduler {
+        let mut queues = Vec::with_capacity(pattern.len());
+        for &length in pattern {
+            queues.push(priority_queue::PriorityQueue::new(length, format.clone()));
         }
-        RoundRobinScheduler {
+        let rrs = RoundRobinScheduler {
             queues,
-            pps: pps,
-        }
+            pps: AtomicU64::new(pps.to_bits()),
+            gaps: pattern.iter().map(|_| AtomicU64::new(1f64.to_bits())).collect(),
+            limits: Mutex::new(priority_queue::QueueLimits::default()),
+            sorted_indices: pattern::get_sorted_indices(pattern),
+            seq: AtomicU32::new(0),
+            fragment_id: AtomicU16::new(0),
+            fragmented: Counter::default(),
+            oversize: Counter::default(),
+        };
+        rrs.apply_limits();
+        rrs
     }
 
-    pub fn push(&self, packet: Vec<u8>, last_queues: &Vec<(usize,usize)>) -> usize {
-        let mut is_pushed = false;
-        let mut current_q = self.queues.len(); // Return this if unable to push
+    pub fn push(&self, packet: Vec<u8>, last_queues: &[(usize,usize)]) -> usize {
+        // Returns the position of the size class pushed to in the sorted pattern, see pattern::get_push_state_vector,
+        // or the length of the pattern if unable to push
         let length = packet.len();
-        for i in 0..self.queues.len() {
-            // Look if fits in pattern from smallest to largest element
-            if packet.len() <= pattern::PATTERN[i] { // Assumes pattern is in ascending order!!
-                let idx = last_queues[i].0;
-                self.queues[idx].push(packet); 
-                current_q = i;
-                
-                // println!("Pushed to queue {}, length = {}", idx, length);
-                is_pushed = true;
-                // Keep track of total padding]
-                let mut data = TOTAL_PAD.lock().unwrap();
-                *data += (self.queues[i].length - length) as f64 / self.pps;
-                break;
-            }
+        // Look if fits in pattern from smallest to largest element
+        if let Some(i) = self.sorted_indices.iter().position(|&q| length <= self.queues[q].capacity()) {
+            let idx = self.sorted_indices[last_queues[i].0];
+            // Counted by the queue, padding included, or dropped if it is full
+            self.queues[idx].push(packet);
+            // println!("Pushed to queue {}, length = {}", idx, length);
+            return i;
         }
-        if !is_pushed {
-            //println!("Could not push packet of length {}", length);
+
+        // Too large for every queue, split it in the next queue of the largest size. Like above, i is the first
+        // position of that size in the sorted pattern
+        let largest = self.queues[self.sorted_indices[self.queues.len() - 1]].length;
+        let i = self.sorted_indices.iter().position(|&q| self.queues[q].length == largest).unwrap_or(0);
+        let idx = self.sorted_indices[last_queues[i].0];
+        if self.push_fragments(idx, &packet) {
+            i
+        } else {
+            self.queues.len()
         }
-        current_q
     }
 
     pub fn push_no_reorder(&self, packet: Vec<u8>, idx: usize) -> usize {
@@ -55,19 +79,166 @@ impl RoundRobinScheduler {
         let mut current_q = idx;
         for i in 0..self.queues.len() {
             current_q = (idx+i) % self.queues.len();
-            if pkt_len <= self.queues[current_q].length {
+            if pkt_len <= self.queues[current_q].capacity() {
                 self.queues[current_q].push(packet);
-                break;
+                return (current_q+1) % self.queues.len();
             }
             // else {
             //     self.queues[current_q].push_no_reorder(pattern::CHAFF[0..self.queues[current_q].length].to_vec(), true);
             // }
         }
+        // Too large for every queue, split it in the first queue of the largest size
+        let largest = self.sorted_indices[self.queues.len() - 1];
+        self.push_fragments(largest, &packet);
         (current_q+1) % self.queues.len()
     }
 
+    fn push_fragments(&self, idx: usize, packet: &[u8]) -> bool {
+        // All the fragments go to the same queue so they are sent in order. False if the wire format cannot carry
+        // fragments, a full queue counts the packet as dropped like any other push
+        if self.queues[idx].fragment_capacity() == 0 {
+            self.oversize.inc();
+            return false;
+        }
+        let id = self.fragment_id.fetch_add(1, Ordering::Relaxed);
+        if self.queues[idx].push_fragments(packet, id) {
+            self.fragmented.inc();
+        }
+        true
+    }
+
+    pub fn resume_seq(&self, previous: &RoundRobinScheduler) {
+        // Continue the sequence numbers of the scheduler this one replaces, the peer does not see a gap
+        self.seq.store(previous.seq.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.fragment_id.store(previous.fragment_id.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
     pub fn pop(&self, idx: usize) -> Vec<u8> {
         // Pop from the current queue
-        self.queues[idx].pop()
+        self.queues[idx].pop(self.seq.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn pop_into(&self, idx: usize, buffer: &mut [u8]) {
+        // Same as pop but writes the slot to buffer, which must be slot_len(idx) bytes
+        self.queues[idx].pop_into(self.seq.fetch_add(1, Ordering::Relaxed), buffer)
+    }
+
+    pub fn slot_len(&self, idx: usize) -> usize {
+        self.queues[idx].slot_len()
     }
-}
\ No newline at end of file
+
+    pub fn pattern(&self) -> Vec<usize> {
+        self.queues.iter().map(|q| q.length).collect()
+    }
+
+    pub fn pps(&self) -> f64 {
+        f64::from_bits(self.pps.load(Ordering::Relaxed))
+    }
+
+    pub fn set_pps(&self, pps: f64) {
+        self.pps.store(pps.to_bits(), Ordering::Relaxed);
+        self.apply_limits();
+    }
+
+    pub fn limits(&self) -> priority_queue::QueueLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: priority_queue::QueueLimits) {
+        *self.limits.lock().unwrap() = limits;
+        self.apply_limits();
+    }
+
+    fn apply_limits(&self) {
+        // Every queue gets one slot per round of the pattern
+        let limits = self.limits.lock().unwrap();
+        let slots_per_sec = self.pps() / self.queues.len() as f64;
+        for q in &self.queues {
+            q.set_limits(&limits, slots_per_sec);
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        // Average time between two slots
+        Duration::from_secs_f64(1.0 / self.pps())
+    }
+
+    pub fn set_gaps(&self, gaps: &[f64]) -> Result<(), String> {
+        // Relative time from each slot to the next, scaled so that they average to one interval and the rate holds
+        pattern::validate_gaps(gaps, self.queues.len())?;
+        let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        for (gap, &value) in self.gaps.iter().zip(gaps) {
+            gap.store((value / mean).to_bits(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn gaps(&self) -> Vec<f64> {
+        self.gaps.iter().map(|gap| f64::from_bits(gap.load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn gap(&self, idx: usize) -> Duration {
+        // Time from the slot of queue idx to the next one
+        self.interval().mul_f64(f64::from_bits(self.gaps[idx].load(Ordering::Relaxed)))
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            queues: self.queues.iter().map(|q| q.stats()).collect(),
+            fragmented: self.fragmented.get(),
+            oversize: self.oversize.get(),
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        // Real packets waiting in all the queues
+        self.queues.iter().map(|q| q.depth()).sum()
+    }
+
+    pub fn backlog(&self) -> Duration {
+        // Time to send the packets waiting in the fullest queue, which gets one slot per round of the pattern. Less if
+        // several of them fit in one slot
+        let depth = self.queues.iter().map(|q| q.depth()).max().unwrap_or(0);
+        self.interval().mul_f64((depth * self.queues.len()) as f64)
+    }
+}
+
+// Counters of all the queues of a scheduler at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub queues: Vec<priority_queue::QueueStats>,
+    pub fragmented: u64,
+    pub oversize: u64,
+}
+
+impl SchedulerStats {
+    pub fn pushed(&self) -> u64 {
+        self.queues.iter().map(|q| q.pushed).sum()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        // Too large for every queue or their queue was full
+        self.oversize + self.queues.iter().map(|q| q.dropped).sum::<u64>()
+    }
+
+    pub fn expired(&self) -> u64 {
+        // Waited in their queue for longer than it allows
+        self.queues.iter().map(|q| q.expired).sum()
+    }
+
+    pub fn padding(&self) -> u64 {
+        self.queues.iter().map(|q| q.padding).sum()
+    }
+
+    pub fn avg_pad(&self) -> f64 {
+        // Bytes of padding per pushed packet
+        match self.pushed() {
+            0 => 0.0,
+            pushed => self.padding() as f64 / pushed as f64,
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queues.iter().map(|q| q.depth).sum()
+    }
+}
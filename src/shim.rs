@@ -0,0 +1,102 @@
+use serde::Deserialize;
+
+// Header in front of the inner packet, after the outer IP header (and the nonce when encrypted)
+//  0               1               2               3
+// | version | type |     flags     |         inner length          |
+// |                        sequence number                        |
+pub const SHIM_VERSION: u8 = 1;
+pub const SHIM_HEADER_LEN: usize = 8;
+const SEQ_OFFSET: usize = 4;
+
+// Fragments of a packet too large for any slot have a second header, counted in the inner length, before their part
+//  0               1               2               3
+// |          fragment id          |            offset             |
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+// Set on every fragment but the last one of a packet
+pub const FLAG_MORE_FRAGMENTS: u8 = 0x01;
+
+// Aggregate slots carry several packets, each after its length on 2 bytes, the inner length covers all of them
+pub const PACKED_LEN_LEN: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    // Explicit shim header with type, sequence number and inner length
+    Shim,
+    // Format before the shim header: chaff is recognized by zero bytes and the inner length read from the outer IP header
+    Legacy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShimType {
+    Chaff = 0,
+    Data = 1,
+    Fragment = 2,
+    Aggregate = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShimHeader {
+    pub packet_type: ShimType,
+    pub flags: u8,
+    pub length: u16,
+    pub seq: u32,
+}
+
+impl ShimHeader {
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0] = SHIM_VERSION << 4 | self.packet_type as u8;
+        buffer[1] = self.flags;
+        buffer[2..4].copy_from_slice(&self.length.to_be_bytes());
+        set_seq(buffer, self.seq);
+    }
+
+    pub fn parse(buffer: &[u8]) -> Option<ShimHeader> {
+        // Returns None for unknown versions and types so they are dropped like invalid packets
+        if buffer.len() < SHIM_HEADER_LEN || buffer[0] >> 4 != SHIM_VERSION {
+            return None;
+        }
+        let packet_type = match buffer[0] & 0x0f {
+            0 => ShimType::Chaff,
+            1 => ShimType::Data,
+            2 => ShimType::Fragment,
+            3 => ShimType::Aggregate,
+            _ => return None,
+        };
+        Some(ShimHeader {
+            packet_type,
+            flags: buffer[1],
+            length: u16::from_be_bytes([buffer[2], buffer[3]]),
+            seq: u32::from_be_bytes([buffer[SEQ_OFFSET], buffer[SEQ_OFFSET + 1], buffer[SEQ_OFFSET + 2], buffer[SEQ_OFFSET + 3]]),
+        })
+    }
+}
+
+pub fn set_seq(buffer: &mut [u8], seq: u32) {
+    // Sequence numbers are only known when the slot is sent, the rest of the header is written when pushing
+    buffer[SEQ_OFFSET..SEQ_OFFSET + 4].copy_from_slice(&seq.to_be_bytes());
+}
+
+// Where the part carried by a fragment goes in the packet, fragments of the same packet have the same id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub id: u16,
+    pub offset: u16,
+}
+
+impl FragmentHeader {
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0..2].copy_from_slice(&self.id.to_be_bytes());
+        buffer[2..4].copy_from_slice(&self.offset.to_be_bytes());
+    }
+
+    pub fn parse(buffer: &[u8]) -> Option<FragmentHeader> {
+        if buffer.len() < FRAGMENT_HEADER_LEN {
+            return None;
+        }
+        Some(FragmentHeader {
+            id: u16::from_be_bytes([buffer[0], buffer[1]]),
+            offset: u16::from_be_bytes([buffer[2], buffer[3]]),
+        })
+    }
+}
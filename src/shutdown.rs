@@ -0,0 +1,59 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+// Shared by the pipeline threads, they stop once a shutdown is requested
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn on_signals() -> io::Result<Shutdown> {
+        // SIGINT and SIGTERM are blocked in this thread and in the threads it spawns afterwards, a dedicated thread
+        // waits for them instead so that nothing has to happen in a signal handler
+        let shutdown = Shutdown::new();
+        let signals = unsafe {
+            let mut signals: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            signals
+        };
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+
+        let handle = shutdown.clone();
+        thread::Builder::new().name("signals".to_string()).spawn(move || {
+            let mut signal = 0;
+            loop {
+                if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                    continue;
+                }
+                if handle.is_requested() {
+                    // Signaled again while draining, do not wait for the queues
+                    eprintln!("Received signal {} again, exiting now", signal);
+                    std::process::exit(128 + signal);
+                }
+                println!("Received signal {}, shutting down...", signal);
+                handle.request();
+            }
+        })?;
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+}
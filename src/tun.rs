@@ -0,0 +1,185 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::util::MacAddr;
+use serde::Deserialize;
+use crate::packet_io;
+use crate::pattern;
+use crate::ChannelCustom;
+
+const TUN_PATH: &str = "/dev/net/tun";
+// _IOW('T', 202, int), not exported by libc
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+// Locally administered address used as src and dst of the Ethernet headers added in front of TUN packets
+const TUN_MAC: MacAddr = MacAddr(0x02, 0xd1, 0x77, 0x00, 0x00, 0x01);
+
+// Kind of device on the unobfuscated side
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    // Existing interface opened with a pnet datalink channel
+    #[default]
+    Interface,
+    // L3 device created by budget_ditto, the OS routes IP packets into it
+    Tun,
+    // L2 device created by budget_ditto, the OS sends Ethernet frames into it
+    Tap,
+}
+
+// TUN/TAP device created for the lifetime of the process, deleted by the kernel when the last channel is dropped
+pub struct TunDevice {
+    file: File,
+    device_type: DeviceType,
+    mac_addr: MacAddr,
+    // Largest frame read or written, the MTU with the Ethernet header and up to two VLAN tags on a TAP device
+    frame_len: usize,
+}
+
+impl TunDevice {
+    pub fn create(name: &str, device_type: DeviceType, mtu: usize) -> io::Result<TunDevice> {
+        let flags = match device_type {
+            DeviceType::Tun => libc::IFF_TUN,
+            DeviceType::Tap => libc::IFF_TAP,
+            DeviceType::Interface => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a TUN/TAP device")),
+        };
+        let file = OpenOptions::new().read(true).write(true).open(TUN_PATH)?;
+
+        // No packet information in front of the frames, they are the same as on a datalink channel
+        let mut request = get_ifreq(name)?;
+        request.ifr_ifru.ifru_flags = (flags | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(file.as_raw_fd(), TUNSETIFF, &mut request)?;
+
+        // Configuration of the interface itself goes through any socket
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        // Larger packets would not fit in the largest slot
+        let mut request = get_ifreq(name)?;
+        request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+        ioctl(socket.as_raw_fd(), libc::SIOCSIFMTU, &mut request)?;
+
+        let mut request = get_ifreq(name)?;
+        ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request)?;
+        unsafe { request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+        ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &mut request)?;
+
+        let mac_addr = match device_type {
+            DeviceType::Tap => {
+                let mut request = get_ifreq(name)?;
+                ioctl(socket.as_raw_fd(), libc::SIOCGIFHWADDR, &mut request)?;
+                let data = unsafe { request.ifr_ifru.ifru_hwaddr.sa_data };
+                MacAddr(data[0] as u8, data[1] as u8, data[2] as u8, data[3] as u8, data[4] as u8, data[5] as u8)
+            },
+            _ => TUN_MAC,
+        };
+
+        let frame_len = mtu + pattern::ETH_HEADER_LEN + 2 * pattern::VLAN_TAG_LEN;
+        Ok(TunDevice { file, device_type, mac_addr, frame_len })
+    }
+
+    pub fn channel(&self) -> io::Result<ChannelCustom> {
+        // All channels share the same queue of the device, one can be used to receive while another sends
+        let tx = TunSender { file: self.file.try_clone()?, device_type: self.device_type, mac_addr: self.mac_addr, buffer: Vec::with_capacity(self.frame_len) };
+        let rx = TunReceiver { file: self.file.try_clone()?, device_type: self.device_type, mac_addr: self.mac_addr, buffer: vec![0u8; self.frame_len] };
+        Ok(ChannelCustom { tx: Box::new(tx), rx: Box::new(rx), mac_addr: Some(self.mac_addr) })
+    }
+}
+
+struct TunSender {
+    file: File,
+    device_type: DeviceType,
+    mac_addr: MacAddr,
+    buffer: Vec<u8>,
+}
+
+struct TunReceiver {
+    file: File,
+    device_type: DeviceType,
+    mac_addr: MacAddr,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkSender for TunSender {
+    fn build_and_send(&mut self, num_packets: usize, packet_size: usize, func: &mut dyn FnMut(&mut [u8])) -> Option<io::Result<()>> {
+        let mut packet = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut packet);
+            if let Some(Err(e)) = self.send_to(&packet, None) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        if packet.len() < pattern::ETH_HEADER_LEN {
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame shorter than an Ethernet header")));
+        }
+        let result = match self.device_type {
+            DeviceType::Tun => {
+                // Deobfuscated traffic is always made of Ethernet frames, a TUN device only takes the IP packet inside,
+                // VLAN tags are dropped with the Ethernet header
+                match pattern::get_ethertype(packet) {
+                    Some((pattern::ETHERTYPE_IPV4 | pattern::ETHERTYPE_IPV6, offset)) => self.file.write(&packet[offset..]),
+                    _ => return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, "TUN devices only carry IP packets"))),
+                }
+            },
+            _ => {
+                // The frame was addressed on the peer's network, deliver unicast frames to this host instead
+                self.buffer.clear();
+                self.buffer.extend_from_slice(packet);
+                if self.buffer[0] & 1 == 0 {
+                    self.buffer[..pattern::MAC_ADDR_LEN].copy_from_slice(&self.mac_addr.octets());
+                }
+                self.file.write(&self.buffer)
+            },
+        };
+        Some(result.map(|_| ()))
+    }
+}
+
+impl DataLinkReceiver for TunReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        packet_io::poll(self.file.as_raw_fd(), libc::POLLIN, Some(packet_io::RECV_TIMEOUT))?;
+        match self.device_type {
+            DeviceType::Tun => {
+                // Add an Ethernet header so the peer gets the same frames whatever the device on this side
+                let length = self.file.read(&mut self.buffer[pattern::ETH_HEADER_LEN..])?;
+                let ethertype = match self.buffer.get(pattern::ETH_HEADER_LEN).map(|b| b >> 4) {
+                    Some(6) => pattern::ETHERTYPE_IPV6,
+                    _ => pattern::ETHERTYPE_IPV4,
+                };
+                self.buffer[..pattern::MAC_ADDR_LEN].copy_from_slice(&self.mac_addr.octets());
+                self.buffer[pattern::MAC_ADDR_LEN..2 * pattern::MAC_ADDR_LEN].copy_from_slice(&self.mac_addr.octets());
+                self.buffer[2 * pattern::MAC_ADDR_LEN..pattern::ETH_HEADER_LEN].copy_from_slice(&ethertype.to_be_bytes());
+                Ok(&self.buffer[..pattern::ETH_HEADER_LEN + length])
+            },
+            _ => {
+                let length = self.file.read(&mut self.buffer)?;
+                Ok(&self.buffer[..length])
+            },
+        }
+    }
+}
+
+fn get_ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Device name must be 1 to {} characters", libc::IFNAMSIZ - 1)));
+    }
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(request)
+}
+
+fn ioctl(fd: libc::c_int, request: libc::c_ulong, ifreq: &mut libc::ifreq) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, request as _, ifreq as *mut libc::ifreq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+use budget_ditto::adaptive::RateController;
+use budget_ditto::config::AdaptiveConfig;
+use budget_ditto::control::Control;
+use budget_ditto::pattern;
+use budget_ditto::queues::priority_queue::SlotFormat;
+use budget_ditto::shim::WireFormat;
+
+const SECOND: Duration = Duration::from_secs(1);
+const HIGH: Duration = Duration::from_millis(100);
+const IDLE: Duration = Duration::ZERO;
+
+fn get_config() -> AdaptiveConfig {
+    AdaptiveConfig {
+        levels: vec![10.0, 20.0, 40.0],
+        min_dwell: 10.0,
+        max_switches: 3,
+        switch_window: 100.0,
+        ..AdaptiveConfig::default()
+    }
+}
+
+#[test]
+fn switches_follow_the_backlog() {
+    let start = Instant::now();
+    assert!(RateController::new(&get_config(), 15.0, start).is_none());
+    let mut controller = RateController::new(&get_config(), 20.0, start).unwrap();
+
+    // Not before min_dwell, then one level at a time
+    assert_eq!(controller.update(HIGH, start + SECOND), None);
+    let switch = controller.update(HIGH, start + 10 * SECOND).unwrap();
+    assert_eq!((switch.from, switch.to), (20.0, 40.0));
+    assert!(switch.reason.contains("above"), "{}", switch.reason);
+    // Already at the top
+    assert_eq!(controller.update(HIGH, start + 20 * SECOND), None);
+
+    // Down only once the queues stayed almost empty for min_dwell
+    assert_eq!(controller.update(IDLE, start + 25 * SECOND), None);
+    assert_eq!(controller.update(HIGH / 10, start + 26 * SECOND), None);
+    assert_eq!(controller.update(IDLE, start + 35 * SECOND), None);
+    let switch = controller.update(IDLE, start + 36 * SECOND).unwrap();
+    assert_eq!((switch.from, switch.to), (40.0, 20.0));
+    assert!(switch.reason.contains("below"), "{}", switch.reason);
+    assert_eq!(controller.rate(), 20.0);
+}
+
+#[test]
+fn switches_are_rate_limited() {
+    let start = Instant::now();
+    let mut controller = RateController::new(&get_config(), 10.0, start).unwrap();
+    let mut switches = Vec::new();
+    // The backlog swings as fast as it can, only max_switches of them get through in a switch_window
+    for i in 1..=150 {
+        let backlog = if (i / 10) % 2 == 0 { HIGH } else { IDLE };
+        if controller.update(backlog, start + i * SECOND).is_some() {
+            switches.push(i);
+        }
+    }
+    assert!(switches.len() >= 4, "{:?}", switches);
+    assert!(switches.windows(4).all(|window| window[3] - window[0] >= 100), "{:?}", switches);
+    assert!(switches.windows(2).all(|pair| pair[1] - pair[0] >= 10), "{:?}", switches);
+
+    // 3 switches, each up or down on one of 100 checks
+    assert!((controller.leakage_bits() - 3.0 * (1.0 + 100f64.log2())).abs() < 1e-9);
+}
+
+#[test]
+fn backlog_of_the_fullest_queue() {
+    let control = Control::new(&[200, 1400], 10.0, SlotFormat::new([10, 9, 0, 1], [10, 9, 0, 2], None, WireFormat::Shim));
+    let rrs = control.scheduler();
+    assert_eq!(rrs.backlog(), Duration::ZERO);
+    let psv = pattern::get_push_state_vector(&rrs.pattern());
+    for _ in 0..3 {
+        rrs.push(vec![1; 1000], &psv);
+    }
+    rrs.push(vec![1; 100], &psv);
+    // 3 rounds of the pattern for the large queue
+    assert_eq!(rrs.backlog(), rrs.interval() * 6);
+}
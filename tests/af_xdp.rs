@@ -0,0 +1,66 @@
+mod common;
+
+use budget_ditto::packet_io::af_xdp::XdpFilter;
+use budget_ditto::pattern;
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use common::*;
+
+fn get_slot(format: SlotFormat, is_real: bool) -> Vec<u8> {
+    // As sent on the wire by peer A, written in place like the send thread does
+    let queue = PriorityQueue::new(200, format);
+    if is_real {
+        assert!(queue.push(get_frame(100, 1)));
+    }
+    let mut slot = vec![0u8; queue.slot_len()];
+    queue.pop_into(0, &mut slot);
+    slot
+}
+
+fn get_filter(format: &SlotFormat) -> XdpFilter {
+    // Of peer B, as open_io sets it up for the slots of peer A
+    XdpFilter { src: format.src, udp_port: format.udp.map(|ports| ports.dst) }
+}
+
+#[test]
+fn slots_of_the_peer_match() {
+    let formats = [
+        SlotFormat::new(IP_A, IP_B, None, WireFormat::Legacy),
+        SlotFormat::new(IP6_A, IP6_B, None, WireFormat::Shim),
+        get_udp_format(IP_A, IP_B, None, WireFormat::Shim),
+        get_udp_format(IP6_A, IP6_B, None, WireFormat::Legacy),
+    ];
+    for format in formats {
+        let filter = get_filter(&format);
+        for is_real in [true, false] {
+            assert!(filter.matches(&get_slot(format.clone(), is_real)), "{:?} {}", filter, is_real);
+        }
+    }
+}
+
+#[test]
+fn other_packets_do_not_match() {
+    let format = get_udp_format(IP_A, IP_B, None, WireFormat::Shim);
+    let slot = get_slot(format.clone(), true);
+    let filter = get_filter(&format);
+
+    // From another address, to another port or carried by IP directly
+    assert!(!XdpFilter { src: IP_C.into(), ..filter }.matches(&slot));
+    assert!(!XdpFilter { udp_port: Some(PORTS.src), ..filter }.matches(&slot));
+    assert!(!XdpFilter { udp_port: None, ..filter }.matches(&slot));
+    assert!(!get_filter(&SlotFormat::new(IP_A, IP_B, None, WireFormat::Shim)).matches(&slot));
+    // Of the other IP version, with the same address bytes where IPv4 has its source
+    let mut ipv6_src = [0u8; 16];
+    ipv6_src[12..].copy_from_slice(&IP_A);
+    assert!(!XdpFilter { src: ipv6_src.into(), ..filter }.matches(&slot));
+    let mut slot_v6 = slot.clone();
+    slot_v6[0] = 0x60 | slot[0] & 0x0f;
+    assert!(!filter.matches(&slot_v6));
+    // Cut before the port, or a frame that starts with an Ethernet header
+    let port_end = pattern::IP_HEADER_LEN + pattern::UDP_DST_PORT_OFFSET + 2;
+    assert!(!filter.matches(&slot[..port_end - 1]));
+    assert!(filter.matches(&slot[..port_end]));
+    let mut frame = get_frame(14, 0);
+    frame.extend_from_slice(&slot);
+    assert!(!filter.matches(&frame));
+}
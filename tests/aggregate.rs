@@ -0,0 +1,58 @@
+mod common;
+
+use budget_ditto::crypto::Cipher;
+use budget_ditto::deobfuscate::Deobfuscator;
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+
+fn check_aggregated_round_trip(cipher: Option<Cipher>) {
+    let (mut host_a, input_a) = memory::link();
+    let mut format = get_format(cipher, WireFormat::Shim);
+    format.aggregate = true;
+    let mut host_b = start_peers(input_a, &PATTERN, format, RATE, &Shutdown::new(), TIMEOUT).0;
+
+    // Fragments in between are sent in slots of their own
+    let frames: Vec<Vec<u8>> = (0..30).map(|i| get_frame([60, 100, 1000, 3000, 60][i % 5], i as u8 + 1)).collect();
+    for frame in &frames {
+        host_a.send(frame).unwrap();
+    }
+
+    let mut received = receive_all(&mut host_b, frames.len());
+    let mut expected = frames.clone();
+    received.sort();
+    expected.sort();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn aggregated_round_trip() {
+    check_aggregated_round_trip(None);
+}
+
+#[test]
+fn aggregated_round_trip_encrypted() {
+    check_aggregated_round_trip(Some(Cipher::from_hex(KEY).unwrap()));
+}
+
+#[test]
+fn slots_are_packed() {
+    let mut format = get_format(None, WireFormat::Shim);
+    format.aggregate = true;
+    let queue = PriorityQueue::new(200, format);
+    let mut deobfuscator = Deobfuscator::new(SlotFormat::new(IP_B, IP_A, None, WireFormat::Shim), false, false, TIMEOUT);
+
+    // 192B in a slot, each packet takes 2B more
+    let frames: Vec<Vec<u8>> = [60, 60, 60, 100, 190].iter().enumerate().map(|(i, &length)| get_frame(length, i as u8 + 1)).collect();
+    for frame in &frames {
+        assert!(queue.push(frame.clone()));
+    }
+    let slots: Vec<Vec<u8>> = (0..4).map(|seq| queue.pop(seq)).collect();
+    let received: Vec<Vec<Vec<u8>>> = slots.iter().map(|slot| deobfuscator.process_packet(slot).into_iter().flatten().map(|f| f.to_vec()).collect()).collect();
+    assert_eq!(received, [frames[..3].to_vec(), frames[3..4].to_vec(), frames[4..].to_vec(), vec![]]);
+    assert_eq!(queue.stats().aggregated, 2);
+    assert_eq!(queue.stats().real_sent, 3);
+}
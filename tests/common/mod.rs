@@ -0,0 +1,136 @@
+// Helpers shared by the tests running frames between peers, each test crate uses its own part of them
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use budget_ditto::control::Control;
+use budget_ditto::crypto::Cipher;
+use budget_ditto::deobfuscate::Deobfuscator;
+use budget_ditto::metrics::Metrics;
+use budget_ditto::packet_io::memory::{self, MemoryIo};
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::pacer::{CatchUp, SleepPacer};
+use budget_ditto::peer::Classifier;
+use budget_ditto::queues::priority_queue::{QueueLimits, SlotFormat, UdpPorts};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use pnet::util::MacAddr;
+
+pub const IP_A: [u8; 4] = [10, 9, 0, 1];
+pub const IP_B: [u8; 4] = [10, 9, 0, 2];
+pub const IP_C: [u8; 4] = [10, 9, 0, 3];
+pub const IP6_A: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+pub const IP6_B: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+pub const PORTS: UdpPorts = UdpPorts { src: 4500, dst: 4501 };
+pub const PATTERN: [usize; 3] = [200, 1400, 1400];
+// In Mbps, about 7000 slots/s
+pub const RATE: f64 = 64.0;
+pub const KEY: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+// The test threads share the CPU, frames may wait for longer than the limit derived from the rate
+pub const LIMITS: QueueLimits = QueueLimits { max_packets: None, max_bytes: None, max_sojourn: Some(TIMEOUT) };
+
+pub fn get_frame(length: usize, id: u8) -> Vec<u8> {
+    // Ethernet frame carrying IPv4, the id is in every payload byte so frames can be told apart
+    let mut frame = vec![id; length];
+    frame[..12].copy_from_slice(&[0x02, 0x11, 0x22, 0x33, 0x44, 0x01, 0x02, 0x11, 0x22, 0x33, 0x44, 0x02]);
+    frame[12..14].copy_from_slice(&[0x08, 0x00]);
+    frame
+}
+
+pub fn get_frame_with(dst: [u8; 6], header: &[u8], length: usize, id: u8) -> Vec<u8> {
+    // Ethernet frame to dst with header (ethertype, or VLAN tags and ethertype) after the source address
+    let mut frame = vec![id; length];
+    frame[..6].copy_from_slice(&dst);
+    frame[6..12].copy_from_slice(&[0x02, 0x11, 0x22, 0x33, 0x44, 0x02]);
+    frame[12..12 + header.len()].copy_from_slice(header);
+    frame
+}
+
+pub fn get_format(cipher: Option<Cipher>, wire_format: WireFormat) -> SlotFormat {
+    // Slots sent by peer A
+    SlotFormat::new(IP_A, IP_B, cipher, wire_format)
+}
+
+pub fn spawn_peers<T: PacketIo + 'static>(input: T, cipher: Option<Cipher>, wire_format: WireFormat) -> MemoryIo {
+    // The threads are left running, they stop when the links are dropped
+    start_peers(input, &PATTERN, get_format(cipher, wire_format), RATE, &Shutdown::new(), TIMEOUT).0
+}
+
+pub fn start_peers<T: PacketIo + 'static>(input: T, pattern: &[usize], format: SlotFormat, rate: f64, shutdown: &Shutdown, drain_timeout: Duration) -> (MemoryIo, Vec<JoinHandle<()>>) {
+    // Peer A obfuscates the frames of input and sends them with pattern on a link to peer B, which deobfuscates
+    // them to the returned end. Peer B stops once peer A has stopped sending
+    let (wire_a, wire_b) = memory::link();
+    let (output_b, host_b) = memory::link();
+
+    let udp = format.udp.map(|ports| UdpPorts { src: ports.dst, dst: ports.src });
+    let deobf_format = SlotFormat { src: format.dst, dst: format.src, udp, ..format.clone() };
+    let control = Arc::new(Control::new(pattern, rate, format));
+    control.set_limits(LIMITS);
+    let rx_control = Arc::clone(&control);
+    let (shutdown_obf, shutdown_send) = (shutdown.clone(), shutdown.clone());
+    let obf_handle = thread::spawn(move || budget_ditto::obfuscate_data(input, MacAddr::zero(), &[rx_control], &Classifier::default(), 1e3, false, &shutdown_obf));
+    let send_handle = thread::spawn(move || budget_ditto::transmit(wire_a, SleepPacer, CatchUp::Burst, control, false, &shutdown_send, drain_timeout));
+
+    let mut deobfuscator = Deobfuscator::new(deobf_format, false, false, TIMEOUT);
+    let deobf_handle = thread::spawn(move || budget_ditto::deobfuscate_data(wire_b, output_b, std::slice::from_mut(&mut deobfuscator), &Metrics::default(), None, false, &Shutdown::new()));
+    (host_b, vec![obf_handle, send_handle, deobf_handle])
+}
+
+pub fn receive_all(host: &mut MemoryIo, count: usize) -> Vec<Vec<u8>> {
+    let mut received = Vec::new();
+    while received.len() < count {
+        match host.recv_timeout(TIMEOUT) {
+            Some(frame) => received.push(frame.to_vec()),
+            None => break,
+        }
+    }
+    received
+}
+
+pub fn check_round_trip(format: SlotFormat) {
+    let (mut host_a, input_a) = memory::link();
+    let mut host_b = start_peers(input_a, &PATTERN, format.clone(), RATE, &Shutdown::new(), TIMEOUT).0;
+
+    // Frames for both sizes of the pattern
+    let frames: Vec<Vec<u8>> = (0..20).map(|i| get_frame(if i % 2 == 0 { 60 } else { 1000 }, i as u8 + 1)).collect();
+    for frame in &frames {
+        host_a.send(frame).unwrap();
+    }
+
+    let received = receive_all(&mut host_b, frames.len());
+    check_delivered(&received, &frames, &PATTERN, &format);
+}
+
+pub fn check_delivered(received: &[Vec<u8>], sent: &[Vec<u8>], pattern: &[usize], format: &SlotFormat) {
+    // Frames pushed to a size served by a single queue come out in the order they were sent. A size with several
+    // queues spreads its frames over them, so only the frames themselves are the same. Frames too large for every
+    // queue are split over the queues of the largest size
+    let mut sizes = pattern.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    let get_class = |frame: &Vec<u8>| sizes.iter().position(|&size| frame.len() <= size - format.overhead()).unwrap_or(sizes.len() - 1);
+    for (class, &size) in sizes.iter().enumerate() {
+        let mut received: Vec<&Vec<u8>> = received.iter().filter(|frame| get_class(frame) == class).collect();
+        let mut sent: Vec<&Vec<u8>> = sent.iter().filter(|frame| get_class(frame) == class).collect();
+        if pattern.iter().filter(|&&p| p == size).count() > 1 {
+            received.sort();
+            sent.sort();
+        }
+        assert_eq!(received, sent, "frames of the {}B slots", size);
+    }
+    assert_eq!(received.len(), sent.len());
+}
+
+pub fn get_udp_format(src: impl Into<std::net::IpAddr>, dst: impl Into<std::net::IpAddr>, cipher: Option<Cipher>, wire_format: WireFormat) -> SlotFormat {
+    let mut format = SlotFormat::new(src, dst, cipher, wire_format);
+    format.udp = Some(PORTS);
+    format
+}
+
+pub fn get_frame_to(ip_dst: [u8; 4], length: usize, id: u8) -> Vec<u8> {
+    let mut frame = get_frame(length, id);
+    frame[30..34].copy_from_slice(&ip_dst);
+    frame
+}
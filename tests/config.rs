@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+use budget_ditto::config::Config;
+use budget_ditto::pattern;
+use budget_ditto::shim::WireFormat;
+
+// The sections without defaults
+const MINIMAL: &str = "
+[ip]
+src = '10.7.0.2'
+dst = '10.7.0.1'
+
+[interface]
+no_obf = 'eth1'
+obf = 'eth2'
+";
+
+const KEY: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+fn get_problems(toml_str: &str) -> Vec<String> {
+    Config::from_toml_str(toml_str).expect_err("config should be rejected").problems
+}
+
+#[test]
+fn defaults() {
+    let config = Config::from_toml_str(MINIMAL).unwrap();
+    assert_eq!(config.pattern.sizes, pattern::DEFAULT_PATTERN);
+    assert_eq!(config.pattern.gaps, None);
+    assert_eq!(config.general.rate, 100.0);
+    assert_eq!(config.general.wire_format, WireFormat::Legacy);
+    assert!(!config.general.aggregate && !config.general.local);
+    assert_eq!((config.general.drain_timeout, config.general.reassembly_timeout), (1.0, 1.0));
+    assert_eq!(config.isolation.priority, 99);
+    assert_eq!(config.crypto.key, None);
+    assert_eq!(config.metrics.textfile_interval, 10.0);
+    assert!(config.adaptive.levels.is_empty() && config.peer.is_empty());
+    assert_eq!(config.interface.src_device(), "eth1");
+}
+
+#[test]
+fn unknown_keys_are_reported() {
+    let problems = get_problems(&format!("{}
+[general]
+rat = 10.0
+
+[patern]
+sizes = [200]
+
+[[peer]]
+dst = '10.7.0.3'
+route = ['10.8.0.0/24']
+", MINIMAL));
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems.contains(&"Unknown section [patern]".to_string()), "{:?}", problems);
+    assert!(problems.contains(&"Unknown key `rat` in [general]".to_string()), "{:?}", problems);
+    // Keys of a peer are prefixed by its index
+    assert!(problems.contains(&"Unknown key `0.route` in [[peer]]".to_string()), "{:?}", problems);
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let problems = get_problems(&format!("
+[ip]
+src = '10.7.0.2'
+dst = 'fd00::1'
+
+[interface]
+no_obf = 'eth1'
+obf = 'eth2'
+
+[pattern]
+sizes = [200, 1600]
+
+[general]
+rate = -1.0
+aggregate = true
+wire_format = 'legacy'
+
+[crypto]
+key = '{}'
+", &KEY[..10]));
+    assert_eq!(problems.len(), 5, "{:?}", problems);
+    for part in ["same IP version", "above the MTU", "[crypto]", "rate must be", "aggregate needs"] {
+        assert!(problems.iter().any(|problem| problem.contains(part)), "{}: {:?}", part, problems);
+    }
+
+    // Missing sections and values of the wrong type are reported with the rest
+    let problems = get_problems("[ip]\nsrc = '10.7.0.2'\n\n[general]\nrate = 'fast'\n");
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert!(problems.iter().all(|problem| problem.starts_with("[ip]") || problem.starts_with("[interface]") || problem.starts_with("[general]")), "{:?}", problems);
+
+    let error = Config::from_toml_str("[ip]\n[interface]\n").unwrap_err();
+    let text = error.to_string();
+    assert!(text.starts_with(&format!("Invalid configuration, found {} problem(s):", error.problems.len())), "{}", text);
+    assert!(error.problems.iter().all(|problem| text.contains(problem.as_str())), "{}", text);
+}
+
+#[test]
+fn slots_must_fit_their_headers() {
+    // 24B of nonce, 16B of tag and 8B of shim header leave no room for a 14B Ethernet header in 60B
+    let min_size = pattern::get_min_slot_size(true, WireFormat::Shim);
+    assert_eq!(min_size, 62);
+    let encrypted = format!("{}\n[general]\nwire_format = 'shim'\n\n[crypto]\nkey = '{}'\n", MINIMAL, KEY);
+    let problems = get_problems(&format!("{}\n[pattern]\nsizes = [60, 1400]\n", encrypted));
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert!(problems[0].contains("too small for the slot headers"), "{:?}", problems);
+    let problems = get_problems(&format!("{}\n[[peer]]\ndst = '10.7.0.3'\npattern = [300, 60]\n", encrypted));
+    assert!(problems.len() == 1 && problems[0].starts_with("[[peer]] 10.7.0.3:"), "{:?}", problems);
+    assert!(Config::from_toml_str(&format!("{}\n[pattern]\nsizes = [{}, 1400]\n", encrypted, min_size)).is_ok());
+
+    // The same check applies to a pattern set on the running pipeline
+    assert!(pattern::validate_pattern(&[60], pattern::IP_HEADER_LEN, true, WireFormat::Shim).is_err());
+    assert!(pattern::validate_pattern(&[60], pattern::IP_HEADER_LEN, false, WireFormat::Legacy).is_ok());
+    assert!(pattern::validate_pattern(&[10], pattern::IP_HEADER_LEN, false, WireFormat::Legacy).is_err());
+}
+
+#[test]
+fn shipped_configs_load() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("config");
+    let mut count = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            let path = path.to_str().unwrap();
+            // Some pin threads to cores of the machine they were written for, which this one may not have
+            if let Err(e) = Config::from_file(path) {
+                let problems: Vec<&String> = e.problems.iter().filter(|problem| !problem.ends_with("cores are available")).collect();
+                assert!(problems.is_empty(), "{}: {:?}", path, problems);
+            }
+            count += 1;
+        }
+    }
+    assert!(count > 0);
+}
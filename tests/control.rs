@@ -0,0 +1,119 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use budget_ditto::control::{self, Control};
+use budget_ditto::queues::priority_queue::SlotFormat;
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use serde_json::Value;
+
+const PATTERN: [usize; 3] = [200, 1400, 1400];
+const RATE: f64 = 10.0;
+
+fn get_control() -> Arc<Control> {
+    Arc::new(Control::new(&PATTERN, RATE, SlotFormat::new([10, 9, 0, 1], [10, 9, 0, 2], None, WireFormat::Shim)))
+}
+
+#[test]
+fn commands() {
+    let controls = [get_control()];
+    let control = &controls[0];
+
+    let reply = control::handle_command("queues", &controls);
+    assert_eq!(reply["ok"], true);
+    let lengths: Vec<&Value> = reply["queues"].as_array().unwrap().iter().map(|q| &q["length"]).collect();
+    assert_eq!(lengths, [200, 1400, 1400]);
+
+    let pps = control.scheduler().pps();
+    let reply = control::handle_command("rate 20", &controls);
+    assert_eq!(reply["rate"], 20.0);
+    assert!((control.scheduler().pps() - 2.0 * pps).abs() < 1e-6);
+
+    control::handle_command("pause", &controls);
+    assert!(control.is_paused());
+    assert_eq!(control::handle_command("stats", &controls)["paused"], true);
+    control::handle_command("resume", &controls);
+    assert!(!control.is_paused());
+
+    for command in ["rate -1", "rate fast", "pattern 10", "pattern", "unknown", ""] {
+        let reply = control::handle_command(command, &controls);
+        assert_eq!(reply["ok"], false, "{}", command);
+        assert!(reply["error"].is_string());
+    }
+}
+
+#[test]
+fn pattern_change_drops_queued_packets() {
+    let controls = [get_control()];
+    let control = &controls[0];
+    let rrs = control.scheduler();
+    let psv = budget_ditto::pattern::get_push_state_vector(&PATTERN);
+    rrs.push(vec![1; 100], &psv);
+    // Too large even split in fragments
+    rrs.push(vec![1; 70000], &psv);
+    let stats = rrs.stats();
+    assert_eq!((stats.pushed(), stats.dropped(), stats.queued()), (1, 1, 1));
+    assert_eq!(stats.queues[0].bytes, 100);
+    assert_eq!(stats.queues[0].padding, 100);
+
+    let reply = control::handle_command("gaps 0 1 2", &controls);
+    assert_eq!(reply["gaps"], serde_json::json!([0.0, 1.0, 2.0]));
+    for command in ["gaps 0 0 0", "gaps 1 1", "gaps 1 -1 1", "gaps 1 x 1"] {
+        assert_eq!(control::handle_command(command, &controls)["ok"], false, "{}", command);
+    }
+
+    let reply = control::handle_command("pattern 600 300", &controls);
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["dropped"], 1);
+    // The gaps were for the previous pattern
+    assert_eq!(control.scheduler().gaps(), [1.0, 1.0]);
+    assert_eq!(control.generation(), 1);
+    assert_eq!(control.scheduler().pattern(), [600, 300]);
+    assert_eq!(control.scheduler().queued(), 0);
+}
+
+#[test]
+fn socket() {
+    let path = std::env::temp_dir().join(format!("budget_ditto_{}.sock", std::process::id()));
+    let path = path.to_str().unwrap();
+    let control = get_control();
+    let shutdown = Shutdown::new();
+    let handle = control::serve(path, vec![Arc::clone(&control)], shutdown.clone()).unwrap();
+    assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let mut stream = UnixStream::connect(path).unwrap();
+    writeln!(stream, "pause").unwrap();
+    writeln!(stream, "stats").unwrap();
+    let mut replies = BufReader::new(stream).lines();
+    let reply: Value = serde_json::from_str(&replies.next().unwrap().unwrap()).unwrap();
+    assert_eq!(reply["paused"], true);
+    let reply: Value = serde_json::from_str(&replies.next().unwrap().unwrap()).unwrap();
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["queued"], 0);
+    assert!(control.is_paused());
+    drop(replies);
+
+    shutdown.request();
+    handle.join().unwrap();
+    assert!(!std::path::Path::new(path).exists());
+}
+
+#[test]
+fn commands_per_peer() {
+    // Without dst the first peer, which every reply names
+    let controls = [get_control(), Arc::new(Control::new(&[300], RATE, SlotFormat::new([10, 9, 0, 1], [10, 9, 0, 3], None, WireFormat::Shim)))];
+    let reply = control::handle_command("rate 20", &controls);
+    assert_eq!((&reply["dst"], &reply["rate"]), (&Value::from("10.9.0.2"), &Value::from(20.0)));
+    let reply = control::handle_command("dst 10.9.0.3 pause", &controls);
+    assert_eq!((&reply["ok"], &reply["dst"]), (&Value::from(true), &Value::from("10.9.0.3")));
+    assert!(!controls[0].is_paused() && controls[1].is_paused());
+    assert_eq!(control::handle_command("dst 10.9.0.3 queues", &controls)["queues"].as_array().unwrap().len(), 1);
+
+    let reply = control::handle_command("peers", &controls);
+    let peers: Vec<(&Value, &Value, &Value)> = reply["peers"].as_array().unwrap().iter().map(|peer| (&peer["dst"], &peer["rate"], &peer["paused"])).collect();
+    assert_eq!(peers, [(&Value::from("10.9.0.2"), &Value::from(20.0), &Value::from(false)), (&Value::from("10.9.0.3"), &Value::from(RATE), &Value::from(true))]);
+    for command in ["dst 10.9.0.4 stats", "dst peer stats", "dst 10.9.0.3"] {
+        assert_eq!(control::handle_command(command, &controls)["ok"], false, "{}", command);
+    }
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::time::Duration;
+use budget_ditto::crypto::Cipher;
+use budget_ditto::deobfuscate::Deobfuscator;
+use budget_ditto::metrics::Metrics;
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+
+const OTHER_KEY: &str = "ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+fn get_slots(format: SlotFormat, count: usize) -> Vec<Vec<u8>> {
+    // Slots of peer A each carrying a frame, frame i has id i + 1
+    let queue = PriorityQueue::new(200, format);
+    (0..count).map(|i| {
+        assert!(queue.push(get_frame(100, i as u8 + 1)));
+        queue.pop(i as u32)
+    }).collect()
+}
+
+fn deobfuscate_all(slots: &[Vec<u8>], key: &str, wire_format: WireFormat) -> (Vec<Vec<u8>>, Metrics) {
+    // Frames peer B forwards from slots, it stops once they have all been read
+    let (mut wire_a, wire_b) = memory::link();
+    let (output_b, mut host_b) = memory::link();
+    for slot in slots {
+        wire_a.send(slot).unwrap();
+    }
+    drop(wire_a);
+
+    let format = SlotFormat::new(IP_B, IP_A, Some(Cipher::from_hex(key).unwrap()), wire_format);
+    let mut deobfuscator = Deobfuscator::new(format, false, false, TIMEOUT);
+    let metrics = Metrics::default();
+    budget_ditto::deobfuscate_data(wire_b, output_b, std::slice::from_mut(&mut deobfuscator), &metrics, None, false, &Shutdown::new());
+
+    let mut received = Vec::new();
+    while let Some(frame) = host_b.recv_timeout(Duration::ZERO) {
+        received.push(frame.to_vec());
+    }
+    (received, metrics)
+}
+
+#[test]
+fn tampered_slots_are_dropped() {
+    for wire_format in [WireFormat::Shim, WireFormat::Legacy] {
+        let format = get_format(Some(Cipher::from_hex(KEY).unwrap()), wire_format);
+        let outer_header_len = format.outer_header_len();
+        let mut slots = get_slots(format, 4);
+        // A byte flipped in the nonce, the ciphertext and the tag
+        for (slot, offset) in slots.iter_mut().zip([outer_header_len, outer_header_len + 50, 199]) {
+            slot[offset] ^= 1;
+        }
+
+        let (received, metrics) = deobfuscate_all(&slots, KEY, wire_format);
+        assert_eq!(received, [get_frame(100, 4)], "{:?}", wire_format);
+        assert_eq!((metrics.discarded.get(), metrics.deobfuscated.get()), (3, 1));
+    }
+}
+
+#[test]
+fn wrong_key_is_dropped() {
+    for wire_format in [WireFormat::Shim, WireFormat::Legacy] {
+        let slots = get_slots(get_format(Some(Cipher::from_hex(KEY).unwrap()), wire_format), 4);
+        let (received, metrics) = deobfuscate_all(&slots, OTHER_KEY, wire_format);
+        assert!(received.is_empty(), "{:?}", wire_format);
+        assert_eq!((metrics.discarded.get(), metrics.deobfuscated.get()), (4, 0));
+    }
+}
+
+#[test]
+fn truncated_slots_are_dropped() {
+    for wire_format in [WireFormat::Shim, WireFormat::Legacy] {
+        let format = get_format(Some(Cipher::from_hex(KEY).unwrap()), wire_format);
+        let outer_header_len = format.outer_header_len();
+        let slot = get_slots(format, 1).remove(0);
+        // Cut in the tag, in the ciphertext, right after the outer header and within it
+        let mut slots: Vec<Vec<u8>> = [slot.len() - 1, outer_header_len + 30, outer_header_len, 10, 0].iter().map(|&length| slot[..length].to_vec()).collect();
+        slots.push(slot);
+
+        let (received, metrics) = deobfuscate_all(&slots, KEY, wire_format);
+        assert_eq!(received, [get_frame(100, 1)], "{:?}", wire_format);
+        assert_eq!((metrics.discarded.get(), metrics.deobfuscated.get()), (5, 1));
+    }
+}
+
+#[test]
+fn replayed_slots_are_dropped() {
+    // Told apart by the shim sequence number, which the legacy format does not have
+    let slots = get_slots(get_format(Some(Cipher::from_hex(KEY).unwrap()), WireFormat::Shim), 3);
+    let replayed = [&slots[0], &slots[1], &slots[0], &slots[2], &slots[1], &slots[2]].map(|slot| slot.clone());
+
+    let (received, metrics) = deobfuscate_all(&replayed, KEY, WireFormat::Shim);
+    assert_eq!(received, [get_frame(100, 1), get_frame(100, 2), get_frame(100, 3)]);
+    assert_eq!((metrics.discarded.get(), metrics.slots_replayed.get()), (3, 3));
+    assert_eq!((metrics.slots_lost.get(), metrics.slots_late.get()), (0, 0));
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::thread;
+use std::time::Duration;
+use budget_ditto::crypto::Cipher;
+use budget_ditto::deobfuscate::{Deobfuscator, Frames};
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use common::*;
+
+fn check_fragmented_round_trip(cipher: Option<Cipher>) {
+    let (mut host_a, input_a) = memory::link();
+    let mut host_b = spawn_peers(input_a, cipher, WireFormat::Shim);
+
+    // The largest frame that fits is 1392B, the others are split across slots of the two 1400B queues
+    let frames: Vec<Vec<u8>> = (0..12).map(|i| get_frame([60, 1393, 3000, 9000][i % 4], i as u8 + 1)).collect();
+    for frame in &frames {
+        host_a.send(frame).unwrap();
+    }
+
+    let mut received = receive_all(&mut host_b, frames.len());
+    let mut expected = frames.clone();
+    received.sort();
+    expected.sort();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn fragmented_round_trip() {
+    check_fragmented_round_trip(None);
+}
+
+#[test]
+fn fragmented_round_trip_encrypted() {
+    check_fragmented_round_trip(Some(Cipher::from_hex(KEY).unwrap()));
+}
+
+#[test]
+fn missing_fragment_times_out() {
+    let queue = PriorityQueue::new(200, SlotFormat::new(IP_A, IP_B, None, WireFormat::Shim));
+    let mut deobfuscator = Deobfuscator::new(SlotFormat::new(IP_B, IP_A, None, WireFormat::Shim), false, false, Duration::from_millis(20));
+
+    // 188B in each fragment
+    assert!(queue.push_fragments(&get_frame(500, 1), 0));
+    assert!(queue.push_fragments(&get_frame(500, 2), 1));
+    let slots: Vec<Vec<u8>> = (0..6).map(|seq| queue.pop(seq)).collect();
+    assert!(deobfuscator.process_packet(&slots[0]).is_none());
+    assert!(deobfuscator.process_packet(&slots[2]).is_none());
+
+    thread::sleep(Duration::from_millis(30));
+    assert!(deobfuscator.process_packet(&slots[3]).is_none());
+    assert!(deobfuscator.process_packet(&slots[4]).is_none());
+    assert_eq!(deobfuscator.process_packet(&slots[5]), Some(Frames::One(&get_frame(500, 2))));
+    assert_eq!(deobfuscator.reassembly_timeouts(), 1);
+    // Too late for the first frame
+    assert!(deobfuscator.process_packet(&slots[1]).is_none());
+}
@@ -0,0 +1,73 @@
+use budget_ditto::hardware_obf::deobfuscate_tofino;
+
+// Ethertypes of the headers the switch adds, with the bytes each one takes
+const PADS: [(u16, usize); 8] = [(2184, 18), (2049, 32), (2050, 16), (2051, 8), (2052, 4), (2053, 2), (9, 1), (2313, 1)];
+const LAST_PAD: u16 = 2304;
+const ETHERTYPE_32B_PADS: u16 = 2049;
+
+fn get_inner(header: &[u8], length: usize) -> Vec<u8> {
+    // Ethernet frame with header (ethertype, or VLAN tags and ethertype) after the addresses
+    let mut frame = vec![0xab; length];
+    frame[..12].copy_from_slice(&[0x02, 0x11, 0x22, 0x33, 0x44, 0x01, 0x02, 0x11, 0x22, 0x33, 0x44, 0x02]);
+    frame[12..12 + header.len()].copy_from_slice(header);
+    frame
+}
+
+fn get_padded(pads: &[(u16, usize)], inner: &[u8]) -> Vec<u8> {
+    // Each pad has its ethertype where a 14B header starting at it would, a pad shorter than that shares bytes with
+    // the next one as the switch writes them
+    let length: usize = pads.iter().map(|&(_, len)| len).sum();
+    let mut buffer = vec![0; length + 12];
+    let mut offset = 0;
+    for &(ethertype, len) in pads {
+        buffer[offset + 12..offset + 14].copy_from_slice(&ethertype.to_be_bytes());
+        offset += len;
+    }
+    buffer.truncate(length);
+    buffer.extend_from_slice(inner);
+    buffer
+}
+
+#[test]
+fn every_padding_is_removed() {
+    let inner = get_inner(&[0x08, 0x00], 100);
+    for pad in PADS {
+        let buffer = get_padded(&[pad, (LAST_PAD, 14)], &inner);
+        assert_eq!(deobfuscate_tofino(&buffer), Some(&inner[..]), "{:?}", pad);
+    }
+    // All of them in a row
+    let mut pads = PADS.to_vec();
+    pads.push((LAST_PAD, 14));
+    assert_eq!(deobfuscate_tofino(&get_padded(&pads, &inner)), Some(&inner[..]));
+}
+
+#[test]
+fn last_pad_looks_ahead() {
+    // Followed by padding it takes 32B, otherwise the inner frame starts right after it whatever it carries
+    let inner = get_inner(&[0x86, 0xdd], 100);
+    let buffer = get_padded(&[(LAST_PAD, 14), (ETHERTYPE_32B_PADS, 18), (LAST_PAD, 14)], &inner);
+    assert_eq!(deobfuscate_tofino(&buffer), Some(&inner[..]));
+    for header in [&[0x08, 0x06][..], &[0x81, 0x00, 0x00, 0x64, 0x08, 0x00], &[0x88, 0xa8, 0x00, 0x0a, 0x81, 0x00, 0x00, 0x64, 0x86, 0xdd]] {
+        let inner = get_inner(header, 100);
+        assert_eq!(deobfuscate_tofino(&get_padded(&[(LAST_PAD, 14)], &inner)), Some(&inner[..]));
+    }
+}
+
+#[test]
+fn other_header_is_stripped() {
+    let inner = get_inner(&[0x08, 0x00], 100);
+    let buffer = get_padded(&[(0x88b5, 14)], &inner);
+    assert_eq!(deobfuscate_tofino(&buffer), Some(&inner[..]));
+}
+
+#[test]
+fn short_frames_are_dropped() {
+    let inner = get_inner(&[0x08, 0x00], 60);
+    let buffer = get_padded(&[PADS[0], PADS[1], (LAST_PAD, 14)], &inner);
+    assert_eq!(deobfuscate_tofino(&[]), None);
+    assert_eq!(deobfuscate_tofino(&buffer[..13]), None);
+    // A frame cut anywhere in the padding is not read past its end
+    for length in 0..buffer.len() {
+        assert!(deobfuscate_tofino(&buffer[..length]).is_none_or(|frame| frame.len() < inner.len()), "{}", length);
+    }
+}
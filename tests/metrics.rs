@@ -0,0 +1,74 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use budget_ditto::control::Control;
+use budget_ditto::metrics;
+use budget_ditto::pattern;
+use budget_ditto::queues::priority_queue::SlotFormat;
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+
+const PATTERN: [usize; 2] = [200, 1400];
+
+fn get_control() -> Arc<Control> {
+    Arc::new(Control::new(&PATTERN, 10.0, SlotFormat::new([10, 9, 0, 1], [10, 9, 0, 2], None, WireFormat::Shim)))
+}
+
+#[test]
+fn render() {
+    let control = get_control();
+    let rrs = control.scheduler();
+    let psv = pattern::get_push_state_vector(&PATTERN);
+    rrs.push(vec![1; 100], &psv);
+    rrs.push(vec![1; 2000], &psv);
+    rrs.pop(0);
+    rrs.pop(0);
+    control.metrics().frames_received.add(3);
+    control.metrics().pacing_deviation.record(std::time::Duration::from_micros(20));
+
+    // A second peer with a pattern of its own
+    let other = Arc::new(Control::new(&[300], 5.0, SlotFormat::new([10, 9, 0, 1], [10, 9, 0, 3], None, WireFormat::Shim)));
+    let text = metrics::render(&[Arc::clone(&control), other], control.metrics());
+    for line in [
+        "# TYPE ditto_frames_received_total counter",
+        "ditto_frames_received_total 3",
+        "ditto_queue_pushed_total{peer=\"10.9.0.2\",queue=\"0\",length=\"200\"} 1",
+        "ditto_queue_pushed_total{peer=\"10.9.0.2\",queue=\"1\",length=\"1400\"} 2",
+        "ditto_queue_padding_bytes_total{peer=\"10.9.0.2\",queue=\"0\",length=\"200\"} 100",
+        "ditto_slots_sent_total{peer=\"10.9.0.2\",queue=\"0\",length=\"200\",type=\"real\"} 1",
+        "ditto_slots_sent_total{peer=\"10.9.0.2\",queue=\"0\",length=\"200\",type=\"chaff\"} 1",
+        "# TYPE ditto_pacing_deviation_seconds histogram",
+        "ditto_pacing_deviation_seconds_bucket{le=\"0.00001\"} 0",
+        "ditto_pacing_deviation_seconds_bucket{le=\"0.00005\"} 1",
+        "ditto_pacing_deviation_seconds_bucket{le=\"+Inf\"} 1",
+        "ditto_pacing_deviation_seconds_count 1",
+        "ditto_pacing_deviation_max_seconds 0.00002",
+        "ditto_queue_pushed_total{peer=\"10.9.0.3\",queue=\"0\",length=\"300\"} 0",
+        "ditto_fragmented_total{peer=\"10.9.0.2\"} 1",
+        "ditto_oversize_dropped_total{peer=\"10.9.0.2\"} 0",
+        "ditto_rate_mbps{peer=\"10.9.0.2\"} 10",
+        "ditto_rate_mbps{peer=\"10.9.0.3\"} 5",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing `{}` in\n{}", line, text);
+    }
+}
+
+#[test]
+fn http_endpoint() {
+    let control = get_control();
+    let shutdown = Shutdown::new();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let handle = metrics::serve_http(addr, vec![Arc::clone(&control)], Arc::clone(control.metrics()), shutdown.clone()).unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("ditto_paused{peer=\"10.9.0.2\"} 0"));
+
+    shutdown.request();
+    handle.join().unwrap();
+}
@@ -0,0 +1,77 @@
+use budget_ditto::optimizer::{self, SlotModel, Trace};
+use budget_ditto::pattern;
+use budget_ditto::shim::WireFormat;
+
+// Plain slots over IPv4, 1480B at most once wrapped
+const SHIM: (bool, WireFormat) = (false, WireFormat::Shim);
+const LEGACY: (bool, WireFormat) = (false, WireFormat::Legacy);
+
+fn get_model((is_encrypted, wire_format): (bool, WireFormat)) -> SlotModel {
+    SlotModel::new(is_encrypted, wire_format, pattern::IP_HEADER_LEN)
+}
+
+#[test]
+fn trace_histogram() {
+    let trace = Trace::new(vec![100, 60, 1514, 100], 2.0);
+    assert_eq!(trace.histogram, [(60, 1), (100, 2), (1514, 1)]);
+    assert_eq!((trace.num_packets, trace.max_size()), (4, 1514));
+}
+
+#[test]
+fn evaluate_takes_out_the_slot_overhead() {
+    // 8B of shim header in each slot, a frame of 193B is split in two 188B fragments
+    let model = get_model(SHIM);
+    let fits = optimizer::evaluate(&Trace::new(vec![192; 10], 1.0), &[200], &model, None, 0.0);
+    assert_eq!((fits.fragmented, fits.dropped), (0, 0));
+    assert_eq!(fits.classes[0].arrival_rate, 10.0);
+
+    let split = optimizer::evaluate(&Trace::new(vec![193; 10], 1.0), &[200], &model, None, 0.0);
+    assert_eq!((split.fragmented, split.dropped), (10, 0));
+    assert_eq!((split.classes[0].packet_rate, split.classes[0].arrival_rate), (10.0, 20.0));
+    assert!(split.cost.is_finite());
+
+    // Smaller frames go to the smallest size they fit in
+    let evaluation = optimizer::evaluate(&Trace::new(vec![60, 60, 500], 1.0), &[1000, 68], &model, None, 0.0);
+    let rates: Vec<(usize, f64)> = evaluation.classes.iter().map(|class| (class.size, class.packet_rate)).collect();
+    assert_eq!(rates, [(68, 2.0), (1000, 1.0)]);
+}
+
+#[test]
+fn full_frames_are_split_or_dropped() {
+    // A 1514B frame does not fit in the largest slot the MTU allows
+    let trace = Trace::new(vec![1514; 4], 1.0);
+    let max_size = get_model(SHIM).max_size();
+    assert_eq!(max_size, 1480);
+
+    let shim = optimizer::evaluate(&trace, &[max_size], &get_model(SHIM), None, 0.0);
+    assert_eq!((shim.fragmented, shim.dropped), (4, 0));
+    assert_eq!(shim.classes[0].arrival_rate, 8.0);
+    // Without the shim header frames cannot be split
+    let legacy = optimizer::evaluate(&trace, &[max_size], &get_model(LEGACY), None, 0.0);
+    assert_eq!((legacy.fragmented, legacy.dropped), (0, 4));
+}
+
+#[test]
+fn optimize_fits_the_config() {
+    let mut sizes = vec![60; 900];
+    sizes.extend([1514; 100]);
+    let trace = Trace::new(sizes, 1.0);
+    for config in [SHIM, (true, WireFormat::Shim), (true, WireFormat::Legacy)] {
+        let model = get_model(config);
+        let evaluation = optimizer::optimize(&trace, 4, &model, None, 0.1);
+        assert!(evaluation.cost.is_finite(), "{:?}", config);
+        assert!(pattern::validate_pattern(&evaluation.pattern, model.outer_header_len, config.0, config.1).is_ok(), "{:?}", evaluation.pattern);
+        // The small frames get slots of their own size, the full ones are split over the largest slots when the
+        // wire format allows it
+        assert!(evaluation.pattern.contains(&(60 + model.overhead)), "{:?}", evaluation.pattern);
+        assert_eq!(evaluation.pattern.iter().max(), Some(&model.max_size()));
+        assert_eq!(evaluation.dropped, if model.can_fragment { 0 } else { 100 });
+        assert!(evaluation.pattern.iter().all(|&size| size >= model.min_size));
+    }
+}
+
+#[test]
+fn interleave() {
+    assert_eq!(optimizer::interleave(&[1, 2, 3, 4, 5]), [5, 1, 4, 2, 3]);
+    assert_eq!(optimizer::interleave(&[200, 1400]), [1400, 200]);
+}
@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant};
+use budget_ditto::metrics::DeviationHistogram;
+use budget_ditto::pacer::{self, PacerKind};
+
+const DELAY: Duration = Duration::from_millis(5);
+// Generous, the test threads share the CPU
+const TOLERANCE: Duration = Duration::from_millis(100);
+
+#[test]
+fn pacers_wait_until_deadline() {
+    for kind in [PacerKind::Sleep, PacerKind::Timerfd, PacerKind::Hybrid, PacerKind::Busy] {
+        let mut pacer = pacer::open(kind).unwrap();
+        for _ in 0..3 {
+            let deadline = Instant::now() + DELAY;
+            pacer.wait_until(deadline);
+            let now = Instant::now();
+            assert!(now >= deadline, "{:?} returned early", kind);
+            assert!(now - deadline < TOLERANCE, "{:?} returned {:?} late", kind, now - deadline);
+        }
+
+        // A deadline already past does not wait
+        let start = Instant::now();
+        pacer.wait_until(start - DELAY);
+        assert!(start.elapsed() < TOLERANCE, "{:?}", kind);
+    }
+}
+
+#[test]
+fn deviation_histogram() {
+    let histogram = DeviationHistogram::default();
+    for micros in [0, 3, 20, 20_000] {
+        histogram.record(Duration::from_micros(micros));
+    }
+    assert_eq!(histogram.count(), 4);
+    assert_eq!(histogram.max(), Duration::from_millis(20));
+    assert_eq!(histogram.mean(), Duration::from_nanos(20_023_000 / 4));
+}
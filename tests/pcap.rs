@@ -0,0 +1,40 @@
+mod common;
+
+use std::io::Cursor;
+use std::time::Duration;
+use budget_ditto::packet_io::pcap::PcapIo;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::pcap::{PcapReader, PcapWriter, LINKTYPE_ETHERNET};
+use budget_ditto::shim::WireFormat;
+use common::*;
+
+#[test]
+fn replay_pcap() {
+    let frames: Vec<Vec<u8>> = (0..10).map(|i| get_frame(100 + 10 * i, i as u8 + 1)).collect();
+    let mut capture = PcapWriter::new(Vec::new(), LINKTYPE_ETHERNET).unwrap();
+    for (i, frame) in frames.iter().enumerate() {
+        capture.write_packet(Duration::from_millis(i as u64), frame).unwrap();
+    }
+    let reader = PcapReader::new(Cursor::new(capture.into_inner())).unwrap();
+    let input: PcapIo<_, Vec<u8>> = PcapIo::new(Some(reader), None);
+
+    let mut host_b = spawn_peers(input, None, WireFormat::Shim);
+    assert_eq!(receive_all(&mut host_b, frames.len()), frames);
+}
+
+#[test]
+fn record_pcap() {
+    let mut output: PcapIo<Cursor<Vec<u8>>, _> = PcapIo::new(None, Some(PcapWriter::new(Vec::new(), LINKTYPE_ETHERNET).unwrap()));
+    let frames: Vec<Vec<u8>> = (0..3).map(|i| get_frame(64 * (i + 1), i as u8)).collect();
+    for frame in &frames {
+        output.send(frame).unwrap();
+    }
+
+    let capture = output.into_writer().unwrap().into_inner();
+    let mut reader = PcapReader::new(Cursor::new(capture)).unwrap();
+    assert_eq!(reader.link_type, LINKTYPE_ETHERNET);
+    for frame in &frames {
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, &frame[..]);
+    }
+    assert!(reader.read_packet().unwrap().is_none());
+}
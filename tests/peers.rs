@@ -0,0 +1,78 @@
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+use budget_ditto::control::Control;
+use budget_ditto::deobfuscate::Deobfuscator;
+use budget_ditto::metrics::Metrics;
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::pacer::{CatchUp, SleepPacer};
+use budget_ditto::peer::Classifier;
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+use pnet::util::MacAddr;
+
+#[test]
+fn frames_are_routed_to_peers() {
+    // Peer A sends to B and C, each on its own link at its own rate
+    let (mut host_a, input_a) = memory::link();
+    let mut classifier = Classifier::default();
+    classifier.add_subnet("10.8.0.0/16".parse().unwrap(), 0);
+    classifier.add_subnet("10.8.3.0/24".parse().unwrap(), 1);
+    classifier.add_mac(MacAddr::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x03), 1);
+    let controls = vec![
+        Arc::new(Control::new(&PATTERN, RATE, SlotFormat::new(IP_A, IP_B, None, WireFormat::Shim))),
+        Arc::new(Control::new(&[300, 1000], RATE / 2.0, SlotFormat::new(IP_A, IP_C, None, WireFormat::Shim))),
+    ];
+    for control in &controls {
+        control.set_limits(LIMITS);
+    }
+    let rx_controls = controls.clone();
+    let shutdown = Shutdown::new();
+    let shutdown_obf = shutdown.clone();
+    thread::spawn(move || budget_ditto::obfuscate_data(input_a, MacAddr::zero(), &rx_controls, &classifier, 1e3, false, &shutdown_obf));
+
+    let mut hosts = Vec::new();
+    for (control, ip) in controls.into_iter().zip([IP_B, IP_C]) {
+        let (wire_a, wire_peer) = memory::link();
+        let (output, host) = memory::link();
+        let shutdown_send = shutdown.clone();
+        thread::spawn(move || budget_ditto::transmit(wire_a, SleepPacer, CatchUp::Burst, control, false, &shutdown_send, TIMEOUT));
+        let mut deobfuscator = Deobfuscator::new(SlotFormat::new(ip, IP_A, None, WireFormat::Shim), false, false, TIMEOUT);
+        thread::spawn(move || budget_ditto::deobfuscate_data(wire_peer, output, std::slice::from_mut(&mut deobfuscator), &Metrics::default(), None, false, &Shutdown::new()));
+        hosts.push(host);
+    }
+
+    // Matching no route they go to the first peer, the longest subnet wins over the one added first
+    let to_b = vec![get_frame_to([10, 8, 2, 1], 100, 1), get_frame_to([10, 8, 4, 1], 900, 2)];
+    let to_c = vec![get_frame_to([10, 8, 3, 7], 100, 3), get_frame_with([0x02, 0x11, 0x22, 0x33, 0x44, 0x03], &[0x08, 0x06], 60, 4)];
+    for frame in to_b.iter().chain(&to_c) {
+        host_a.send(frame).unwrap();
+    }
+    // The frames to C share its 300B queue
+    for ((host, expected), pattern) in hosts.iter_mut().zip([to_b, to_c]).zip([&PATTERN[..], &[300, 1000]]) {
+        let received = receive_all(host, 3);
+        check_delivered(&received, &expected, pattern, &SlotFormat::new(IP_A, IP_B, None, WireFormat::Shim));
+    }
+    shutdown.request();
+}
+
+#[test]
+fn slots_are_told_apart_by_peer() {
+    // A receives the slots of B and C on the same interface
+    let queue_b = PriorityQueue::new(200, SlotFormat::new(IP_B, IP_A, None, WireFormat::Shim));
+    let queue_c = PriorityQueue::new(200, SlotFormat::new(IP_C, IP_A, None, WireFormat::Shim));
+    assert!(queue_c.push(get_frame(100, 1)));
+    let deobfuscators = vec![
+        Deobfuscator::new(SlotFormat::new(IP_A, IP_B, None, WireFormat::Shim), false, false, TIMEOUT),
+        Deobfuscator::new(SlotFormat::new(IP_A, IP_C, None, WireFormat::Shim), false, false, TIMEOUT),
+    ];
+
+    assert_eq!(budget_ditto::deobfuscate::select_peer(&deobfuscators, &queue_b.pop(0)), Some(0));
+    assert_eq!(budget_ditto::deobfuscate::select_peer(&deobfuscators, &queue_c.pop(0)), Some(1));
+    let stranger = PriorityQueue::new(200, SlotFormat::new([10, 9, 0, 4], IP_A, None, WireFormat::Shim));
+    assert_eq!(budget_ditto::deobfuscate::select_peer(&deobfuscators, &stranger.pop(0)), None);
+}
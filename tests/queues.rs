@@ -0,0 +1,59 @@
+mod common;
+
+use std::thread;
+use std::time::Duration;
+use budget_ditto::control::Control;
+use budget_ditto::queues::priority_queue::{PriorityQueue, QueueLimits};
+use budget_ditto::shim::WireFormat;
+use common::*;
+
+#[test]
+fn queue_limits() {
+    // In packets and in bytes of the packets, the one left out is derived from the rate
+    let queue = PriorityQueue::new(200, get_format(None, WireFormat::Shim));
+    queue.set_limits(&QueueLimits { max_packets: Some(2), ..QueueLimits::default() }, 1000.0);
+    assert_eq!(queue.max_sojourn(), Duration::from_millis(100));
+    assert!(queue.push(get_frame(60, 1)) && queue.push(get_frame(60, 2)));
+    assert!(!queue.push(get_frame(60, 3)));
+
+    let queue = PriorityQueue::new(200, get_format(None, WireFormat::Shim));
+    queue.set_limits(&QueueLimits { max_bytes: Some(150), ..QueueLimits::default() }, 1000.0);
+    assert!(queue.push(get_frame(100, 1)));
+    assert!(!queue.push(get_frame(100, 2)));
+    queue.pop(0);
+    assert!(queue.push(get_frame(100, 3)));
+    assert_eq!(queue.stats().dropped, 1);
+
+    // Stale packets are dropped when their turn comes, the slot carries the next one or chaff
+    let queue = PriorityQueue::new(200, get_format(None, WireFormat::Shim));
+    queue.set_limits(&QueueLimits { max_sojourn: Some(Duration::from_millis(10)), ..QueueLimits::default() }, 1000.0);
+    assert!(queue.push(get_frame(60, 1)));
+    thread::sleep(Duration::from_millis(20));
+    assert!(queue.push(get_frame(60, 2)));
+    queue.pop(0);
+    queue.pop(1);
+    let stats = queue.stats();
+    assert_eq!((stats.expired, stats.real_sent, stats.chaff_sent), (1, 1, 1));
+}
+
+#[test]
+fn queue_limits_follow_the_rate() {
+    // At 64Mbps each queue gets about 2700 slots/s, 270 of them in 100ms
+    let control = Control::new(&PATTERN, RATE, get_format(None, WireFormat::Shim));
+    let rrs = control.scheduler();
+    let slots_per_sec = rrs.pps() / PATTERN.len() as f64;
+    for q in &rrs.queues {
+        assert_eq!(q.max_sojourn(), Duration::from_millis(100));
+        assert_eq!(q.max_packets(), (slots_per_sec / 10.0).ceil() as usize);
+        assert_eq!(q.max_bytes(), q.max_packets() * q.capacity());
+    }
+
+    // At 10 slots/s packets may wait for 4 slots of their queue, and the limits set are kept
+    control.set_rate(RATE * 10.0 / slots_per_sec).unwrap();
+    control.set_limits(QueueLimits { max_packets: Some(100), ..QueueLimits::default() });
+    let rrs = control.scheduler();
+    assert!((rrs.queues[0].max_sojourn().as_secs_f64() - 0.4).abs() < 1e-6);
+    assert_eq!(rrs.queues[0].max_packets(), 100);
+    control.set_pattern(&[500, 500]).unwrap();
+    assert_eq!(control.scheduler().queues[0].max_packets(), 100);
+}
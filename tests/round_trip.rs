@@ -0,0 +1,148 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use budget_ditto::control::Control;
+use budget_ditto::crypto::Cipher;
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::pacer::{CatchUp, SleepPacer};
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+
+// Sizes out of order, with one of them twice
+const UNSORTED_PATTERN: [usize; 4] = [1400, 200, 1400, 600];
+
+// Sends nowhere, keeping the length of every slot
+struct LengthIo {
+    lengths: Arc<Mutex<Vec<usize>>>,
+}
+
+impl PacketIo for LengthIo {
+    fn send(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        self.lengths.lock().unwrap().push(packet.len());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> std::io::Result<&[u8]> {
+        Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Nothing to receive"))
+    }
+}
+
+#[test]
+fn round_trip_shim() {
+    check_round_trip(get_format(None, WireFormat::Shim));
+}
+
+#[test]
+fn round_trip_shim_encrypted() {
+    check_round_trip(get_format(Some(Cipher::from_hex(KEY).unwrap()), WireFormat::Shim));
+}
+
+#[test]
+fn round_trip_legacy() {
+    check_round_trip(get_format(None, WireFormat::Legacy));
+}
+
+#[test]
+fn round_trip_legacy_encrypted() {
+    check_round_trip(get_format(Some(Cipher::from_hex(KEY).unwrap()), WireFormat::Legacy));
+}
+
+#[test]
+fn round_trip_ipv6() {
+    for wire_format in [WireFormat::Shim, WireFormat::Legacy] {
+        check_round_trip(SlotFormat::new(IP6_A, IP6_B, None, wire_format));
+        check_round_trip(SlotFormat::new(IP6_A, IP6_B, Some(Cipher::from_hex(KEY).unwrap()), wire_format));
+    }
+}
+
+#[test]
+fn round_trip_any_ethertype() {
+    let unicast = [0x02, 0x11, 0x22, 0x33, 0x44, 0x01];
+    let mut frames = vec![
+        // IPv6 to a host and to the all-nodes multicast address, ARP request, IPv4 in 802.1Q and in 802.1ad + 802.1Q
+        get_frame_with(unicast, &[0x86, 0xdd], 100, 1),
+        get_frame_with([0x33, 0x33, 0x00, 0x00, 0x00, 0x01], &[0x86, 0xdd], 90, 2),
+        get_frame_with([0xff; 6], &[0x08, 0x06], 42, 3),
+        get_frame_with(unicast, &[0x81, 0x00, 0x00, 0x64, 0x08, 0x00], 500, 4),
+        get_frame_with(unicast, &[0x88, 0xa8, 0x00, 0x0a, 0x81, 0x00, 0x00, 0x64, 0x08, 0x00], 1000, 5),
+    ];
+    frames.sort();
+    let key = Cipher::from_hex(KEY).unwrap();
+    for (cipher, wire_format) in [(None, WireFormat::Shim), (Some(key.clone()), WireFormat::Shim), (None, WireFormat::Legacy), (Some(key), WireFormat::Legacy)] {
+        let (mut host_a, input_a) = memory::link();
+        let mut host_b = spawn_peers(input_a, cipher, wire_format);
+        for frame in &frames {
+            host_a.send(frame).unwrap();
+        }
+
+        let mut received = receive_all(&mut host_b, frames.len());
+        received.sort();
+        assert_eq!(received, frames, "{:?}", wire_format);
+    }
+}
+
+#[test]
+fn ipv6_outer_header() {
+    let queue = PriorityQueue::new(200, SlotFormat::new(IP6_A, IP6_B, None, WireFormat::Shim));
+    assert!(queue.push(get_frame(100, 1)));
+    let slot = queue.pop(0);
+
+    // 40B header, with the slot after it as payload
+    assert_eq!(slot.len(), 240);
+    assert_eq!(slot[0] >> 4, 6);
+    assert_eq!(u16::from_be_bytes([slot[4], slot[5]]), 200);
+    assert_eq!(slot[6], 41);
+    assert_eq!(slot[8..24], IP6_A);
+    assert_eq!(slot[24..40], IP6_B);
+}
+
+#[test]
+fn oversize_frames_are_dropped() {
+    let (mut host_a, input_a) = memory::link();
+    let mut host_b = spawn_peers(input_a, None, WireFormat::Legacy);
+
+    // Without the shim header frames cannot be split, the largest one that fits is the largest size of the pattern
+    host_a.send(&get_frame(1401, 1)).unwrap();
+    host_a.send(&get_frame(1400, 2)).unwrap();
+
+    let received = receive_all(&mut host_b, 2);
+    assert_eq!(received, vec![get_frame(1400, 2)]);
+}
+
+#[test]
+fn unsorted_pattern() {
+    // The slots follow the pattern as written, the queues are only sorted to push to them
+    let format = get_format(None, WireFormat::Shim);
+    let control = Arc::new(Control::new(&UNSORTED_PATTERN, RATE, format.clone()));
+    let lengths = Arc::new(Mutex::new(Vec::new()));
+    let shutdown = Shutdown::new();
+    let (tx_control, io, shutdown_send) = (Arc::clone(&control), LengthIo { lengths: Arc::clone(&lengths) }, shutdown.clone());
+    let handle = thread::spawn(move || budget_ditto::transmit(io, SleepPacer, CatchUp::Burst, tx_control, false, &shutdown_send, TIMEOUT));
+    // Two rounds of the pattern, the test threads share the CPU
+    let deadline = Instant::now() + TIMEOUT;
+    while lengths.lock().unwrap().len() < 2 * UNSORTED_PATTERN.len() && Instant::now() < deadline {
+        thread::sleep(control.scheduler().interval());
+    }
+    shutdown.request();
+    handle.join().unwrap();
+    let lengths = lengths.lock().unwrap();
+    assert!(lengths.len() >= 2 * UNSORTED_PATTERN.len(), "{} slots sent", lengths.len());
+    for (i, &length) in lengths.iter().enumerate() {
+        assert_eq!(length, UNSORTED_PATTERN[i % UNSORTED_PATTERN.len()] + format.outer_header_len(), "slot {}", i);
+    }
+
+    // Frames of every size, the largest one split over the 1400B slots
+    let (mut host_a, input_a) = memory::link();
+    let mut host_b = start_peers(input_a, &UNSORTED_PATTERN, format.clone(), RATE, &Shutdown::new(), TIMEOUT).0;
+    let frames: Vec<Vec<u8>> = (0..24).map(|i| get_frame([100, 500, 1000, 60, 3000, 592][i % 6], i as u8 + 1)).collect();
+    for frame in &frames {
+        host_a.send(frame).unwrap();
+    }
+    let received = receive_all(&mut host_b, frames.len());
+    check_delivered(&received, &frames, &UNSORTED_PATTERN, &format);
+}
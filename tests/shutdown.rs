@@ -0,0 +1,58 @@
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+use budget_ditto::packet_io::memory;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+
+#[test]
+fn shutdown_drains_queues() {
+    let (mut host_a, input_a) = memory::link();
+    let shutdown = Shutdown::new();
+    // Slow enough for the frames to still be queued when the shutdown is requested, about 200 slots/s
+    let (mut host_b, handles) = start_peers(input_a, &PATTERN, get_format(None, WireFormat::Shim), 1.8, &shutdown, TIMEOUT);
+
+    let frames: Vec<Vec<u8>> = (0..20).map(|i| get_frame(if i % 2 == 0 { 60 } else { 1000 }, i as u8 + 1)).collect();
+    for frame in &frames {
+        host_a.send(frame).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    shutdown.request();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut received = receive_all(&mut host_b, frames.len());
+    let mut expected = frames.clone();
+    received.sort();
+    expected.sort();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn drain_stops_at_deadline() {
+    let (mut host_a, input_a) = memory::link();
+    let shutdown = Shutdown::new();
+    let (mut host_b, handles) = start_peers(input_a, &PATTERN, get_format(None, WireFormat::Shim), 0.18, &shutdown, Duration::from_millis(200));
+
+    // Sending them all would take 1.5s
+    for i in 0..20 {
+        host_a.send(&get_frame(1000, i + 1)).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    shutdown.request();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let mut received = 0;
+    while host_b.recv_timeout(Duration::from_millis(10)).is_some() {
+        received += 1;
+    }
+    assert!(received < 20);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use budget_ditto::control::Control;
+use budget_ditto::packet_io::PacketIo;
+use budget_ditto::pacer::{CatchUp, Pacer, SleepPacer};
+use budget_ditto::shim::WireFormat;
+use budget_ditto::shutdown::Shutdown;
+use common::*;
+
+// Sends nowhere, taking STALL for the send of the slot at STALL_AT
+struct StallingIo {
+    sent: usize,
+}
+
+const STALL: Duration = Duration::from_millis(30);
+const STALL_AT: usize = 5;
+
+impl PacketIo for StallingIo {
+    fn send(&mut self, _packet: &[u8]) -> std::io::Result<()> {
+        self.sent += 1;
+        if self.sent == STALL_AT {
+            thread::sleep(STALL);
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> std::io::Result<&[u8]> {
+        Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Nothing to receive"))
+    }
+}
+
+#[test]
+fn catch_up_policies() {
+    // About 200 slots are due while the send is stalled, each policy only counts them in its own way
+    for catch_up in [CatchUp::Burst, CatchUp::Skip, CatchUp::Reanchor] {
+        let control = Arc::new(Control::new(&PATTERN, RATE, get_format(None, WireFormat::Shim)));
+        let shutdown = Shutdown::new();
+        let (tx_control, shutdown_send) = (Arc::clone(&control), shutdown.clone());
+        let handle = thread::spawn(move || budget_ditto::transmit(StallingIo { sent: 0 }, SleepPacer, catch_up, tx_control, false, &shutdown_send, TIMEOUT));
+        thread::sleep(STALL * 3);
+        shutdown.request();
+        handle.join().unwrap();
+
+        let metrics = control.metrics();
+        let (bursted, missed) = (metrics.slots_bursted.get(), metrics.slots_missed.get());
+        match catch_up {
+            CatchUp::Burst => assert!(bursted > 100 && missed == 0, "{} bursted, {} missed", bursted, missed),
+            _ => assert!(missed > 100 && bursted == 0, "{:?}: {} bursted, {} missed", catch_up, bursted, missed),
+        }
+    }
+}
+
+// Sends nowhere, keeping when every slot was sent
+struct RecordingIo {
+    times: Arc<Mutex<Vec<Instant>>>,
+}
+
+impl PacketIo for RecordingIo {
+    fn send(&mut self, _packet: &[u8]) -> std::io::Result<()> {
+        self.times.lock().unwrap().push(Instant::now());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> std::io::Result<&[u8]> {
+        Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Nothing to receive"))
+    }
+}
+
+// Sleeps like SleepPacer, keeping the deadline of every slot
+struct RecordingPacer {
+    deadlines: Arc<Mutex<Vec<Instant>>>,
+}
+
+impl Pacer for RecordingPacer {
+    fn wait_until(&mut self, deadline: Instant) {
+        self.deadlines.lock().unwrap().push(deadline);
+        SleepPacer.wait_until(deadline);
+    }
+}
+
+// How far the mean gap of a kind of slot may be from the one asked for, in intervals
+const GAP_TOLERANCE: f64 = 1e-3;
+
+#[test]
+fn gaps_shape_the_timing() {
+    // Slots go out in bursts of the 3 of the pattern, 3 intervals apart, so the rate is the same
+    let control = Arc::new(Control::new(&PATTERN, 0.8, get_format(None, WireFormat::Shim)));
+    let weights = [0.0, 0.0, 2.0];
+    control.set_gaps(&weights).unwrap();
+    let interval = control.scheduler().interval();
+    assert_eq!(control.scheduler().gaps(), [0.0, 0.0, 3.0]);
+
+    let (times, deadlines) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+    let shutdown = Shutdown::new();
+    let (tx_control, io, shutdown_send) = (Arc::clone(&control), RecordingIo { times: Arc::clone(&times) }, shutdown.clone());
+    let pacer = RecordingPacer { deadlines: Arc::clone(&deadlines) };
+    let handle = thread::spawn(move || budget_ditto::transmit(io, pacer, CatchUp::Burst, tx_control, false, &shutdown_send, TIMEOUT));
+    thread::sleep(interval * 13);
+    shutdown.request();
+    handle.join().unwrap();
+
+    // The deadline after slot k is the one of slot k + 1, the schedule keeps to them even when a slot is late
+    let (times, deadlines) = (times.lock().unwrap(), deadlines.lock().unwrap());
+    assert!(deadlines.len() >= 12, "{} slots sent", deadlines.len());
+    for (k, deadline) in deadlines.iter().enumerate().take(times.len() - 1) {
+        assert!(times[k + 1] >= *deadline, "slot {} sent before its deadline", k + 1);
+    }
+    let mean_weight = weights.iter().sum::<f64>() / weights.len() as f64;
+    for (q, weight) in weights.iter().enumerate() {
+        // Gap after the slots of queue q, in intervals
+        let gaps: Vec<f64> = deadlines.windows(2).enumerate()
+            .filter(|(k, _)| (k + 1) % PATTERN.len() == q)
+            .map(|(_, pair)| (pair[1] - pair[0]).as_secs_f64() / interval.as_secs_f64())
+            .collect();
+        let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        assert!((mean - weight / mean_weight).abs() < GAP_TOLERANCE, "queue {}: gaps {:?}", q, gaps);
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use budget_ditto::crypto::Cipher;
+use budget_ditto::deobfuscate::{Deobfuscator, Frames};
+use budget_ditto::queues::priority_queue::{PriorityQueue, SlotFormat, UdpPorts};
+use budget_ditto::shim::WireFormat;
+use common::*;
+
+#[test]
+fn round_trip_udp() {
+    check_round_trip(get_udp_format(IP_A, IP_B, None, WireFormat::Shim));
+    check_round_trip(get_udp_format(IP_A, IP_B, Some(Cipher::from_hex(KEY).unwrap()), WireFormat::Shim));
+    check_round_trip(get_udp_format(IP_A, IP_B, None, WireFormat::Legacy));
+    check_round_trip(get_udp_format(IP6_A, IP6_B, None, WireFormat::Shim));
+}
+
+#[test]
+fn udp_header() {
+    for (src, dst) in [(IP_A.into(), IP_B.into()), (IP6_A.into(), IP6_B.into())] {
+        let format = get_udp_format(src, dst, Some(Cipher::from_hex(KEY).unwrap()), WireFormat::Shim);
+        let ip_header_len = format.ip_header_len();
+        let queue = PriorityQueue::new(200, format);
+        assert!(queue.push(get_frame(100, 1)));
+
+        // The UDP header comes on top of the size of the pattern, its checksum is over the sealed slot
+        for slot in [queue.pop(0), queue.pop(1)] {
+            assert_eq!(slot.len(), ip_header_len + 8 + 200);
+            let datagram = pnet::packet::udp::UdpPacket::new(&slot[ip_header_len..]).unwrap();
+            assert_eq!((datagram.get_source(), datagram.get_destination(), datagram.get_length()), (4500, 4501, 208));
+            let checksum = match (src, dst) {
+                (std::net::IpAddr::V4(src), std::net::IpAddr::V4(dst)) => pnet::packet::udp::ipv4_checksum(&datagram, &src, &dst),
+                (std::net::IpAddr::V6(src), std::net::IpAddr::V6(dst)) => pnet::packet::udp::ipv6_checksum(&datagram, &src, &dst),
+                _ => unreachable!(),
+            };
+            assert_eq!(datagram.get_checksum(), checksum);
+        }
+    }
+}
+
+#[test]
+fn udp_to_other_port_is_ignored() {
+    let queue = PriorityQueue::new(200, get_udp_format(IP_A, IP_B, None, WireFormat::Shim));
+    assert!(queue.push(get_frame(100, 1)));
+    let slot = queue.pop(0);
+
+    let mut deobfuscator = Deobfuscator::new(SlotFormat { udp: Some(UdpPorts { src: 4501, dst: 4500 }), ..SlotFormat::new(IP_B, IP_A, None, WireFormat::Shim) }, false, false, TIMEOUT);
+    assert_eq!(deobfuscator.process_packet(&slot), Some(Frames::One(&get_frame(100, 1))));
+    let mut deobfuscator = Deobfuscator::new(SlotFormat { udp: Some(UdpPorts { src: 4502, dst: 4500 }), ..SlotFormat::new(IP_B, IP_A, None, WireFormat::Shim) }, false, false, TIMEOUT);
+    assert_eq!(deobfuscator.process_packet(&slot), None);
+}